@@ -0,0 +1,339 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::dictionary::Dictionary;
+use crate::encode::Quality;
+use crate::precompress::Algorithm;
+
+/// Version of the cache key layout; bump when encoder output changes.
+const KEY_VERSION: u8 = 1;
+
+/// Distinguishes the temporary files of concurrent inserts in this process.
+static NEXT_TMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A content-addressed store of compressed variants.
+///
+/// Entries are keyed by the SHA-256 of the source contents together with the
+/// algorithm and the encoder parameters used, so identical files compressed
+/// with the same settings share a single entry.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+/// The number and total size of cache entries, either stored or removed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheUsage {
+    /// The number of entries.
+    pub entries: u64,
+    /// The total size of the entries.
+    pub bytes: u64,
+}
+
+impl Cache {
+    /// Returns a cache stored in `dir`, which is created on the first insert.
+    pub fn new(dir: PathBuf) -> Self {
+        Cache { dir }
+    }
+
+    /// Returns `$XDG_CACHE_HOME/precompress`, falling back to
+    /// `$HOME/.cache/precompress`.
+    pub fn default_dir() -> Option<PathBuf> {
+        let base = env::var_os("XDG_CACHE_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(base.join("precompress"))
+    }
+
+    /// Returns the directory the cache is stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the cache key for a source digest compressed with `alg`,
+    /// using `zstd_dictionary` for zstd.
+    pub(crate) fn key(
+        digest: &[u8; 32],
+        alg: Algorithm,
+        quality: &Quality,
+        zstd_dictionary: Option<&Dictionary>,
+    ) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update([KEY_VERSION]);
+        hasher.update(alg.to_string());
+        hasher.update(quality.get(alg).to_le_bytes());
+        if let Some(iterations) = quality.zopfli(alg) {
+            hasher.update(b"zopfli");
+            hasher.update(iterations.get().to_le_bytes());
+        }
+        if alg == Algorithm::Zstd
+            && let Some(dictionary) = zstd_dictionary
+        {
+            hasher.update(b"dictionary");
+            hasher.update(dictionary.hash);
+        }
+        hasher.update(digest);
+        to_hex(&hasher.finalize())
+    }
+
+    /// Opens the entry for `key`, marking it as recently used when the cache
+    /// is writable.
+    pub(crate) fn get(&self, key: &str) -> Option<File> {
+        let entry_path = self.entry_path(key);
+        let file = File::open(&entry_path).ok()?;
+        _ = File::options()
+            .write(true)
+            .open(&entry_path)
+            .and_then(|entry| entry.set_modified(SystemTime::now()));
+        Some(file)
+    }
+
+    /// Stores a copy of the file at `path` under `key`.
+    pub(crate) fn insert(&self, key: &str, path: &Path) -> io::Result<()> {
+        let entry_path = self.entry_path(key);
+        if let Some(parent) = entry_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp_name = entry_path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            NEXT_TMP_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let tmp_path = entry_path.with_file_name(tmp_name);
+        let result = fs::copy(path, &tmp_path).and_then(|_| fs::rename(&tmp_path, &entry_path));
+        if result.is_err() {
+            _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+
+    /// Returns the number and total size of the stored entries.
+    pub fn usage(&self) -> io::Result<CacheUsage> {
+        let mut usage = CacheUsage::default();
+        for (_, metadata) in self.entries()? {
+            usage.entries += 1;
+            usage.bytes += metadata.len();
+        }
+        Ok(usage)
+    }
+
+    /// Removes the least recently used entries until the cache occupies at
+    /// most `max_bytes`, returning what was removed.
+    pub fn prune(&self, max_bytes: u64) -> io::Result<CacheUsage> {
+        let mut entries = self.entries()?;
+        let mut total: u64 = entries.iter().map(|(_, m)| m.len()).sum();
+        entries.sort_by_key(|(_, m)| m.modified().unwrap_or(SystemTime::UNIX_EPOCH));
+
+        let mut removed = CacheUsage::default();
+        for (path, metadata) in entries {
+            if total <= max_bytes {
+                break;
+            }
+            fs::remove_file(&path)?;
+            total -= metadata.len();
+            removed.entries += 1;
+            removed.bytes += metadata.len();
+        }
+        Ok(removed)
+    }
+
+    /// Removes every entry from the cache.
+    pub fn clear(&self) -> io::Result<CacheUsage> {
+        self.prune(0)
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[0..2]).join(key)
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, fs::Metadata)>> {
+        let mut entries = Vec::new();
+        let shards = match fs::read_dir(&self.dir) {
+            Ok(shards) => shards,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(entries),
+            Err(err) => return Err(err),
+        };
+        for shard in shards {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_file() {
+                    entries.push((entry.path(), metadata));
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Computes the SHA-256 digest of everything remaining in `file`.
+pub(crate) fn hash_file(file: &mut File, buf: &mut [u8]) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    loop {
+        let n = file.read(buf)?;
+        if n == 0 {
+            return Ok(hasher.finalize().into());
+        }
+        hasher.update(&buf[0..n]);
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::Read,
+        thread,
+        time::{Duration, SystemTime},
+    };
+
+    use anyhow::Result;
+
+    use super::Cache;
+    use crate::dictionary::Dictionary;
+    use crate::encode::{DEFAULT_ZOPFLI_ITERATIONS, Quality};
+    use crate::precompress::Algorithm;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn key_depends_on_algorithm_and_quality() {
+        let digest = [7; 32];
+        let mut quality = Quality::default();
+        let brotli = Cache::key(&digest, Algorithm::Brotli, &quality, None);
+        assert_ne!(brotli, Cache::key(&digest, Algorithm::Gzip, &quality, None));
+        assert_eq!(
+            brotli,
+            Cache::key(&digest, Algorithm::Brotli, &quality, None)
+        );
+
+        assert!(quality.set(Algorithm::Brotli, 4));
+        assert_ne!(
+            brotli,
+            Cache::key(&digest, Algorithm::Brotli, &quality, None)
+        );
+        assert_ne!(
+            brotli,
+            Cache::key(&[8; 32], Algorithm::Brotli, &quality, None)
+        );
+
+        let gzip = Cache::key(&digest, Algorithm::Gzip, &quality, None);
+        assert!(quality.set_zopfli(Algorithm::Gzip, DEFAULT_ZOPFLI_ITERATIONS));
+        assert_ne!(gzip, Cache::key(&digest, Algorithm::Gzip, &quality, None));
+
+        let zstd = Cache::key(&digest, Algorithm::Zstd, &quality, None);
+        let dictionary = Dictionary::new(b"dictionary".to_vec());
+        assert_ne!(
+            zstd,
+            Cache::key(&digest, Algorithm::Zstd, &quality, Some(&dictionary))
+        );
+        assert_eq!(
+            gzip,
+            Cache::key(
+                &digest,
+                Algorithm::Gzip,
+                &Quality::default(),
+                Some(&dictionary)
+            )
+        );
+    }
+
+    #[test]
+    fn insert_get_and_prune_least_recently_used() -> Result<()> {
+        let root = test_dir("cache");
+        let cache = Cache::new(root.join("cache"));
+        let old = Cache::key(&[1; 32], Algorithm::Gzip, &Quality::default(), None);
+        let new = Cache::key(&[2; 32], Algorithm::Gzip, &Quality::default(), None);
+
+        fs::write(root.join("variant"), [0; 100])?;
+        cache.insert(&old, &root.join("variant"))?;
+        cache.insert(&new, &root.join("variant"))?;
+        assert!(cache.get(&old).is_some());
+        fs::File::options()
+            .write(true)
+            .open(cache.entry_path(&old))?
+            .set_modified(SystemTime::now() - Duration::from_secs(60))?;
+        assert!(cache.get(&new).is_some());
+
+        let usage = cache.usage()?;
+        assert_eq!(usage.entries, 2);
+        assert_eq!(usage.bytes, 200);
+
+        let removed = cache.prune(150)?;
+        assert_eq!(removed.entries, 1);
+        assert!(cache.get(&new).is_some());
+        assert!(cache.get(&old).is_none());
+
+        cache.clear()?;
+        assert_eq!(cache.usage()?.entries, 0);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_inserts_of_one_key_leave_a_whole_entry() -> Result<()> {
+        let root = test_dir("cache-concurrent");
+        let cache = Cache::new(root.join("cache"));
+        let key = Cache::key(&[3; 32], Algorithm::Gzip, &Quality::default(), None);
+        let contents = (0..=255).cycle().take(1 << 20).collect::<Vec<u8>>();
+        fs::write(root.join("variant"), &contents)?;
+
+        thread::scope(|scope| {
+            let handles = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..4).try_for_each(|_| cache.insert(&key, &root.join("variant")))
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().expect("insert panicked"))
+        })?;
+
+        let mut entry = cache.get(&key).expect("entry should exist");
+        let mut cached = Vec::new();
+        entry.read_to_end(&mut cached)?;
+        assert!(cached == contents);
+        assert_eq!(cache.usage()?.entries, 1);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn get_hits_a_read_only_entry() -> Result<()> {
+        let root = test_dir("cache-read-only");
+        let cache = Cache::new(root.join("cache"));
+        let key = Cache::key(&[4; 32], Algorithm::Gzip, &Quality::default(), None);
+        fs::write(root.join("variant"), [0; 100])?;
+        cache.insert(&key, &root.join("variant"))?;
+
+        let entry_path = cache.entry_path(&key);
+        let mut permissions = fs::metadata(&entry_path)?.permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&entry_path, permissions)?;
+        assert!(cache.get(&key).is_some());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,395 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use strum::IntoEnumIterator;
+
+use crate::config::{Config, FileSettings};
+use crate::dictionary::{Dictionary, DictionaryFormat};
+use crate::encode::{Context, Quality};
+use crate::output::{Layout, Output};
+use crate::precompress::{
+    Algorithm, Algorithms, WalkOptions, build_walk, is_compressible, is_current, tmp_target_path,
+};
+use crate::state::State;
+
+#[derive(Debug, Clone)]
+pub struct CleanOptions {
+    pub algorithms: Algorithms,
+    pub extensions: Option<HashSet<String>>,
+    pub output: Output,
+    /// The dictionary zstd variants were compressed with, if any.
+    pub zstd_dictionary: Option<Arc<Dictionary>>,
+    /// Per-file rules that override the algorithms and extensions above.
+    pub config: Config,
+}
+
+impl CleanOptions {
+    /// Returns the settings of the source at `rel`, relative to the root.
+    fn settings(&self, rel: &Path) -> FileSettings {
+        let base = FileSettings {
+            algorithms: self.algorithms,
+            quality: Quality::default(),
+            min_size: 0,
+            include: None,
+        };
+        self.config.resolve(rel, base)
+    }
+
+    /// Reports whether the file at `path`, or `rel` relative to the root, is
+    /// selected for compression.
+    fn is_source(&self, path: &Path, rel: &Path) -> bool {
+        self.settings(rel)
+            .include
+            .unwrap_or_else(|| is_compressible(path, self.extensions.as_ref()))
+    }
+
+    /// Reports whether variants may have been written for a file at `path`,
+    /// because of its extension or a rule, even if it is now excluded.
+    fn may_be_source(&self, path: &Path, rel: &Path) -> bool {
+        is_compressible(path, self.extensions.as_ref()) || self.settings(rel).include == Some(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The source of the variant no longer exists.
+    MissingSource,
+    /// The source exists but is no longer selected for compression, or no
+    /// longer compressed with the variant's algorithm.
+    Excluded,
+    /// The variant does not match the current contents of its source.
+    Stale,
+    /// A temporary file left behind by an interrupted write.
+    Temporary,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::MissingSource => "source missing",
+            Reason::Excluded => "source excluded",
+            Reason::Stale => "stale",
+            Reason::Temporary => "temporary file",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Removal {
+    pub path: PathBuf,
+    pub reason: Reason,
+}
+
+/// Finds the compressed variants under `root` (or the output directory) that
+/// should be removed, sorted by path.
+///
+/// Variants and sources are discovered with the same walk used when
+/// compressing, so files hidden by ignore rules are never touched.
+pub fn find_removals(
+    root: &Path,
+    options: &CleanOptions,
+    walk_options: &WalkOptions,
+) -> Result<Vec<Removal>> {
+    // Rules may enable algorithms for some files only, and their variants
+    // must still be recognized.
+    let algorithms = options.config.all_algorithms(options.algorithms);
+    let mut sources = HashSet::new();
+    for entry in build_walk(root, walk_options)? {
+        let Ok(entry) = entry else { continue };
+        let path = entry.path();
+        if options.is_source(path, path.strip_prefix(root).unwrap_or(path))
+            && !options.output.is_sibling_variant(path, algorithms)
+            && !path.is_symlink()
+            && path.is_file()
+        {
+            sources.insert(path.to_path_buf());
+        }
+    }
+
+    let variant_root = options.output.out_dir.as_deref().unwrap_or(root);
+    if !variant_root.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let state = State::load(variant_root);
+    let mut ctx = Context::new(1 << 14, Quality::default())
+        .with_zstd_dictionary(options.zstd_dictionary.clone());
+    let mut removals = Vec::new();
+    for entry in build_walk(variant_root, walk_options)? {
+        let Ok(entry) = entry else { continue };
+        let path = entry.path();
+        if path.is_symlink() || !path.is_file() {
+            continue;
+        }
+
+        if let Some(target) = tmp_target_path(path)
+            && locate_source(root, &target, options, algorithms).is_some()
+        {
+            removals.push(Removal {
+                path: path.to_path_buf(),
+                reason: Reason::Temporary,
+            });
+            continue;
+        }
+
+        let Some((alg, source)) = locate_source(root, path, options, algorithms) else {
+            continue;
+        };
+        let rel = source.strip_prefix(root).unwrap_or(&source);
+        let reason = if !source.is_file() {
+            Reason::MissingSource
+        } else if !sources.contains(&source)
+            || alg.is_some_and(|alg| !options.settings(rel).algorithms.is_enabled(alg))
+        {
+            Reason::Excluded
+        } else if let Some(alg) = alg
+            && !is_current(&mut ctx, &source, state.get(rel), alg, path)
+        {
+            // Dictionary-compressed variants cannot be decoded without the
+            // dictionary they were written with, so they are never stale.
+            Reason::Stale
+        } else {
+            continue;
+        };
+        removals.push(Removal {
+            path: path.to_path_buf(),
+            reason,
+        });
+    }
+
+    removals.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(removals)
+}
+
+/// Returns the algorithm and expected source path when `path` is a variant
+/// of one of `algorithms` that would have been written for a source under
+/// `root`. Variants in a dictionary format, which have no algorithm, are
+/// recognized whatever the enabled algorithms.
+fn locate_source(
+    root: &Path,
+    path: &Path,
+    options: &CleanOptions,
+    algorithms: Algorithms,
+) -> Option<(Option<Algorithm>, PathBuf)> {
+    let output = &options.output;
+    match (&output.out_dir, output.layout) {
+        (Some(out_dir), Layout::PerEncoding) => {
+            let mut components = path.strip_prefix(out_dir).ok()?.components();
+            let Some(Component::Normal(name)) = components.next() else {
+                return None;
+            };
+            let alg = match Algorithm::iter().find(|alg| name == alg.encoding()) {
+                Some(alg) if algorithms.is_enabled(alg) => Some(alg),
+                Some(_) => return None,
+                None => {
+                    DictionaryFormat::iter().find(|format| name == format.encoding())?;
+                    None
+                }
+            };
+            let rel = components.as_path();
+            (!rel.as_os_str().is_empty() && options.may_be_source(rel, rel))
+                .then(|| (alg, root.join(rel)))
+        }
+        (out_dir, _) => {
+            let file_name = path.file_name()?;
+            let (alg, source_name) = match output.naming.source_name(file_name, algorithms) {
+                Some((alg, source_name)) => (Some(alg), source_name),
+                None => {
+                    let file_name = file_name.to_str()?;
+                    let source_name = DictionaryFormat::iter()
+                        .find_map(|format| file_name.strip_suffix(format.extension()))?;
+                    (None, source_name.to_owned())
+                }
+            };
+            let dir = path.parent()?;
+            let dir = match out_dir {
+                None => dir.to_path_buf(),
+                Some(out_dir) => root.join(dir.strip_prefix(out_dir).ok()?),
+            };
+            let source = dir.join(source_name);
+            options
+                .may_be_source(&source, source.strip_prefix(root).unwrap_or(&source))
+                .then_some((alg, source))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use anyhow::Result;
+
+    use super::{CleanOptions, Reason, find_removals};
+    use crate::config::Config;
+    use crate::output::{Layout, Output};
+    use crate::precompress::{Algorithms, CompressOptions, Compressor, WalkOptions};
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn finds_orphaned_stale_excluded_and_temporary_variants() -> Result<()> {
+        let root = test_dir("clean");
+        for name in ["kept.js", "stale.js", "deleted.js", "skip.js"] {
+            fs::write(root.join(name), format!("// {name}\n").repeat(128))?;
+        }
+        compress(&root, Output::default())?;
+        fs::remove_file(root.join("deleted.js"))?;
+        fs::write(root.join("stale.js"), "// changed\n".repeat(128))?;
+        fs::write(root.join("kept.js.br.tmp"), "partial")?;
+        fs::write(root.join("archive.tar.gz"), "not a variant")?;
+        for name in ["kept.js.dcz", "deleted.js.dcz", "skip.js.dcb"] {
+            fs::write(root.join(name), "dictionary-compressed")?;
+        }
+
+        let walk_options = WalkOptions {
+            respect_ignore: true,
+            exclude: vec![String::from("skip.js")],
+        };
+        let options = CleanOptions {
+            algorithms: Algorithms::all(),
+            extensions: None,
+            output: Output::default(),
+            zstd_dictionary: None,
+            config: Config::default(),
+        };
+        let removals = find_removals(&root, &options, &walk_options)?
+            .into_iter()
+            .map(|removal| (relative(&root, &removal.path), removal.reason))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            removals,
+            vec![
+                (String::from("deleted.js.br"), Reason::MissingSource),
+                (String::from("deleted.js.dcz"), Reason::MissingSource),
+                (String::from("deleted.js.gz"), Reason::MissingSource),
+                (String::from("deleted.js.zst"), Reason::MissingSource),
+                (String::from("kept.js.br.tmp"), Reason::Temporary),
+                (String::from("skip.js.br"), Reason::Excluded),
+                (String::from("skip.js.dcb"), Reason::Excluded),
+                (String::from("skip.js.gz"), Reason::Excluded),
+                (String::from("skip.js.zst"), Reason::Excluded),
+                (String::from("stale.js.br"), Reason::Stale),
+                (String::from("stale.js.gz"), Reason::Stale),
+                (String::from("stale.js.zst"), Reason::Stale),
+            ]
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn applies_config_rules_to_sources_and_algorithms() -> Result<()> {
+        let root = test_dir("clean-config");
+        for name in ["app.js", "vendor.js"] {
+            fs::write(root.join(name), format!("// {name}\n").repeat(128))?;
+        }
+        compress(&root, Output::default())?;
+        fs::write(root.join("orphan.blob.gz"), "included by a rule")?;
+
+        let config = Config::parse(
+            r#"
+            [[rule]]
+            glob = "vendor.js"
+            include = false
+
+            [[rule]]
+            glob = "app.js"
+            algorithms = ["br"]
+
+            [[rule]]
+            glob = "*.blob"
+            include = true
+            "#,
+        )?;
+        let options = CleanOptions {
+            algorithms: Algorithms::default(),
+            extensions: None,
+            output: Output::default(),
+            zstd_dictionary: None,
+            config,
+        };
+        let removals = find_removals(&root, &options, &WalkOptions::default())?
+            .into_iter()
+            .map(|removal| (relative(&root, &removal.path), removal.reason))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            removals,
+            vec![
+                (String::from("app.js.gz"), Reason::Excluded),
+                (String::from("app.js.zst"), Reason::Excluded),
+                (String::from("orphan.blob.gz"), Reason::MissingSource),
+                (String::from("vendor.js.br"), Reason::Excluded),
+                (String::from("vendor.js.gz"), Reason::Excluded),
+                (String::from("vendor.js.zst"), Reason::Excluded),
+            ]
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn finds_orphans_in_per_encoding_out_dir() -> Result<()> {
+        let root = test_dir("clean-per-encoding");
+        let src = root.join("src");
+        fs::create_dir(&src)?;
+        fs::write(src.join("app.js"), "const x = 1;\n".repeat(128))?;
+        fs::write(src.join("old.js"), "const y = 2;\n".repeat(128))?;
+        let output = Output {
+            out_dir: Some(root.join("out")),
+            layout: Layout::PerEncoding,
+            ..Output::default()
+        };
+        compress(&src, output.clone())?;
+        fs::remove_file(src.join("old.js"))?;
+
+        let options = CleanOptions {
+            algorithms: Algorithms::default(),
+            extensions: None,
+            output,
+            zstd_dictionary: None,
+            config: Config::default(),
+        };
+        let removals = find_removals(&src, &options, &WalkOptions::default())?
+            .into_iter()
+            .map(|removal| relative(&root, &removal.path))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            removals,
+            vec!["out/br/old.js", "out/gzip/old.js", "out/zstd/old.js"]
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    fn compress(root: &Path, output: Output) -> Result<()> {
+        let compressor = Compressor::new(
+            1,
+            CompressOptions {
+                min_size: 1,
+                output,
+                ..CompressOptions::default()
+            },
+        );
+        compressor.precompress(root, &WalkOptions::default())?;
+        compressor.finish();
+        Ok(())
+    }
+
+    fn relative(root: &Path, path: &Path) -> String {
+        path.strip_prefix(root)
+            .expect("path should be under root")
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+}
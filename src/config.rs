@@ -0,0 +1,362 @@
+//! Per-file compression settings from a `precompress.toml` file.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context as _, Result, anyhow};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+
+use crate::encode::Quality;
+use crate::naming::{Naming, Template};
+use crate::precompress::{Algorithm, Algorithms};
+
+/// The name of the config file looked for in the directory being compressed.
+pub const CONFIG_FILE_NAME: &str = "precompress.toml";
+
+/// Per-file settings from a `precompress.toml` file.
+///
+/// ```toml
+/// [[rule]]
+/// glob = "*.wasm"
+/// algorithms = ["zstd"]
+/// quality = { zstd = 22 }
+///
+/// [[rule]]
+/// glob = "data/**"
+/// include = false
+///
+/// [naming]
+/// zstd = "{name}.zstd"
+/// ```
+///
+/// Every rule whose glob matches a file applies in order, so later rules
+/// override the settings of earlier ones.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    rules: Vec<Rule>,
+    /// Variant file name templates, which apply to every file.
+    naming: Vec<(Algorithm, Template)>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    matcher: GlobMatcher,
+    algorithms: Option<Algorithms>,
+    /// The algorithms whose quality the rule overrides, taken from
+    /// `quality`.
+    quality_overrides: Algorithms,
+    quality: Quality,
+    min_size: Option<u64>,
+    include: Option<bool>,
+}
+
+/// The settings that apply to a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSettings {
+    /// The algorithms to write variants of the file with.
+    pub algorithms: Algorithms,
+    /// The compression level of each algorithm.
+    pub quality: Quality,
+    /// The minimum size of the file for it to be compressed.
+    pub min_size: u64,
+    /// Whether a rule includes or excludes the file regardless of its
+    /// extension, if any does.
+    pub include: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    rule: Vec<RawRule>,
+    #[serde(default)]
+    naming: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    glob: String,
+    algorithms: Option<Vec<String>>,
+    #[serde(default)]
+    quality: BTreeMap<String, RawQuality>,
+    min_size: Option<u64>,
+    include: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawQuality {
+    Level(i64),
+    Name(String),
+}
+
+impl Config {
+    /// Loads the config file at `path`.
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = fs::read_to_string(path)?;
+        Config::parse(&contents)
+    }
+
+    /// Loads `precompress.toml` from `root`, returning `None` when there is
+    /// no such file.
+    pub fn find(root: &Path) -> Result<Option<(PathBuf, Config)>> {
+        let path = root.join(CONFIG_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let config = Config::load(&path).with_context(|| path.display().to_string())?;
+        Ok(Some((path, config)))
+    }
+
+    /// Parses the contents of a `precompress.toml` file.
+    pub fn parse(contents: &str) -> Result<Config> {
+        let raw: RawConfig = toml::from_str(contents)?;
+        let rules = raw
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let glob = rule.glob.clone();
+                Rule::parse(rule).with_context(|| format!("rule {} ({:?})", i + 1, glob))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let naming = raw
+            .naming
+            .into_iter()
+            .map(|(name, template)| {
+                let alg = name.parse::<Algorithm>().context("naming")?;
+                let template = template
+                    .parse()
+                    .map_err(|err| anyhow!("naming.{name}: {err}"))?;
+                Ok((alg, template))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Config { rules, naming })
+    }
+
+    /// Reports whether the config has neither rules nor naming templates.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.naming.is_empty()
+    }
+
+    /// Returns the variant file name templates, with the default template
+    /// for every algorithm the config does not name.
+    pub fn naming(&self) -> Naming {
+        let mut naming = Naming::default();
+        for (alg, template) in &self.naming {
+            naming.set(*alg, template.clone());
+        }
+        naming
+    }
+
+    /// Returns the settings for the file at `rel`, relative to the root being
+    /// compressed, starting from `base`.
+    pub fn resolve(&self, rel: &Path, base: FileSettings) -> FileSettings {
+        let mut settings = base;
+        for rule in self.rules.iter().filter(|rule| rule.matcher.is_match(rel)) {
+            if let Some(algorithms) = rule.algorithms {
+                settings.algorithms = algorithms;
+            }
+            for alg in rule.quality_overrides.iter() {
+                settings.quality.copy_from(alg, &rule.quality);
+            }
+            if let Some(min_size) = rule.min_size {
+                settings.min_size = min_size;
+            }
+            if let Some(include) = rule.include {
+                settings.include = Some(include);
+            }
+        }
+        settings
+    }
+
+    /// Returns `base` together with every algorithm that a rule enables, so
+    /// that the variants of any of them are recognized.
+    pub fn all_algorithms(&self, base: Algorithms) -> Algorithms {
+        let mut all = base;
+        for alg in self.rules.iter().filter_map(|rule| rule.algorithms) {
+            alg.iter().for_each(|alg| all.enable(alg));
+        }
+        all
+    }
+}
+
+impl Rule {
+    fn parse(raw: RawRule) -> Result<Rule> {
+        // Like gitignore, a glob without a slash matches at any depth and a
+        // leading slash anchors it to the root.
+        let glob = match raw.glob.strip_prefix('/') {
+            Some(anchored) => anchored.to_owned(),
+            None if !raw.glob.contains('/') => format!("**/{}", raw.glob),
+            None => raw.glob,
+        };
+        let matcher = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+
+        let algorithms = match raw.algorithms {
+            Some(names) => {
+                let mut algorithms = Algorithms::empty();
+                for name in names {
+                    algorithms.enable(name.parse()?);
+                }
+                if algorithms.iter().count() == 0 {
+                    return Err(anyhow!("no compression algorithms enabled"));
+                }
+                Some(algorithms)
+            }
+            None => None,
+        };
+
+        let mut quality_overrides = Algorithms::empty();
+        let mut quality = Quality::default();
+        for (name, value) in raw.quality {
+            let alg: Algorithm = name.parse()?;
+            let value = match value {
+                RawQuality::Level(level) => level.to_string(),
+                RawQuality::Name(name) => name,
+            };
+            quality.set_from_str(alg, &value)?;
+            quality_overrides.enable(alg);
+        }
+
+        Ok(Rule {
+            matcher,
+            algorithms,
+            quality_overrides,
+            quality,
+            min_size: raw.min_size,
+            include: raw.include,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Config, FileSettings};
+    use crate::encode::{DEFAULT_ZOPFLI_ITERATIONS, Quality};
+    use crate::precompress::{Algorithm, Algorithms};
+
+    const CONFIG: &str = r#"
+        [[rule]]
+        glob = "*.woff2"
+        algorithms = ["br"]
+
+        [[rule]]
+        glob = "*.wasm"
+        algorithms = ["zstd"]
+        quality = { zstd = 22 }
+
+        [[rule]]
+        glob = "data/**"
+        algorithms = ["gzip"]
+        quality = { gz = "zopfli" }
+
+        [[rule]]
+        glob = "data/private/**"
+        include = false
+
+        [[rule]]
+        glob = "*.html"
+        min_size = 256
+    "#;
+
+    fn base() -> FileSettings {
+        FileSettings {
+            algorithms: Algorithms::default(),
+            quality: Quality::default(),
+            min_size: 1024,
+            include: None,
+        }
+    }
+
+    fn enabled(settings: FileSettings) -> Vec<Algorithm> {
+        settings.algorithms.iter().collect()
+    }
+
+    #[test]
+    fn rules_apply_in_order_to_matching_files() {
+        let config = Config::parse(CONFIG).expect("valid config");
+
+        assert_eq!(config.resolve(Path::new("app.js"), base()), base());
+
+        let font = config.resolve(Path::new("fonts/inter.woff2"), base());
+        assert_eq!(enabled(font), vec![Algorithm::Brotli]);
+
+        let wasm = config.resolve(Path::new("app.wasm"), base());
+        assert_eq!(enabled(wasm), vec![Algorithm::Zstd]);
+        assert_eq!(wasm.quality.get(Algorithm::Zstd), 22);
+        assert_eq!(wasm.quality.get(Algorithm::Brotli), 10);
+
+        let data = config.resolve(Path::new("data/items.json"), base());
+        assert_eq!(enabled(data), vec![Algorithm::Gzip]);
+        assert_eq!(
+            data.quality.zopfli(Algorithm::Gzip),
+            Some(DEFAULT_ZOPFLI_ITERATIONS)
+        );
+        assert_eq!(data.include, None);
+        assert!(
+            config
+                .resolve(Path::new("other/data/items.json"), base())
+                .algorithms
+                .brotli
+        );
+
+        let private = config.resolve(Path::new("data/private/keys.json"), base());
+        assert_eq!(private.include, Some(false));
+        assert_eq!(enabled(private), vec![Algorithm::Gzip]);
+
+        let page = config.resolve(Path::new("blog/index.html"), base());
+        assert_eq!(page.min_size, 256);
+
+        let all = config.all_algorithms(Algorithms::empty());
+        assert_eq!(
+            all.iter().collect::<Vec<_>>(),
+            vec![Algorithm::Brotli, Algorithm::Gzip, Algorithm::Zstd]
+        );
+    }
+
+    #[test]
+    fn parse_reports_the_invalid_rule() {
+        let err = Config::parse("[[rule]]\nglob = \"*.js\"\nalgorithms = [\"gzp\"]\n")
+            .expect_err("unknown algorithm");
+        assert_eq!(
+            format!("{err:#}"),
+            r#"rule 1 ("*.js"): unknown compression algorithm "gzp"; did you mean "gz"?"#
+        );
+
+        let err = Config::parse("[[rule]]\nglob = \"*.js\"\nquality = { br = 12 }\n")
+            .expect_err("invalid quality");
+        assert_eq!(
+            format!("{err:#}"),
+            r#"rule 1 ("*.js"): invalid brotli quality "12": expected 0 to 11"#
+        );
+
+        assert!(Config::parse("[[rule]]\nglob = \"*.js\"\nlevel = 3\n").is_err());
+        assert!(Config::parse("[[rule]]\nglob = \"[\"\n").is_err());
+        assert!(Config::parse("").expect("empty config").is_empty());
+
+        let err = Config::parse("[naming]\ngz = \"{stem}.{enc}\"\n").expect_err("bad template");
+        assert_eq!(
+            format!("{err:#}"),
+            "naming.gz: template must contain {name}, or both {stem} and {ext}: {stem}.{enc}"
+        );
+    }
+
+    #[test]
+    fn naming_sets_templates_per_algorithm() {
+        let config = Config::parse("[naming]\nzstd = \"{name}.zstd\"\n").expect("valid config");
+        assert!(!config.is_empty());
+        let naming = config.naming();
+        assert_eq!(naming.get(Algorithm::Zstd).to_string(), "{name}.zstd");
+        assert_eq!(naming.get(Algorithm::Gzip).to_string(), "{name}.{enc}");
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use sha2::{Digest, Sha256};
+use strum_macros::EnumIter;
+
+use crate::precompress::Algorithm;
+
+/// The first bytes of a `dcz` stream: a zstd skippable frame whose 32-byte
+/// payload is the SHA-256 hash of the dictionary.
+pub(crate) const DCZ_MAGIC: [u8; 8] = [0x5e, 0x2a, 0x4d, 0x18, 0x20, 0x00, 0x00, 0x00];
+
+/// The first bytes of a `dcb` stream, followed by the 32-byte SHA-256 hash of
+/// the dictionary.
+pub(crate) const DCB_MAGIC: [u8; 4] = [0xff, 0x44, 0x43, 0x42];
+
+/// A content encoding that compresses against a shared dictionary, as
+/// defined by Compression Dictionary Transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter, clap::ValueEnum)]
+pub enum DictionaryFormat {
+    // Dictionary-compressed zstd.
+    Dcz,
+    // Dictionary-compressed brotli.
+    Dcb,
+}
+
+impl fmt::Display for DictionaryFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.encoding())
+    }
+}
+
+impl DictionaryFormat {
+    /// Returns the extension appended to the names of variants in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Dcz => ".dcz",
+            Self::Dcb => ".dcb",
+        }
+    }
+
+    /// Returns the HTTP `Content-Encoding` token for the format.
+    pub fn encoding(self) -> &'static str {
+        match self {
+            Self::Dcz => "dcz",
+            Self::Dcb => "dcb",
+        }
+    }
+
+    /// Returns the algorithm whose plain variants this format is compared
+    /// against.
+    pub fn baseline(self) -> Algorithm {
+        match self {
+            Self::Dcz => Algorithm::Zstd,
+            Self::Dcb => Algorithm::Brotli,
+        }
+    }
+
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Self::Dcz => &DCZ_MAGIC,
+            Self::Dcb => &DCB_MAGIC,
+        }
+    }
+
+    /// Returns the header that starts every stream compressed against
+    /// `dictionary`.
+    pub(crate) fn header(self, dictionary: &Dictionary) -> Vec<u8> {
+        [self.magic(), &dictionary.hash].concat()
+    }
+
+    /// Reads the header from the start of `encoded`, returning whether it
+    /// identifies `dictionary`.
+    pub(crate) fn read_header(
+        self,
+        encoded: &mut impl Read,
+        dictionary: &Dictionary,
+    ) -> io::Result<bool> {
+        let expected = self.header(dictionary);
+        let mut header = vec![0; expected.len()];
+        encoded.read_exact(&mut header)?;
+        if !header.starts_with(self.magic()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("missing {self} header"),
+            ));
+        }
+        Ok(header == expected)
+    }
+}
+
+/// A raw dictionary along with the SHA-256 hash that identifies it.
+#[derive(Debug)]
+pub struct Dictionary {
+    pub(crate) data: Vec<u8>,
+    pub(crate) hash: [u8; 32],
+}
+
+impl Dictionary {
+    /// Wraps the raw contents of a dictionary.
+    pub fn new(data: Vec<u8>) -> Self {
+        let hash = Sha256::digest(&data).into();
+        Dictionary { data, hash }
+    }
+
+    /// Reads a dictionary from the file at `path`.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Dictionary::new(fs::read(path)?))
+    }
+
+    /// Returns the hash as an HTTP structured field byte sequence, which is
+    /// how clients identify the dictionary in `Available-Dictionary`.
+    pub fn structured_hash(&self) -> String {
+        format!(":{}:", STANDARD.encode(self.hash))
+    }
+}
+
+/// Where the dictionary for each source file comes from.
+#[derive(Debug, Clone)]
+pub enum Dictionaries {
+    /// A single dictionary shared by every source file.
+    File(Arc<Dictionary>),
+    /// The root of a previous build, where each source file is compressed
+    /// against its previous version at the same relative path.
+    PreviousTree(PathBuf),
+}
+
+impl Dictionaries {
+    /// Returns the dictionary for the source file at `rel`, if there is one.
+    pub(crate) fn get(&self, rel: &Path) -> io::Result<Option<Arc<Dictionary>>> {
+        match self {
+            Dictionaries::File(dictionary) => Ok(Some(dictionary.clone())),
+            Dictionaries::PreviousTree(root) => match fs::read(root.join(rel)) {
+                Ok(data) if data.is_empty() => Ok(None),
+                Ok(data) => Ok(Some(Arc::new(Dictionary::new(data)))),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use anyhow::Result;
+
+    use super::{DCB_MAGIC, DCZ_MAGIC, Dictionaries, Dictionary, DictionaryFormat};
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn dcz_header_identifies_the_dictionary() -> Result<()> {
+        let dictionary = Dictionary::new(b"previous contents".to_vec());
+        let header = DictionaryFormat::Dcz.header(&dictionary);
+        assert_eq!(header.len(), 40);
+        assert_eq!(header[0..8], DCZ_MAGIC);
+        assert_eq!(header[8..], dictionary.hash);
+
+        assert!(DictionaryFormat::Dcz.read_header(&mut header.as_slice(), &dictionary)?);
+        let other = Dictionary::new(b"other contents".to_vec());
+        assert!(!DictionaryFormat::Dcz.read_header(&mut header.as_slice(), &other)?);
+        assert!(
+            DictionaryFormat::Dcz
+                .read_header(&mut [0; 40].as_slice(), &dictionary)
+                .is_err()
+        );
+
+        let header = DictionaryFormat::Dcb.header(&dictionary);
+        assert_eq!(header.len(), 36);
+        assert_eq!(header[0..4], DCB_MAGIC);
+        assert!(DictionaryFormat::Dcb.read_header(&mut header.as_slice(), &dictionary)?);
+        Ok(())
+    }
+
+    #[test]
+    fn structured_hash_is_base64_between_colons() {
+        let dictionary = Dictionary::new(Vec::new());
+        assert_eq!(
+            dictionary.structured_hash(),
+            ":47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=:"
+        );
+    }
+
+    #[test]
+    fn previous_tree_uses_the_file_at_the_same_path() -> Result<()> {
+        let root = test_dir("dictionary");
+        fs::create_dir(root.join("js"))?;
+        fs::write(root.join("js/app.js"), "const version = 1;")?;
+        fs::write(root.join("empty.js"), "")?;
+
+        let dictionaries = Dictionaries::PreviousTree(root.clone());
+        let dictionary = dictionaries
+            .get(Path::new("js/app.js"))?
+            .expect("previous version exists");
+        assert_eq!(dictionary.data, b"const version = 1;");
+        assert!(dictionaries.get(Path::new("js/new.js"))?.is_none());
+        assert!(dictionaries.get(Path::new("empty.js"))?.is_none());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -1,24 +1,42 @@
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Result, Write};
+use std::io::{self, Read, Result, Write};
+use std::num::{NonZeroU32, NonZeroU64};
+use std::ops::RangeInclusive;
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use brotli::{
-    BrotliCompressCustomAlloc,
-    enc::{BrotliEncoderParams, StandardAlloc},
+    Decompressor, IoReaderWrapper, IoWriterWrapper,
+    enc::{BrotliCompressCustomIoCustomDict, BrotliEncoderParams, StandardAlloc},
 };
 use flate2::{
     Compression,
+    read::{DeflateDecoder, GzDecoder},
     write::{DeflateEncoder, GzEncoder},
 };
-use zstd::Encoder;
+use zopfli::Format;
+use zstd::{Decoder, Encoder, zstd_safe};
 
+use crate::dictionary::{Dictionary, DictionaryFormat};
 use crate::precompress::Algorithm;
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) struct Quality {
+/// The number of zopfli iterations used when none is given.
+pub const DEFAULT_ZOPFLI_ITERATIONS: NonZeroU64 = NonZeroU64::new(15).unwrap();
+
+/// The compression level of each algorithm, and whether gzip and deflate use
+/// zopfli instead of their regular encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quality {
     pub(crate) brotli: i8,
     pub(crate) deflate: i8,
     pub(crate) gzip: i8,
     pub(crate) zstd: i8,
+    /// Zopfli iterations for deflate, replacing the regular encoder when set.
+    pub(crate) deflate_zopfli: Option<NonZeroU64>,
+    /// Zopfli iterations for gzip, replacing the regular encoder when set.
+    pub(crate) gzip_zopfli: Option<NonZeroU64>,
 }
 
 impl Default for Quality {
@@ -28,57 +46,217 @@ impl Default for Quality {
             deflate: 7,
             gzip: 7,
             zstd: 19,
+            deflate_zopfli: None,
+            gzip_zopfli: None,
         }
     }
 }
 
 impl Quality {
-    pub(crate) fn set(&mut self, algorithm: Algorithm, quality: i8) -> bool {
+    /// Returns the level that `algorithm` compresses at.
+    pub fn get(&self, algorithm: Algorithm) -> i8 {
         match algorithm {
-            Algorithm::Brotli => {
-                if (0..=11).contains(&quality) {
-                    self.brotli = quality;
-                    true
-                } else {
-                    false
-                }
-            }
+            Algorithm::Brotli => self.brotli,
+            Algorithm::Deflate => self.deflate,
+            Algorithm::Gzip => self.gzip,
+            Algorithm::Zstd => self.zstd,
+        }
+    }
+
+    /// Returns the zopfli iteration count when zopfli encodes `algorithm`.
+    pub fn zopfli(&self, algorithm: Algorithm) -> Option<NonZeroU64> {
+        match algorithm {
+            Algorithm::Deflate => self.deflate_zopfli,
+            Algorithm::Gzip => self.gzip_zopfli,
+            Algorithm::Brotli | Algorithm::Zstd => None,
+        }
+    }
+
+    /// Reports whether `algorithm` can be encoded with zopfli.
+    pub fn supports_zopfli(algorithm: Algorithm) -> bool {
+        matches!(algorithm, Algorithm::Deflate | Algorithm::Gzip)
+    }
+
+    /// Encodes `algorithm` with zopfli using `iterations`. Only deflate and
+    /// gzip support zopfli.
+    pub fn set_zopfli(&mut self, algorithm: Algorithm, iterations: NonZeroU64) -> bool {
+        match algorithm {
+            Algorithm::Deflate => self.deflate_zopfli = Some(iterations),
+            Algorithm::Gzip => self.gzip_zopfli = Some(iterations),
+            Algorithm::Brotli | Algorithm::Zstd => return false,
+        }
+        true
+    }
+
+    /// Copies the quality and zopfli setting of `algorithm` from `other`.
+    pub(crate) fn copy_from(&mut self, algorithm: Algorithm, other: &Quality) {
+        match algorithm {
+            Algorithm::Brotli => self.brotli = other.brotli,
             Algorithm::Deflate => {
-                if (1..=9).contains(&quality) {
-                    self.deflate = quality;
-                    true
-                } else {
-                    false
-                }
+                self.deflate = other.deflate;
+                self.deflate_zopfli = other.deflate_zopfli;
             }
             Algorithm::Gzip => {
-                if (1..=9).contains(&quality) {
-                    self.gzip = quality;
-                    true
-                } else {
-                    false
+                self.gzip = other.gzip;
+                self.gzip_zopfli = other.gzip_zopfli;
+            }
+            Algorithm::Zstd => self.zstd = other.zstd,
+        }
+    }
+
+    /// Returns the range of qualities `algorithm` accepts.
+    pub fn range(algorithm: Algorithm) -> RangeInclusive<i8> {
+        match algorithm {
+            Algorithm::Brotli => 0..=11,
+            Algorithm::Deflate | Algorithm::Gzip => 1..=9,
+            Algorithm::Zstd => -7..=22,
+        }
+    }
+
+    /// Sets the level that `algorithm` compresses at, returning false and
+    /// leaving it unchanged if `quality` is outside [`Quality::range`].
+    pub fn set(&mut self, algorithm: Algorithm, quality: i8) -> bool {
+        if !Quality::range(algorithm).contains(&quality) {
+            return false;
+        }
+        match algorithm {
+            Algorithm::Brotli => self.brotli = quality,
+            Algorithm::Deflate => self.deflate = quality,
+            Algorithm::Gzip => self.gzip = quality,
+            Algorithm::Zstd => self.zstd = quality,
+        }
+        true
+    }
+
+    /// Applies a quality override for `algorithm` such as "9", "zopfli", or
+    /// "zopfli=30".
+    pub fn set_from_str(
+        &mut self,
+        algorithm: Algorithm,
+        value: &str,
+    ) -> result::Result<(), ParseCompressionError> {
+        let invalid = || ParseCompressionError::InvalidQuality {
+            algorithm,
+            token: value.to_owned(),
+        };
+        let Some(iterations) = value.strip_prefix("zopfli") else {
+            let quality = value.parse().map_err(|_| invalid())?;
+            return self
+                .set(algorithm, quality)
+                .then_some(())
+                .ok_or_else(invalid);
+        };
+        let iterations = match iterations.strip_prefix('=') {
+            Some(n) => n.parse().ok(),
+            None if iterations.is_empty() => Some(DEFAULT_ZOPFLI_ITERATIONS),
+            None => return Err(invalid()),
+        };
+        let Some(iterations) = iterations else {
+            return Err(ParseCompressionError::InvalidZopfliIterations {
+                algorithm,
+                token: value.to_owned(),
+            });
+        };
+        self.set_zopfli(algorithm, iterations)
+            .then_some(())
+            .ok_or_else(invalid)
+    }
+}
+
+/// Parses qualities in the syntax of `--compression`, such as
+/// "br:11,gz:zopfli". Algorithms named without a quality keep the default.
+impl FromStr for Quality {
+    type Err = ParseCompressionError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut quality = Quality::default();
+        for token in s
+            .split(',')
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+        {
+            let (name, value) = match token.split_once(':') {
+                Some((name, value)) => (name, Some(value)),
+                None => (token, None),
+            };
+            let algorithm = name.parse()?;
+            if let Some(value) = value {
+                quality.set_from_str(algorithm, value)?;
+            }
+        }
+        Ok(quality)
+    }
+}
+
+/// An invalid algorithm or quality in a compression option such as "gz:5".
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseCompressionError {
+    /// The algorithm name is not recognized.
+    UnknownAlgorithm {
+        /// The name as given.
+        token: String,
+        /// The closest known name, if any is close enough to be a typo.
+        suggestion: Option<&'static str>,
+    },
+    /// The quality is not one that the algorithm accepts.
+    InvalidQuality {
+        /// The algorithm the quality was given for.
+        algorithm: Algorithm,
+        /// The quality as given.
+        token: String,
+    },
+    /// The zopfli iteration count is not a positive integer.
+    InvalidZopfliIterations {
+        /// The algorithm the iteration count was given for.
+        algorithm: Algorithm,
+        /// The iteration count as given.
+        token: String,
+    },
+}
+
+impl fmt::Display for ParseCompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseCompressionError::UnknownAlgorithm { token, suggestion } => {
+                write!(f, "unknown compression algorithm \"{token}\"")?;
+                match suggestion {
+                    Some(suggestion) => write!(f, "; did you mean \"{suggestion}\"?"),
+                    None => {
+                        let names = Algorithm::NAMES.map(|(name, _)| name);
+                        write!(f, "; expected one of {}", names.join(", "))
+                    }
                 }
             }
-            Algorithm::Zstd => {
-                if (-7..=22).contains(&quality) {
-                    self.zstd = quality;
-                    true
-                } else {
-                    false
+            ParseCompressionError::InvalidQuality { algorithm, token } => {
+                let range = Quality::range(*algorithm);
+                write!(
+                    f,
+                    "invalid {algorithm} quality \"{token}\": expected {} to {}",
+                    range.start(),
+                    range.end()
+                )?;
+                if Quality::supports_zopfli(*algorithm) {
+                    f.write_str(", or zopfli[=ITERATIONS]")?;
                 }
+                Ok(())
             }
+            ParseCompressionError::InvalidZopfliIterations { algorithm, token } => write!(
+                f,
+                "invalid {algorithm} zopfli iteration count \"{token}\": expected a positive integer"
+            ),
         }
     }
 }
 
+impl std::error::Error for ParseCompressionError {}
+
 pub(crate) struct Context {
     read_buf: Vec<u8>,
     write_buf: Vec<u8>,
 
-    brotli_quality: i32,
-    deflate_quality: u32,
-    gzip_quality: u32,
-    zstd_quality: i32,
+    quality: Quality,
+    zstd_dictionary: Option<Arc<Dictionary>>,
 }
 
 impl Context {
@@ -86,32 +264,111 @@ impl Context {
         Context {
             read_buf: vec![0; buf_size],
             write_buf: vec![0; buf_size],
-            brotli_quality: quality.brotli as i32,
-            deflate_quality: quality.deflate as u32,
-            gzip_quality: quality.gzip as u32,
-            zstd_quality: quality.zstd as i32,
+            quality,
+            zstd_dictionary: None,
         }
     }
 
-    pub(crate) fn write_brotli(&mut self, input: &mut File, output: &mut File) -> Result<()> {
-        let params = BrotliEncoderParams {
-            quality: self.brotli_quality,
+    pub(crate) fn quality(&self) -> &Quality {
+        &self.quality
+    }
+
+    /// Encodes with `quality` from now on, such as for a file matched by a
+    /// config rule.
+    pub(crate) fn set_quality(&mut self, quality: Quality) {
+        self.quality = quality;
+    }
+
+    /// Compresses and decodes zstd variants using `dictionary`.
+    pub(crate) fn with_zstd_dictionary(mut self, dictionary: Option<Arc<Dictionary>>) -> Self {
+        self.zstd_dictionary = dictionary;
+        self
+    }
+
+    /// Reports whether the zstd frame header at the start of `encoded` names
+    /// the configured dictionary, or no dictionary when none is configured.
+    /// Raw content dictionaries have no ID and cannot be told apart.
+    pub(crate) fn zstd_header_matches(&self, encoded: &mut impl Read) -> Result<bool> {
+        let mut header = [0; ZSTD_MAX_FRAME_HEADER];
+        let mut n = 0;
+        while n < header.len() {
+            match encoded.read(&mut header[n..])? {
+                0 => break,
+                m => n += m,
+            }
+        }
+        Ok(zstd_safe::get_dict_id_from_frame(&header[..n]) == self.zstd_dictionary_id())
+    }
+
+    fn zstd_dictionary_id(&self) -> Option<NonZeroU32> {
+        let dictionary = self.zstd_dictionary.as_ref()?;
+        zstd_safe::get_dict_id_from_dict(&dictionary.data)
+    }
+
+    /// Compresses `input` into `output` using `alg`.
+    pub(crate) fn write(
+        &mut self,
+        alg: Algorithm,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        match alg {
+            Algorithm::Brotli => self.write_brotli(input, output),
+            Algorithm::Deflate => self.write_deflate(input, output),
+            Algorithm::Gzip => self.write_gzip(input, output),
+            Algorithm::Zstd => self.write_zstd(input, output),
+        }
+    }
+
+    pub(crate) fn write_brotli(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        self.write_brotli_with_dictionary(&[], input, output)
+    }
+
+    /// Compresses with brotli, treating `dictionary` as if it preceded the
+    /// input when it is not empty.
+    fn write_brotli_with_dictionary(
+        &mut self,
+        dictionary: &[u8],
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        let mut params = BrotliEncoderParams {
+            quality: self.quality.brotli as i32,
             ..Default::default()
         };
-        BrotliCompressCustomAlloc(
-            input,
-            output,
+        if !dictionary.is_empty() {
+            // Use the largest standard window so that as much of the
+            // dictionary as possible can be referenced.
+            params.lgwin = DCB_WINDOW_LOG;
+        }
+        BrotliCompressCustomIoCustomDict(
+            &mut IoReaderWrapper(input),
+            &mut IoWriterWrapper(output),
             &mut self.read_buf,
             &mut self.write_buf,
             &params,
             StandardAlloc::default(),
+            &mut |_, _, _, _| (),
+            dictionary,
+            io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of input"),
         )?;
         Ok(())
     }
 
-    pub(crate) fn write_deflate(&mut self, input: &mut File, output: &mut File) -> Result<()> {
+    pub(crate) fn write_deflate(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        if let Some(iterations) = self.quality.deflate_zopfli {
+            return self.write_zopfli(Format::Deflate, iterations, input, output);
+        }
         let output = BufWriter::new(output, &mut self.write_buf);
-        let mut enc = DeflateEncoder::new(output, Compression::new(self.deflate_quality));
+        let mut enc = DeflateEncoder::new(output, Compression::new(self.quality.deflate as u32));
         loop {
             let n = input.read(&mut self.read_buf)?;
             if n == 0 {
@@ -122,9 +379,16 @@ impl Context {
         }
     }
 
-    pub(crate) fn write_gzip(&mut self, input: &mut File, output: &mut File) -> Result<()> {
+    pub(crate) fn write_gzip(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        if let Some(iterations) = self.quality.gzip_zopfli {
+            return self.write_zopfli(Format::Gzip, iterations, input, output);
+        }
         let output = BufWriter::new(output, &mut self.write_buf);
-        let mut enc = GzEncoder::new(output, Compression::new(self.gzip_quality));
+        let mut enc = GzEncoder::new(output, Compression::new(self.quality.gzip as u32));
         loop {
             let n = input.read(&mut self.read_buf)?;
             if n == 0 {
@@ -135,9 +399,53 @@ impl Context {
         }
     }
 
-    pub(crate) fn write_zstd(&mut self, input: &mut File, output: &mut File) -> Result<()> {
+    /// Compresses with zopfli, which is much slower than the regular deflate
+    /// encoder but produces smaller, fully compatible output.
+    fn write_zopfli(
+        &mut self,
+        format: Format,
+        iterations: NonZeroU64,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        let options = zopfli::Options {
+            iteration_count: iterations,
+            ..Default::default()
+        };
+        let mut output = BufWriter::new(output, &mut self.write_buf);
+        zopfli::compress(options, format, input, &mut output)?;
+        output.flush()
+    }
+
+    pub(crate) fn write_zstd(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        match self.zstd_dictionary.clone() {
+            Some(dictionary) => self.write_zstd_with_dictionary(&dictionary.data, input, output),
+            None => self.write_zstd_with_dictionary(&[], input, output),
+        }
+    }
+
+    /// Compresses `input` with zstd without the configured dictionary, for
+    /// comparison against the output that uses it.
+    pub(crate) fn write_plain_zstd(
+        &mut self,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        self.write_zstd_with_dictionary(&[], input, output)
+    }
+
+    fn write_zstd_with_dictionary(
+        &mut self,
+        dictionary: &[u8],
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
         let output = BufWriter::new(output, &mut self.write_buf);
-        let mut enc = Encoder::new(output, self.zstd_quality)?;
+        let mut enc = Encoder::with_dictionary(output, self.quality.zstd as i32, dictionary)?;
         // Cap the HTTP window at 8 MiB (2^23) for browser support.
         enc.window_log(23)?;
         enc.long_distance_matching(false)?;
@@ -150,6 +458,147 @@ impl Context {
             enc.write_all(&self.read_buf[0..n])?;
         }
     }
+
+    /// Compresses `input` into `output` against `dictionary` using `format`.
+    pub(crate) fn write_dictionary(
+        &mut self,
+        format: DictionaryFormat,
+        dictionary: &Dictionary,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        output.write_all(&format.header(dictionary))?;
+        match format {
+            DictionaryFormat::Dcz => self.write_dcz(dictionary, input, output),
+            DictionaryFormat::Dcb => {
+                self.write_brotli_with_dictionary(&dictionary.data, input, output)
+            }
+        }
+    }
+
+    fn write_dcz(
+        &mut self,
+        dictionary: &Dictionary,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
+        let output = BufWriter::new(output, &mut self.write_buf);
+        let mut enc = Encoder::with_ref_prefix(output, self.quality.zstd as i32, &dictionary.data)?;
+        enc.window_log(dcz_window_log(dictionary.data.len()))?;
+        enc.long_distance_matching(false)?;
+        loop {
+            let n = input.read(&mut self.read_buf)?;
+            if n == 0 {
+                enc.finish()?;
+                return Ok(());
+            }
+            enc.write_all(&self.read_buf[0..n])?;
+        }
+    }
+
+    /// Reports whether `encoded` decodes to exactly the contents of `src`.
+    ///
+    /// Returns an error if `encoded` is not a valid, complete stream.
+    pub(crate) fn decodes_to(
+        &mut self,
+        alg: Algorithm,
+        encoded: &mut File,
+        src: &mut File,
+    ) -> Result<bool> {
+        let mut dec: Box<dyn Read + '_> = match alg {
+            Algorithm::Brotli => Box::new(Decompressor::new(encoded, 1 << 14)),
+            Algorithm::Deflate => Box::new(DeflateDecoder::new(encoded)),
+            Algorithm::Gzip => Box::new(GzDecoder::new(encoded)),
+            Algorithm::Zstd => match &self.zstd_dictionary {
+                Some(dictionary) => Box::new(Decoder::with_dictionary(
+                    io::BufReader::new(encoded),
+                    &dictionary.data,
+                )?),
+                None => Box::new(Decoder::new(encoded)?),
+            },
+        };
+        self.matches(&mut dec, src)
+    }
+
+    /// Reports whether `encoded`, compressed against `dictionary` using
+    /// `format`, decodes to exactly the contents of `src`.
+    ///
+    /// Returns an error if `encoded` is not a valid, complete stream.
+    pub(crate) fn dictionary_decodes_to(
+        &mut self,
+        format: DictionaryFormat,
+        dictionary: &Dictionary,
+        encoded: &mut File,
+        src: &mut File,
+    ) -> Result<bool> {
+        if !format.read_header(encoded, dictionary)? {
+            return Ok(false);
+        }
+        let mut dec: Box<dyn Read + '_> = match format {
+            DictionaryFormat::Dcz => {
+                let mut dec =
+                    Decoder::with_ref_prefix(io::BufReader::new(encoded), &dictionary.data)?;
+                dec.window_log_max(DCZ_MAX_WINDOW_LOG)?;
+                Box::new(dec)
+            }
+            DictionaryFormat::Dcb => Box::new(Decompressor::new_with_custom_dict(
+                encoded,
+                1 << 14,
+                dictionary.data.clone().into(),
+            )),
+        };
+        self.matches(&mut dec, src)
+    }
+
+    fn matches(&mut self, dec: &mut impl Read, src: &mut File) -> Result<bool> {
+        let mut matches = true;
+        loop {
+            let n = if matches {
+                src.read(&mut self.read_buf)?
+            } else {
+                0
+            };
+            if n == 0 {
+                // Drain the decoder so that corrupt streams are reported even
+                // once the contents have diverged; extra output is a mismatch.
+                loop {
+                    if dec.read(&mut self.write_buf)? == 0 {
+                        return Ok(matches);
+                    }
+                    matches = false;
+                }
+            }
+
+            let mut filled = 0;
+            while filled < n {
+                let m = dec.read(&mut self.write_buf[filled..n])?;
+                if m == 0 {
+                    break;
+                }
+                filled += m;
+            }
+            if filled != n || self.write_buf[0..n] != self.read_buf[0..n] {
+                matches = false;
+            }
+        }
+    }
+}
+
+/// The largest possible zstd frame header, including the magic number.
+const ZSTD_MAX_FRAME_HEADER: usize = 18;
+
+/// The brotli window used for `dcb`, 16 MiB.
+const DCB_WINDOW_LOG: i32 = 24;
+
+/// The largest zstd window used for `dcz`, 128 MiB.
+const DCZ_MAX_WINDOW_LOG: u32 = 27;
+
+/// Returns the window size for `dcz` output, which must cover 1.25 times the
+/// dictionary and at least 8 MiB, up to 128 MiB.
+fn dcz_window_log(dictionary_len: usize) -> u32 {
+    let window = (dictionary_len as u64).saturating_mul(5) / 4;
+    let log = u64::BITS - window.saturating_sub(1).leading_zeros();
+    log.clamp(23, DCZ_MAX_WINDOW_LOG)
 }
 
 struct BufWriter<'a, W: Write> {
@@ -200,3 +649,81 @@ impl<W: Write> Drop for BufWriter<'_, W> {
         _ = self.flush();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+
+    use anyhow::Result;
+
+    use super::{Context, DEFAULT_ZOPFLI_ITERATIONS, ParseCompressionError, Quality};
+    use crate::dictionary::{Dictionary, DictionaryFormat};
+    use crate::precompress::Algorithm;
+    use crate::test_util::test_dir;
+
+    // `v2.html` compressed against `v1.html`. `reference.dcb` was written by
+    // the reference brotli 1.2 encoder with `v1.html` attached as a raw shared
+    // dictionary, as browsers attach it, and `precompress.dcb` by
+    // `write_dictionary` at quality 11, then checked with the reference
+    // decoder. If an encoder upgrade changes the output, check the new output
+    // with the reference decoder before replacing `precompress.dcb`.
+    const DCB_DICTIONARY: &[u8] = include_bytes!("../tests/fixtures/dcb/v1.html");
+    const DCB_SOURCE: &[u8] = include_bytes!("../tests/fixtures/dcb/v2.html");
+    const DCB_REFERENCE: &[u8] = include_bytes!("../tests/fixtures/dcb/reference.dcb");
+    const DCB_PRECOMPRESS: &[u8] = include_bytes!("../tests/fixtures/dcb/precompress.dcb");
+
+    #[test]
+    fn quality_parses_from_compression_options() {
+        let quality: Quality = "br:5, gz:zopfli,zstd".parse().expect("valid qualities");
+        assert_eq!(quality.get(Algorithm::Brotli), 5);
+        assert_eq!(
+            quality.zopfli(Algorithm::Gzip),
+            Some(DEFAULT_ZOPFLI_ITERATIONS)
+        );
+        assert_eq!(
+            quality.get(Algorithm::Zstd),
+            Quality::default().get(Algorithm::Zstd)
+        );
+
+        assert_eq!(
+            "br:12".parse::<Quality>(),
+            Err(ParseCompressionError::InvalidQuality {
+                algorithm: Algorithm::Brotli,
+                token: String::from("12"),
+            })
+        );
+        assert!("brotly:5".parse::<Quality>().is_err());
+    }
+
+    #[test]
+    fn dcb_matches_the_reference_implementation() -> Result<()> {
+        let dictionary = Dictionary::new(DCB_DICTIONARY.to_vec());
+        let mut quality = Quality::default();
+        assert!(quality.set(Algorithm::Brotli, 11));
+        let mut ctx = Context::new(1 << 14, quality);
+
+        let mut encoded = Vec::new();
+        ctx.write_dictionary(
+            DictionaryFormat::Dcb,
+            &dictionary,
+            &mut &DCB_SOURCE[..],
+            &mut encoded,
+        )?;
+        assert!(encoded == DCB_PRECOMPRESS);
+
+        // Both streams share the header, and the reference stream decodes.
+        assert_eq!(DCB_REFERENCE[..36], DCB_PRECOMPRESS[..36]);
+        let root = test_dir("dcb-reference");
+        fs::write(root.join("v2.html"), DCB_SOURCE)?;
+        fs::write(root.join("v2.html.dcb"), DCB_REFERENCE)?;
+        assert!(ctx.dictionary_decodes_to(
+            DictionaryFormat::Dcb,
+            &dictionary,
+            &mut File::open(root.join("v2.html.dcb"))?,
+            &mut File::open(root.join("v2.html"))?,
+        )?);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+//! Precompress a directory of static files.
+//!
+//! A [`Compressor`] walks a directory and writes brotli, deflate, gzip and
+//! zstd variants of every compressible file alongside it (or into a separate
+//! output directory), so that a web server can serve them without
+//! compressing on the fly.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use precompress::{Algorithm, Algorithms, Compressor, Quality, WalkOptions};
+//!
+//! let mut quality = Quality::default();
+//! quality.set(Algorithm::Brotli, 11);
+//!
+//! let compressor = Compressor::builder()
+//!     .algorithms(Algorithms::all())
+//!     .quality(quality)
+//!     .incremental(true)
+//!     .build();
+//! compressor.precompress(Path::new("public"), &WalkOptions::default())?;
+//! let stats = compressor.finish();
+//! println!("compressed {} files", stats.num_source_files);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! To compress contents that are already in memory, such as pages rendered
+//! by a static-site generator, use a [`MemoryCompressor`] instead.
+//!
+//! The library never prints. Pass a channel to
+//! [`CompressorBuilder::events`] to receive per-file results and warnings.
+
+#![forbid(unsafe_code)]
+
+// Modules whose types make up the core API are private and re-exported
+// below, so that every type has a single public path.
+mod cache;
+mod clean;
+pub mod config;
+mod dictionary;
+mod encode;
+pub mod manifest;
+mod memory;
+pub mod naming;
+pub mod negotiate;
+mod output;
+mod plan;
+mod precompress;
+mod progress;
+mod report;
+pub mod serve;
+pub mod server_config;
+mod state;
+#[cfg(test)]
+mod test_util;
+mod train;
+
+pub use crate::cache::{Cache, CacheUsage};
+pub use crate::dictionary::{Dictionaries, Dictionary, DictionaryFormat};
+pub use crate::encode::{DEFAULT_ZOPFLI_ITERATIONS, ParseCompressionError, Quality};
+pub use crate::memory::MemoryCompressor;
+pub use crate::output::{Layout, Originals, Output};
+pub use crate::plan::{Decision, PlanEntry, SkipReason};
+pub use crate::precompress::{
+    AlgStat, Algorithm, Algorithms, Compressor, CompressorBuilder, DictStat, KeepBest, MinSavings,
+    Mode, Stats, Threshold, WalkOptions,
+};
+pub use crate::progress::{AlgProgress, Progress};
+pub use crate::report::{Event, FileReport, VariantOutcome, VariantReport};
+
+// Only the command-line tool uses these, so they are not part of the API.
+#[doc(hidden)]
+pub use crate::clean::{CleanOptions, find_removals};
+#[doc(hidden)]
+pub use crate::report::{ReportFormat, Reporter, calc_savings};
+#[doc(hidden)]
+pub use crate::train::{
+    DEFAULT_DICTIONARY_SIZE, DEFAULT_MAX_SAMPLES, TrainOptions, collect_samples, train,
+};
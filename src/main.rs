@@ -1,80 +1,283 @@
 #![forbid(unsafe_code)]
 
 use std::collections::HashSet;
-use std::path::PathBuf;
-use std::process::exit;
-use std::thread::available_parallelism;
+use std::env;
+use std::fs;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::{available_parallelism, spawn};
 use std::time::{Duration, Instant};
 
-use clap::Parser;
+use anyhow::{Context as _, Result, anyhow};
+use clap::{Parser, Subcommand};
 use mimalloc::MiMalloc;
-use precompress::Algorithm;
-
-use crate::encode::Quality;
-use crate::precompress::{Algorithms, Compressor, Stats, WalkOptions};
-
-mod encode;
-mod precompress;
+use precompress::config::Config;
+use precompress::manifest::Manifest;
+use precompress::naming::Naming;
+use precompress::serve::{ServeOptions, Server};
+use precompress::server_config::{ServerConfigOptions, Target, server_config};
+use precompress::{
+    Algorithm, Algorithms, Cache, CacheUsage, CleanOptions, Compressor, CompressorBuilder,
+    DEFAULT_DICTIONARY_SIZE, DEFAULT_MAX_SAMPLES, Decision, Dictionaries, Dictionary,
+    DictionaryFormat, Event, FileReport, KeepBest, Layout, MinSavings, Mode, Originals, Output,
+    ParseCompressionError, Progress, Quality, ReportFormat, Reporter, Stats, TrainOptions,
+    VariantOutcome, VariantReport, WalkOptions, calc_savings, collect_samples, find_removals,
+    train,
+};
+use terminal_size::{Width, terminal_size_of};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-fn main() {
-    let args = Args::parse();
-    let mut threads = args.threads;
-    if threads == 0 {
-        threads = available_parallelism().map(|v| v.get()).unwrap_or(1);
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            ExitCode::FAILURE
+        }
     }
+}
 
-    let (algs, quality) = parse_compression(args.compression);
-
-    if algs.iter().count() == 0 {
-        eprintln!("Error: no compression algorithms enabled");
-        exit(1);
+/// Runs the command given by `args`, returning the status to exit with.
+fn run(mut args: Args) -> Result<ExitCode> {
+    if let Some(command) = args.command.take() {
+        match command {
+            Command::Cache { action, cache_dir } => run_cache(action, cache_dir)?,
+            Command::Clean {
+                path,
+                dry_run,
+                files,
+                config,
+            } => {
+                let config = load_config(&path, config)?;
+                run_clean(&path, dry_run, files, config)?
+            }
+            Command::Serve {
+                path,
+                files,
+                addr,
+                threads,
+                no_compress,
+                config,
+            } => {
+                let config = load_config(&path, config)?;
+                run_serve(&path, files, &addr, threads, no_compress, config)?
+            }
+            Command::ServerConfig {
+                target,
+                files,
+                config,
+            } => {
+                let config = match config {
+                    Some(path) => {
+                        Config::load(&path).with_context(|| path.display().to_string())?
+                    }
+                    None => Config::default(),
+                };
+                run_server_config(target, files, config)?
+            }
+            Command::Check {
+                path,
+                files,
+                min_size,
+                threads,
+                verbose,
+                config,
+                savings,
+                dictionaries,
+            } => {
+                let config = load_config(&path, config)?;
+                return run_check(
+                    &path,
+                    files,
+                    min_size,
+                    threads,
+                    verbose,
+                    config,
+                    savings,
+                    dictionaries,
+                );
+            }
+            Command::TrainDict {
+                path,
+                dictionary,
+                files,
+                min_size,
+                size,
+                max_samples,
+                config,
+            } => {
+                let config = load_config(&path, config)?;
+                run_train_dict(
+                    &path,
+                    &dictionary,
+                    files,
+                    min_size,
+                    size,
+                    max_samples,
+                    config,
+                )?
+            }
+        }
+        return Ok(ExitCode::SUCCESS);
     }
 
-    let exts = args
-        .extensions
-        .map(|v| split_csv(v).collect::<HashSet<String>>());
-    let walk_options = WalkOptions {
-        respect_ignore: !args.no_respect_ignore,
-        exclude: args.exclude.map(split_csv).into_iter().flatten().collect(),
+    let Some(path) = args.path.take() else {
+        unreachable!("clap requires a path without a subcommand");
     };
+    let config = load_config(&path, args.config.take())?;
+    let files = args.files.resolve(None, args.originals, &config)?;
 
-    let cmp = Compressor::new(threads, args.min_size, quality, algs, exts, args.verbose);
-    let start = Instant::now();
-    if let Err(err) = cmp.precompress(&args.path, &walk_options) {
-        eprintln!("Error: {err}");
-        exit(1);
+    let cache_enabled = args.cache || args.cache_dir.is_some();
+    let cache = if cache_enabled {
+        Some(Cache::new(
+            args.cache_dir.map_or_else(default_cache_dir, Ok)?,
+        ))
+    } else {
+        None
+    };
+
+    let algs = files.algorithms;
+    let dry_run = args.dry_run;
+    let dictionaries = args.dictionaries.resolve()?;
+    let dictionaries_enabled = dictionaries.is_some();
+    let zstd_dictionary_enabled = files.zstd_dictionary.is_some();
+    let keep_best = args.savings.keep_best;
+    let min_savings = args.savings.resolve()?;
+    let manifest = args
+        .manifest
+        .as_ref()
+        .map(|_| Manifest::new(&path, &files.output));
+    let builder = Compressor::builder()
+        .threads(args.threads)
+        .min_size(args.min_size)
+        .quality(files.quality)
+        .zstd_dictionary(files.zstd_dictionary)
+        .algorithms(algs)
+        .extensions(files.extensions)
+        .incremental(args.incremental)
+        .verify(args.verify)
+        .cache(cache)
+        .output(files.output)
+        .dictionaries(dictionaries)
+        .dictionary_formats(args.dictionaries.dictionary_format.clone())
+        .min_savings(min_savings)
+        .keep_best(keep_best)
+        .config(config);
+    if dry_run {
+        print_plan(&path, &builder, &files.walk_options, args.estimate)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Events are handled on a single thread so that output from different
+    // workers is never interleaved.
+    let (event_tx, event_rx) = mpsc::channel();
+    let reporter = args
+        .report
+        .map(|format| Reporter::new(format, BufWriter::new(io::stdout())));
+    let verbose = args.verbose;
+    let progress = ProgressLine::new(verbose);
+    let drawing = progress.is_some();
+    let events = spawn(move || {
+        handle_events(
+            event_rx,
+            Mode::Compress,
+            verbose,
+            drawing,
+            reporter,
+            manifest,
+        )
+    });
+
+    let mut builder = builder.events(event_tx);
+    if let Some(progress) = progress.clone() {
+        builder = builder.on_progress(move |p| progress.draw(p));
     }
+    let cmp = builder.build();
+    let start = Instant::now();
+    cmp.precompress(&path, &files.walk_options)?;
     let stats = cmp.finish();
     let took = start.elapsed();
+    if let Some(progress) = &progress {
+        progress.clear();
+    }
+
+    let (reporter, manifest) = events.join().expect("unable to join event thread");
+    let result = reporter.and_then(|reporter| match reporter {
+        Some(reporter) => reporter.finish(took, &stats).map(drop),
+        None => Ok(()),
+    });
+    result.context("unable to write report")?;
+    if let (Some(manifest), Some(manifest_path)) = (manifest, &args.manifest) {
+        manifest
+            .save(manifest_path)
+            .with_context(|| format!("unable to write manifest {}", manifest_path.display()))?;
+    }
 
     eprintln!(
         "Compressed {} source files in {}",
         stats.num_source_files,
         format_duration(took)
     );
+    if args.incremental {
+        eprintln!("Skipped {} up-to-date source files", stats.num_up_to_date);
+    }
+    if cache_enabled {
+        eprintln!("Reused {} cached variants", stats.num_cache_hits);
+    }
+    if args.originals != Originals::None {
+        eprintln!("Placed {} original files", stats.num_originals);
+    }
+    if stats.num_discarded > 0 {
+        eprintln!(
+            "Discarded {} variants below the minimum savings",
+            stats.num_discarded
+        );
+    }
+    if let Some(keep_best) = keep_best
+        && stats.num_pruned > 0
+    {
+        eprintln!(
+            "Pruned {} variants that did not beat {}",
+            stats.num_pruned, keep_best.baseline
+        );
+    }
     eprintln!("Data compression:");
     for alg in algs.iter() {
         print_alg_savings(alg, &stats);
     }
+    if algs.is_enabled(Algorithm::Zstd) && zstd_dictionary_enabled {
+        print_zstd_dictionary_savings(&stats);
+    }
+    if dictionaries_enabled {
+        for format in &args.dictionaries.dictionary_format {
+            print_dict_savings(*format, &stats);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
 }
 
 /// Precompress a directory of static files.
 #[derive(Parser, Debug)]
-#[clap(version, about)]
+#[clap(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
-    /// Directory to recursively compress files in.
-    path: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
 
-    /// Compression algorithms to use.
-    #[clap(short, long)]
-    compression: Option<Vec<String>>,
+    /// Directory to recursively compress files in.
+    #[clap(required = true)]
+    path: Option<PathBuf>,
 
-    /// Extensions of files that should be compressed.
-    #[clap(short, long)]
-    extensions: Option<Vec<String>>,
+    #[clap(flatten)]
+    files: FileArgs,
 
     /// Set the minimum size of files to be compressed in bytes.
     #[clap(short, long, default_value = "1024")]
@@ -88,6 +291,65 @@ struct Args {
     #[clap(short, long)]
     verbose: bool,
 
+    /// Skip files whose compressed variants are already up to date.
+    #[clap(short, long)]
+    incremental: bool,
+
+    /// Print what would be compressed or skipped, and why, without writing anything.
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// Estimate compressed sizes in a dry run by compressing a sample of each file.
+    #[clap(long, requires = "dry_run")]
+    estimate: bool,
+
+    /// Decode each compressed file and compare it to its source before saving it.
+    #[clap(long)]
+    verify: bool,
+
+    /// Reuse compressed variants from a cache shared across runs.
+    #[clap(long)]
+    cache: bool,
+
+    /// Directory of the compression cache; implies `--cache`.
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// How to place original files into the output directory.
+    #[clap(long, value_enum, default_value = "none", requires = "out_dir")]
+    originals: Originals,
+
+    #[clap(flatten)]
+    dictionaries: DictionaryArgs,
+
+    /// Write per-file results and totals to stdout in a machine-readable format.
+    #[clap(long, value_enum, conflicts_with = "dry_run")]
+    report: Option<ReportFormat>,
+
+    /// Write a JSON manifest of every compressed file's variants, sizes and hashes.
+    #[clap(long, value_name = "FILE", conflicts_with = "dry_run")]
+    manifest: Option<PathBuf>,
+
+    /// Read per-file rules from this file instead of `precompress.toml` in the directory.
+    #[clap(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    savings: SavingsArgs,
+}
+
+/// Options selecting the source files and where their variants live, shared
+/// by every command that walks a tree.
+#[derive(clap::Args, Debug)]
+struct FileArgs {
+    /// Compression algorithms to use.
+    #[clap(short, long)]
+    compression: Option<Vec<String>>,
+
+    /// Extensions of files that should be compressed.
+    #[clap(short, long)]
+    extensions: Option<Vec<String>>,
+
     /// Do not respect ignore files such as `.gitignore` and `.ignore`.
     #[clap(long)]
     no_respect_ignore: bool,
@@ -95,79 +357,884 @@ struct Args {
     /// Exclude paths matching a gitignore-style glob.
     #[clap(long)]
     exclude: Option<Vec<String>>,
+
+    /// Write compressed files to a separate directory mirroring the source tree.
+    #[clap(short, long)]
+    out_dir: Option<PathBuf>,
+
+    /// How compressed files are named and laid out.
+    #[clap(
+        long,
+        value_enum,
+        default_value = "sibling",
+        requires_if("per-encoding", "out_dir")
+    )]
+    layout: Layout,
+
+    /// Variant file name template for an algorithm, e.g. "zstd={name}.zstd".
+    #[clap(long, value_name = "ALG=TEMPLATE")]
+    name_template: Option<Vec<String>>,
+
+    /// Compress zstd variants with a dictionary, such as one from `train-dict`.
+    #[clap(long, value_name = "FILE")]
+    zstd_dict: Option<PathBuf>,
 }
 
-fn parse_compression(compression: Option<Vec<String>>) -> (Algorithms, Quality) {
-    let mut quality = Quality::default();
+/// Options for dictionary-compressed variants, shared by compressing and
+/// `check`.
+#[derive(clap::Args, Debug)]
+struct DictionaryArgs {
+    /// Also write dictionary-compressed variants using this dictionary.
+    #[clap(long, value_name = "FILE", conflicts_with = "dictionary_dir")]
+    dictionary: Option<PathBuf>,
 
-    let algs = compression
-        .map(|v| {
-            let raw = v
-                .into_iter()
-                .flat_map(|s| s.split(',').map(|s| s.to_owned()).collect::<Vec<_>>());
-            let mut algs = Algorithms::empty();
-            for s in raw {
-                let (c, q) = if let Some((c, q)) = s.split_once(':') {
-                    let q: i8 = match q.parse() {
-                        Ok(q) => q,
-                        Err(_) => {
-                            eprintln!("Error: invalid compression quality: {q}");
-                            exit(1);
-                        }
-                    };
-                    (c, Some(q))
-                } else {
-                    (s.as_ref(), None)
-                };
+    /// Also write dictionary-compressed variants using each file's previous version in this directory.
+    #[clap(long, value_name = "DIR")]
+    dictionary_dir: Option<PathBuf>,
 
-                match c {
-                    "br" | "brotli" => {
-                        algs.brotli = true;
-                        if let Some(q) = q
-                            && !quality.set(Algorithm::Brotli, q)
-                        {
-                            eprintln!("Error: invalid brotli compression quality: {q}");
-                            exit(1);
-                        }
-                    }
-                    "de" | "deflate" => {
-                        algs.deflate = true;
-                        if let Some(q) = q
-                            && !quality.set(Algorithm::Deflate, q)
-                        {
-                            eprintln!("Error: invalid deflate compression quality: {q}");
-                            exit(1);
-                        }
-                    }
-                    "gz" | "gzip" => {
-                        algs.gzip = true;
-                        if let Some(q) = q
-                            && !quality.set(Algorithm::Gzip, q)
-                        {
-                            eprintln!("Error: invalid gzip compression quality: {q}");
-                            exit(1);
-                        }
-                    }
-                    "zst" | "zstd" => {
-                        algs.zstd = true;
-                        if let Some(q) = q
-                            && !quality.set(Algorithm::Zstd, q)
-                        {
-                            eprintln!("Error: invalid zstd compression quality: {q}");
-                            exit(1);
-                        }
-                    }
-                    _ => {
-                        eprintln!("Error: unknown compression algorithm: {s}");
-                        exit(1);
-                    }
+    /// Dictionary-compressed formats to write.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        value_delimiter = ',',
+        default_value = "dcz"
+    )]
+    dictionary_format: Vec<DictionaryFormat>,
+}
+
+impl DictionaryArgs {
+    /// Loads the dictionary options.
+    fn resolve(&self) -> Result<Option<Dictionaries>> {
+        if let Some(path) = &self.dictionary {
+            let dictionary = Dictionary::load(path).with_context(|| path.display().to_string())?;
+            return Ok(Some(Dictionaries::File(Arc::new(dictionary))));
+        }
+        let Some(dir) = &self.dictionary_dir else {
+            return Ok(None);
+        };
+        if !dir.is_dir() {
+            return Err(anyhow!("{}: not a directory", dir.display()));
+        }
+        Ok(Some(Dictionaries::PreviousTree(dir.clone())))
+    }
+}
+
+/// The minimum savings a compressed file must achieve to be kept.
+#[derive(clap::Args, Debug)]
+struct SavingsArgs {
+    /// Discard compressed files saving less than this fraction of their source, e.g. "0.05",
+    /// "5%" or "br=10%".
+    #[clap(long, value_name = "[ALG=]RATIO")]
+    min_ratio: Option<Vec<String>>,
+
+    /// Discard compressed files saving fewer bytes than this, e.g. "512" or "gz=1K".
+    #[clap(long, value_name = "[ALG=]SIZE")]
+    min_saved_bytes: Option<Vec<String>>,
+
+    /// Remove compressed files that are not smaller than this algorithm's, optionally by a
+    /// margin, e.g. "gz" or "gz:2%".
+    #[clap(long, value_name = "ALG[:MARGIN]", value_parser = parse_keep_best)]
+    keep_best: Option<KeepBest>,
+}
+
+impl SavingsArgs {
+    /// Parses the thresholds.
+    fn resolve(self) -> Result<MinSavings> {
+        parse_min_savings(self.min_ratio, self.min_saved_bytes).map_err(|err| anyhow!(err))
+    }
+}
+
+struct Files {
+    algorithms: Algorithms,
+    quality: Quality,
+    zstd_dictionary: Option<Arc<Dictionary>>,
+    extensions: Option<HashSet<String>>,
+    walk_options: WalkOptions,
+    output: Output,
+}
+
+impl FileArgs {
+    /// Parses the file selection. `default_algs` replaces the default
+    /// algorithms when none are given, and name templates override those of
+    /// `config`.
+    fn resolve(
+        self,
+        default_algs: Option<Algorithms>,
+        originals: Originals,
+        config: &Config,
+    ) -> Result<Files> {
+        let (mut algorithms, quality) = parse_compression(self.compression.clone())?;
+        if let Some(default_algs) = default_algs
+            && self.compression.is_none()
+        {
+            algorithms = default_algs;
+        }
+
+        if algorithms.iter().count() == 0 {
+            return Err(anyhow!("no compression algorithms enabled"));
+        }
+
+        let naming =
+            parse_naming(config.naming(), self.name_template).map_err(|err| anyhow!(err))?;
+        naming.validate(algorithms).map_err(|err| anyhow!(err))?;
+
+        let zstd_dictionary = match self.zstd_dict {
+            Some(path) => Some(Arc::new(
+                Dictionary::load(&path).with_context(|| path.display().to_string())?,
+            )),
+            None => None,
+        };
+
+        Ok(Files {
+            algorithms,
+            quality,
+            zstd_dictionary,
+            extensions: self
+                .extensions
+                .map(|v| split_csv(v).collect::<HashSet<String>>()),
+            walk_options: WalkOptions::new(
+                !self.no_respect_ignore,
+                self.exclude.map(split_csv).into_iter().flatten().collect(),
+            ),
+            output: Output::new(self.out_dir, originals, self.layout, naming),
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect and manage the compression cache.
+    Cache {
+        #[clap(subcommand)]
+        action: Option<CacheAction>,
+
+        /// Cache directory to operate on.
+        #[clap(long, global = true)]
+        cache_dir: Option<PathBuf>,
+    },
+
+    /// Verify that every source has current compressed files, without writing.
+    ///
+    /// Exits with a non-zero status if any are missing, stale or corrupt.
+    Check {
+        /// Directory to recursively check files in.
+        path: PathBuf,
+
+        #[clap(flatten)]
+        files: FileArgs,
+
+        /// Set the minimum size of files to be compressed in bytes.
+        #[clap(short, long, default_value = "1024")]
+        min_size: u64,
+
+        /// Number of threads to use; "0" uses the number of cpus.
+        #[clap(short, long, default_value = "0")]
+        threads: usize,
+
+        /// Print the status of every compressed file.
+        #[clap(short, long)]
+        verbose: bool,
+
+        /// Read per-file rules from this file instead of `precompress.toml` in the directory.
+        #[clap(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+
+        #[clap(flatten)]
+        savings: SavingsArgs,
+
+        #[clap(flatten)]
+        dictionaries: DictionaryArgs,
+    },
+
+    /// Serve a directory over HTTP, negotiating each file's compressed variant.
+    ///
+    /// Meant for checking a precompressed tree locally. Serves the variants
+    /// of every algorithm unless `--compression` is given.
+    Serve {
+        /// Directory to serve.
+        path: PathBuf,
+
+        #[clap(flatten)]
+        files: FileArgs,
+
+        /// Address to listen on.
+        #[clap(long, default_value = "127.0.0.1:8000")]
+        addr: String,
+
+        /// Number of threads to use; "0" uses the number of cpus.
+        #[clap(short, long, default_value = "0")]
+        threads: usize,
+
+        /// Serve files without a suitable variant as is, instead of compressing them on the fly.
+        #[clap(long)]
+        no_compress: bool,
+
+        /// Read per-file rules from this file instead of `precompress.toml` in the directory.
+        #[clap(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+
+    /// Print web server configuration that serves the compressed files.
+    ///
+    /// Pass the same file options as the compression run so the server
+    /// looks for the files it writes.
+    ServerConfig {
+        /// Web server to configure.
+        #[clap(long, value_enum)]
+        target: Target,
+
+        #[clap(flatten)]
+        files: FileArgs,
+
+        /// Read the per-file rules of the compression run from this file.
+        #[clap(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+
+    /// Remove orphaned, stale and excluded compressed files.
+    ///
+    /// Considers every algorithm's variants unless `--compression` is given.
+    Clean {
+        /// Directory to clean compressed files of.
+        path: PathBuf,
+
+        /// List what would be removed without removing anything.
+        #[clap(short = 'n', long)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        files: FileArgs,
+
+        /// Read per-file rules from this file instead of `precompress.toml` in the directory.
+        #[clap(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+
+    /// Train a zstd dictionary from the files that would be compressed.
+    ///
+    /// Pass the dictionary to `--zstd-dict` when compressing.
+    TrainDict {
+        /// Directory to recursively sample files from.
+        path: PathBuf,
+
+        /// File to write the dictionary to.
+        dictionary: PathBuf,
+
+        #[clap(flatten)]
+        files: FileArgs,
+
+        /// Set the minimum size of files to be sampled in bytes.
+        #[clap(short, long, default_value = "1024")]
+        min_size: u64,
+
+        /// Maximum size of the dictionary, e.g. "64K".
+        #[clap(long, value_parser = parse_size, default_value_t = DEFAULT_DICTIONARY_SIZE)]
+        size: u64,
+
+        /// Maximum number of files to sample, spread evenly across the tree.
+        #[clap(long, default_value_t = DEFAULT_MAX_SAMPLES)]
+        max_samples: usize,
+
+        /// Read per-file rules from this file instead of `precompress.toml` in the directory.
+        #[clap(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+}
+
+fn print_plan(
+    path: &Path,
+    builder: &CompressorBuilder,
+    walk_options: &WalkOptions,
+    estimate: bool,
+) -> Result<()> {
+    let entries = builder.plan(path, walk_options, estimate)?;
+
+    let (mut num_compress, mut num_skip, mut total_bytes) = (0, 0, 0);
+    for entry in &entries {
+        match entry.decision {
+            Decision::Compress => {
+                num_compress += 1;
+                total_bytes += entry.size;
+                let mut estimates = entry
+                    .estimates
+                    .iter()
+                    .map(|(alg, size)| format!(", {alg} ~{}", format_bytes(*size)))
+                    .collect::<String>();
+                if let Some(err) = &entry.estimate_error {
+                    estimates.push_str(&format!(", unable to estimate: {err}"));
                 }
+                println!(
+                    "compress: {} ({}{})",
+                    entry.path.display(),
+                    format_bytes(entry.size),
+                    estimates
+                );
+            }
+            Decision::Skip(reason) => {
+                num_skip += 1;
+                println!("skip: {} ({})", entry.path.display(), reason);
+            }
+        }
+    }
+    eprintln!(
+        "Would compress {} source files ({}); skipped {}",
+        num_compress,
+        format_bytes(total_bytes),
+        num_skip
+    );
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_check(
+    path: &Path,
+    files: FileArgs,
+    min_size: u64,
+    threads: usize,
+    verbose: bool,
+    config: Config,
+    savings: SavingsArgs,
+    dictionaries: DictionaryArgs,
+) -> Result<ExitCode> {
+    let files = files.resolve(None, Originals::None, &config)?;
+    let keep_best = savings.keep_best;
+    let min_savings = savings.resolve()?;
+    let (event_tx, event_rx) = mpsc::channel();
+    let progress = ProgressLine::new(verbose);
+    let drawing = progress.is_some();
+    let events = spawn(move || handle_events(event_rx, Mode::Check, verbose, drawing, None, None));
+    let mut builder = Compressor::builder()
+        .threads(threads)
+        .mode(Mode::Check)
+        .min_size(min_size)
+        .quality(files.quality)
+        .zstd_dictionary(files.zstd_dictionary)
+        .algorithms(files.algorithms)
+        .extensions(files.extensions)
+        .output(files.output)
+        .min_savings(min_savings)
+        .keep_best(keep_best)
+        .dictionaries(dictionaries.resolve()?)
+        .dictionary_formats(dictionaries.dictionary_format)
+        .config(config)
+        .events(event_tx);
+    if let Some(progress) = progress.clone() {
+        builder = builder.on_progress(move |p| progress.draw(p));
+    }
+    let cmp = builder.build();
+    cmp.precompress(path, &files.walk_options)?;
+    let stats = cmp.finish();
+    if let Some(progress) = &progress {
+        progress.clear();
+    }
+    _ = events.join().expect("unable to join event thread");
+
+    eprintln!(
+        "Checked {} source files: {} missing, {} stale, {} corrupt",
+        stats.num_source_files, stats.num_missing, stats.num_stale, stats.num_corrupt
+    );
+    if stats.num_missing + stats.num_stale + stats.num_corrupt + stats.num_errors > 0 {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run_clean(path: &Path, dry_run: bool, files: FileArgs, config: Config) -> Result<()> {
+    let files = files.resolve(Some(Algorithms::all()), Originals::None, &config)?;
+    let options = CleanOptions {
+        algorithms: files.algorithms,
+        extensions: files.extensions,
+        output: files.output,
+        zstd_dictionary: files.zstd_dictionary,
+        config,
+    };
+    let removals = find_removals(path, &options, &files.walk_options)?;
+
+    let mut removed = 0;
+    for removal in &removals {
+        if dry_run {
+            println!("{} ({})", removal.path.display(), removal.reason);
+            continue;
+        }
+        match fs::remove_file(&removal.path) {
+            Ok(()) => {
+                eprintln!("Removed {} ({})", removal.path.display(), removal.reason);
+                removed += 1;
+            }
+            Err(err) => eprintln!("Warning: {}: {}", removal.path.display(), err),
+        }
+    }
+    if dry_run {
+        eprintln!("Would remove {} files", removals.len());
+    } else {
+        eprintln!("Removed {removed} files");
+    }
+    Ok(())
+}
+
+fn run_serve(
+    path: &Path,
+    files: FileArgs,
+    addr: &str,
+    threads: usize,
+    no_compress: bool,
+    config: Config,
+) -> Result<()> {
+    let files = files.resolve(Some(Algorithms::all()), Originals::None, &config)?;
+    let options = ServeOptions {
+        algorithms: files.algorithms,
+        quality: files.quality,
+        extensions: files.extensions,
+        output: files.output,
+        compress_missing: !no_compress,
+        zstd_dictionary: files.zstd_dictionary.is_some(),
+    };
+    let server = Server::bind(path, addr, options)?;
+    let addr = server
+        .local_addr()
+        .map_or_else(|| addr.to_owned(), |addr| addr.to_string());
+    eprintln!("Serving {} at http://{}/", path.display(), addr);
+
+    let threads = match threads {
+        0 => available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    server.run(threads, |served| {
+        let encoding = match served.encoding {
+            Some(alg) if served.on_the_fly => format!(" {} (on the fly)", alg.encoding()),
+            Some(alg) => format!(" {}", alg.encoding()),
+            None => String::new(),
+        };
+        eprintln!(
+            "{} {} {}{}",
+            served.method, served.url, served.status, encoding
+        );
+    })?;
+    Ok(())
+}
+
+fn run_server_config(target: Target, files: FileArgs, config: Config) -> Result<()> {
+    let files = files.resolve(None, Originals::None, &config)?;
+    let options = ServerConfigOptions {
+        // The server must find the variants of algorithms that rules enable
+        // for some files only.
+        algorithms: config.all_algorithms(files.algorithms),
+        extensions: files.extensions,
+        output: files.output,
+        zstd_dictionary: files.zstd_dictionary.is_some(),
+    };
+    print!("{}", server_config(target, &options)?);
+    Ok(())
+}
+
+fn run_train_dict(
+    path: &Path,
+    dictionary: &Path,
+    files: FileArgs,
+    min_size: u64,
+    size: u64,
+    max_samples: usize,
+    config: Config,
+) -> Result<()> {
+    let files = files.resolve(Some(Algorithms::all()), Originals::None, &config)?;
+    let options = TrainOptions {
+        min_size,
+        algorithms: files.algorithms,
+        extensions: files.extensions,
+        output: files.output,
+        max_samples,
+        config,
+    };
+    let start = Instant::now();
+    let samples = collect_samples(path, &options, &files.walk_options)?;
+    let data = train(&samples, size as usize)?;
+    fs::write(dictionary, &data)?;
+    eprintln!(
+        "Trained a {} dictionary from {} files ({}) in {}",
+        format_bytes(data.len() as u64),
+        samples.len(),
+        format_bytes(samples.iter().map(|s| s.len() as u64).sum()),
+        format_duration(start.elapsed())
+    );
+    Ok(())
+}
+
+type StdoutReporter = Reporter<BufWriter<io::Stdout>>;
+
+/// Prints warnings, and the result of every variant when `verbose`, as
+/// events arrive, feeding each file's results to `reporter` and `manifest`
+/// if given.
+fn handle_events(
+    events: mpsc::Receiver<Event>,
+    mode: Mode,
+    verbose: bool,
+    progress: bool,
+    mut reporter: Option<StdoutReporter>,
+    mut manifest: Option<Manifest>,
+) -> (io::Result<Option<StdoutReporter>>, Option<Manifest>) {
+    let mut result = Ok(());
+    for event in events {
+        let report = match event {
+            Event::Warning(warning) => {
+                print_lines(&[format!("Warning: {warning}")], progress);
+                continue;
+            }
+            Event::File(report) => report,
+        };
+        print_lines(&file_lines(&report, mode, verbose), progress);
+        if let Some(manifest) = &mut manifest {
+            manifest.add(&report);
+        }
+        if let Some(reporter) = &mut reporter
+            && result.is_ok()
+        {
+            result = reporter.file(report);
+        }
+    }
+    (result.map(|()| reporter), manifest)
+}
+
+fn file_lines(report: &FileReport, mode: Mode, verbose: bool) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(err) = &report.error {
+        lines.push(format!("Warning: {}: {}", report.path, err));
+    }
+    for variant in &report.variants {
+        if let Some(err) = &variant.error {
+            lines.push(format!("Warning: {}: {}", report.path, err));
+        } else if mode == Mode::Check {
+            let expected = matches!(
+                variant.status,
+                VariantOutcome::UpToDate | VariantOutcome::Discarded | VariantOutcome::Pruned
+            );
+            if verbose || !expected {
+                lines.push(format!(
+                    "{}: {}: {}",
+                    check_status(variant.status),
+                    variant.algorithm,
+                    report.path
+                ));
             }
-            algs
+        } else if verbose {
+            lines.push(format!(
+                "{}: {} ({})",
+                variant.algorithm,
+                report.path,
+                describe_variant(variant)
+            ));
+        }
+    }
+    // Without per-variant lines, still report which encodings were pruned.
+    if mode == Mode::Compress && !verbose {
+        let pruned = report
+            .variants
+            .iter()
+            .filter(|variant| variant.status == VariantOutcome::Pruned)
+            .map(|variant| variant.algorithm.as_str())
+            .collect::<Vec<_>>();
+        if !pruned.is_empty() {
+            lines.push(format!("Pruned {}: {}", pruned.join(", "), report.path));
+        }
+    }
+    lines
+}
+
+/// Prints `lines` to stderr, first clearing the progress line if one is
+/// being drawn; the next update draws it again below them.
+fn print_lines(lines: &[String], progress: bool) {
+    if lines.is_empty() {
+        return;
+    }
+    let mut stderr = io::stderr().lock();
+    if progress {
+        _ = write!(stderr, "{CLEAR_LINE}");
+    }
+    for line in lines {
+        _ = writeln!(stderr, "{line}");
+    }
+}
+
+/// Moves the cursor to the start of the line and erases it.
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
+/// Draws a single status line on stderr while a run is in progress.
+struct ProgressLine {
+    /// The snapshot that throughput is measured against, renewed every
+    /// second so that it reflects the current rate.
+    baseline: Mutex<Option<Progress>>,
+}
+
+impl ProgressLine {
+    /// Returns a progress line if stderr is a terminal and per-file results
+    /// are not being printed.
+    fn new(verbose: bool) -> Option<Arc<Self>> {
+        (!verbose && io::stderr().is_terminal()).then(|| {
+            Arc::new(ProgressLine {
+                baseline: Mutex::new(None),
+            })
         })
-        .unwrap_or_default();
+    }
+
+    fn draw(&self, progress: &Progress) {
+        let mut baseline = self.baseline.lock().expect("lock poisoned");
+        let line = format_progress(progress, baseline.as_ref());
+        if baseline
+            .as_ref()
+            .is_none_or(|b| progress.elapsed - b.elapsed >= Duration::from_secs(1))
+        {
+            *baseline = Some(progress.clone());
+        }
+        let width = terminal_width().saturating_sub(1).max(1);
+        let line = line.chars().take(width).collect::<String>();
+        _ = write!(io::stderr().lock(), "{CLEAR_LINE}{line}");
+    }
+
+    fn clear(&self) {
+        _ = write!(io::stderr().lock(), "{CLEAR_LINE}");
+    }
+}
 
-    (algs, quality)
+/// Returns the width of the terminal on stderr, falling back to `COLUMNS` and
+/// then to 80 columns when it cannot be queried.
+fn terminal_width() -> usize {
+    terminal_size_of(io::stderr())
+        .map(|(Width(width), _)| usize::from(width))
+        .or_else(|| env::var("COLUMNS").ok()?.parse().ok())
+        .unwrap_or(80)
+}
+
+/// Formats files and bytes done, the time left, and the rate at which each
+/// algorithm has encoded since `baseline`.
+fn format_progress(progress: &Progress, baseline: Option<&Progress>) -> String {
+    let mut line = format!(
+        "{}/{}{} files",
+        progress.files_done,
+        progress.files_discovered,
+        if progress.walk_done { "" } else { "+" }
+    );
+    if let Some(eta) = progress.eta() {
+        line += &format!(", ETA {}", format_eta(eta));
+    }
+    line += &format!(", {} read", format_bytes(progress.bytes_done));
+    if progress.bytes_written > 0 {
+        line += &format!(", {} written", format_bytes(progress.bytes_written));
+    }
+    let Some(baseline) = baseline else {
+        return line;
+    };
+    let secs = (progress.elapsed.saturating_sub(baseline.elapsed)).as_secs_f64();
+    if secs <= 0.0 {
+        return line;
+    }
+    for (alg, before) in progress.algorithms.iter().zip(&baseline.algorithms) {
+        let rate = (alg.bytes_in - before.bytes_in) as f64 / secs;
+        if alg.bytes_in > 0 {
+            line += &format!(", {} {}/s", alg.algorithm, format_bytes(rate as u64));
+        }
+    }
+    line
+}
+
+fn check_status(status: VariantOutcome) -> &'static str {
+    match status {
+        VariantOutcome::UpToDate => "current",
+        VariantOutcome::Missing => "missing",
+        VariantOutcome::Stale => "stale",
+        VariantOutcome::Corrupt => "corrupt",
+        VariantOutcome::Discarded => "discarded",
+        VariantOutcome::Pruned => "pruned",
+        _ => "unknown",
+    }
+}
+
+fn describe_variant(variant: &VariantReport) -> String {
+    if variant.status == VariantOutcome::UpToDate {
+        return String::from("up to date");
+    }
+    if variant.status == VariantOutcome::Discarded {
+        return String::from("discarded, below the minimum savings");
+    }
+    if variant.status == VariantOutcome::Pruned {
+        return String::from("pruned, beaten by the baseline");
+    }
+    let savings = variant.savings.unwrap_or_default();
+    let size = variant.size.unwrap_or_default();
+    match (variant.dictionary_hash.is_some(), variant.baseline_size) {
+        (true, Some(baseline)) => format!(
+            "{}%, {} vs {}",
+            savings,
+            format_bytes(size),
+            format_bytes(baseline)
+        ),
+        _ => {
+            let saved = variant.saved_bytes.unwrap_or_default();
+            let sign = if saved < 0 { "-" } else { "" };
+            format!(
+                "{}%, {}{}",
+                savings,
+                sign,
+                format_bytes(saved.unsigned_abs())
+            )
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// Print the location and size of the cache (default).
+    Info,
+
+    /// Remove the least recently used entries until the cache fits a size limit.
+    Prune {
+        /// Maximum size of the cache, e.g. "500M" or "2G".
+        #[clap(long, value_parser = parse_size)]
+        max_size: u64,
+    },
+
+    /// Remove every entry from the cache.
+    Clear,
+}
+
+fn run_cache(action: Option<CacheAction>, cache_dir: Option<PathBuf>) -> Result<()> {
+    let cache = Cache::new(cache_dir.map_or_else(default_cache_dir, Ok)?);
+    let result = match action.unwrap_or(CacheAction::Info) {
+        CacheAction::Info => cache.usage().map(|usage| {
+            println!("Location: {}", cache.dir().display());
+            println!("Entries:  {}", usage.entries);
+            println!("Size:     {}", format_bytes(usage.bytes));
+        }),
+        CacheAction::Prune { max_size } => cache.prune(max_size).map(print_removed),
+        CacheAction::Clear => cache.clear().map(print_removed),
+    };
+    result.with_context(|| cache.dir().display().to_string())
+}
+
+fn print_removed(removed: CacheUsage) {
+    eprintln!(
+        "Removed {} cache entries ({})",
+        removed.entries,
+        format_bytes(removed.bytes)
+    );
+}
+
+fn default_cache_dir() -> Result<PathBuf> {
+    Cache::default_dir().context("unable to determine cache directory; pass --cache-dir")
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (num, unit) = value.split_at(split);
+    let num: u64 = num.parse().map_err(|_| format!("invalid size: {value}"))?;
+    let mult: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(format!("invalid size unit: {unit}")),
+    };
+    num.checked_mul(mult)
+        .ok_or_else(|| format!("size too large: {value}"))
+}
+
+/// Parses compression options such as "br:11,gz", returning the default
+/// algorithms when none are given.
+fn parse_compression(
+    compression: Option<Vec<String>>,
+) -> Result<(Algorithms, Quality), ParseCompressionError> {
+    let mut quality = Quality::default();
+    let Some(compression) = compression else {
+        return Ok((Algorithms::default(), quality));
+    };
+
+    let mut algs = Algorithms::empty();
+    for s in split_csv(compression) {
+        let (name, q) = match s.split_once(':') {
+            Some((name, q)) => (name, Some(q)),
+            None => (s.as_str(), None),
+        };
+        let alg = name.parse()?;
+        algs.enable(alg);
+        if let Some(q) = q {
+            quality.set_from_str(alg, q)?;
+        }
+    }
+    Ok((algs, quality))
+}
+
+/// Parses thresholds such as "5%,br=0.1" and "gz=1K". A threshold without an
+/// algorithm applies to every algorithm, and later thresholds override earlier
+/// ones.
+fn parse_min_savings(
+    ratios: Option<Vec<String>>,
+    bytes: Option<Vec<String>>,
+) -> Result<MinSavings, String> {
+    let mut min_savings = MinSavings::default();
+    for s in split_csv(ratios.unwrap_or_default()) {
+        let (algs, value) = split_threshold(&s)?;
+        let ratio = parse_ratio(value).ok_or_else(|| format!("invalid minimum ratio: {value}"))?;
+        algs.iter()
+            .for_each(|alg| min_savings.get_mut(alg).ratio = Some(ratio));
+    }
+    for s in split_csv(bytes.unwrap_or_default()) {
+        let (algs, value) = split_threshold(&s)?;
+        let size = parse_size(value)?;
+        algs.iter()
+            .for_each(|alg| min_savings.get_mut(alg).bytes = Some(size));
+    }
+    Ok(min_savings)
+}
+
+/// Parses a keep-best baseline such as "gz" or "gz:2%".
+fn parse_keep_best(value: &str) -> Result<KeepBest, String> {
+    let (name, margin) = match value.split_once(':') {
+        Some((name, margin)) => (name, Some(margin)),
+        None => (value, None),
+    };
+    let mut keep_best = KeepBest::new(name.parse().map_err(|err| format!("{err}"))?);
+    if let Some(margin) = margin {
+        keep_best = keep_best
+            .with_margin(parse_ratio(margin).ok_or_else(|| format!("invalid margin: {margin}"))?);
+    }
+    Ok(keep_best)
+}
+
+/// Parses a fraction between 0 and 1, such as "0.05" or "5%".
+fn parse_ratio(value: &str) -> Option<f64> {
+    let ratio = match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().ok()? / 100.0,
+        None => value.parse::<f64>().ok()?,
+    };
+    (0.0..=1.0).contains(&ratio).then_some(ratio)
+}
+
+/// Splits "ALG=VALUE" into the algorithm and value, returning every algorithm
+/// when there is no "ALG=".
+fn split_threshold(s: &str) -> Result<(Algorithms, &str), String> {
+    let Some((name, value)) = s.split_once('=') else {
+        return Ok((Algorithms::all(), s));
+    };
+    let alg: Algorithm = name.parse().map_err(|err| format!("{err}"))?;
+    let mut algs = Algorithms::empty();
+    algs.enable(alg);
+    Ok((algs, value))
+}
+
+/// Applies name templates such as "zstd={name}.zstd" over `naming`.
+fn parse_naming(mut naming: Naming, templates: Option<Vec<String>>) -> Result<Naming, String> {
+    for s in templates.into_iter().flatten() {
+        let Some((name, template)) = s.split_once('=') else {
+            return Err(format!(
+                "invalid name template, expected <ALGORITHM>=<TEMPLATE>: {s}"
+            ));
+        };
+        let alg: Algorithm = name.parse().map_err(|err| format!("{err}"))?;
+        naming.set(alg, template.parse()?);
+    }
+    Ok(naming)
 }
 
 fn print_alg_savings(alg: Algorithm, stats: &Stats) {
@@ -183,6 +1250,46 @@ fn print_alg_savings(alg: Algorithm, stats: &Stats) {
     );
 }
 
+fn print_zstd_dictionary_savings(stats: &Stats) {
+    let gain = stats.zstd_baseline_bytes as i64 - stats.zstd.total_bytes as i64;
+    let sign = if gain < 0 { "-" } else { "" };
+    eprintln!(
+        "  zstd dictionary: {}% ({}{}) smaller than without it",
+        calc_savings(gain, stats.zstd.total_bytes),
+        sign,
+        format_bytes(gain.unsigned_abs()),
+    );
+}
+
+fn print_dict_savings(format: DictionaryFormat, stats: &Stats) {
+    let stat = stats.for_dictionary(format);
+    let saved = stat.saved_bytes;
+    let sign = if saved < 0 { "-" } else { "" };
+    let gain = stat.baseline_bytes as i64 - stat.total_bytes as i64;
+    let gain_sign = if gain < 0 { "-" } else { "" };
+    eprintln!(
+        "  {}: {}% ({}{}), {}{} smaller than {}",
+        format,
+        calc_savings(saved, stat.total_bytes),
+        sign,
+        format_bytes(saved.unsigned_abs()),
+        gain_sign,
+        format_bytes(gain.unsigned_abs()),
+        format.baseline(),
+    );
+}
+
+/// Loads the config file at `path`, or `precompress.toml` in `root` if it
+/// exists.
+fn load_config(root: &Path, path: Option<PathBuf>) -> Result<Config> {
+    match path {
+        Some(path) => Config::load(&path).with_context(|| path.display().to_string()),
+        None => Ok(Config::find(root)?
+            .map(|(_, config)| config)
+            .unwrap_or_default()),
+    }
+}
+
 fn split_csv(values: Vec<String>) -> impl Iterator<Item = String> {
     values
         .into_iter()
@@ -190,14 +1297,7 @@ fn split_csv(values: Vec<String>) -> impl Iterator<Item = String> {
         .filter(|s| !s.is_empty())
 }
 
-pub(crate) fn calc_savings(saved: i64, total: u64) -> u8 {
-    if saved == 0 && total == 0 {
-        return 0;
-    }
-    ((saved as f64 / (saved as f64 + total as f64)) * 100.0) as u8
-}
-
-pub(crate) fn format_bytes(bytes: u64) -> String {
+fn format_bytes(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = 1024 * KB;
     const GB: u64 = 1024 * MB;
@@ -212,6 +1312,17 @@ pub(crate) fn format_bytes(bytes: u64) -> String {
     }
 }
 
+fn format_eta(dur: Duration) -> String {
+    let secs = dur.as_secs();
+    if secs >= 3600 {
+        format!("{}h {}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{secs}s")
+    }
+}
+
 fn format_duration(dur: Duration) -> String {
     if dur.as_millis() < 1_000 {
         format!("{}ms", dur.as_millis())
@@ -222,22 +1333,32 @@ fn format_duration(dur: Duration) -> String {
 
 #[cfg(test)]
 mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
     use clap::Parser;
 
-    use super::{Args, calc_savings, parse_compression, split_csv};
-    use crate::precompress::Algorithm;
+    use super::{
+        Args, CacheAction, Command, format_eta, format_progress, parse_compression,
+        parse_keep_best, parse_min_savings, parse_naming, parse_size, split_csv,
+    };
+    use precompress::naming::Naming;
+    use precompress::{
+        AlgProgress, Algorithm, DEFAULT_MAX_SAMPLES, DEFAULT_ZOPFLI_ITERATIONS, KeepBest,
+        MinSavings, ParseCompressionError, Progress,
+    };
 
     #[test]
     fn args_respect_ignore_by_default() {
         let args = Args::parse_from(["precompress", "."]);
-        assert!(!args.no_respect_ignore);
-        assert!(args.exclude.is_none());
+        assert!(!args.files.no_respect_ignore);
+        assert!(args.files.exclude.is_none());
     }
 
     #[test]
     fn args_allow_disabling_ignore_handling() {
         let args = Args::parse_from(["precompress", "--no-respect-ignore", "."]);
-        assert!(args.no_respect_ignore);
+        assert!(args.files.no_respect_ignore);
     }
 
     #[test]
@@ -262,11 +1383,12 @@ mod tests {
         ]);
 
         assert_eq!(
-            split_csv(args.extensions.expect("extensions should be parsed")).collect::<Vec<_>>(),
+            split_csv(args.files.extensions.expect("extensions should be parsed"))
+                .collect::<Vec<_>>(),
             vec!["js", "css", "html"]
         );
         assert_eq!(
-            split_csv(args.exclude.expect("exclude should be parsed")).collect::<Vec<_>>(),
+            split_csv(args.files.exclude.expect("exclude should be parsed")).collect::<Vec<_>>(),
             vec!["dist/**", "build/**", "*.map"]
         );
     }
@@ -276,20 +1398,83 @@ mod tests {
         let (algorithms, quality) = parse_compression(Some(vec![
             String::from("br:11,gzip:5"),
             String::from("zst:-3"),
-        ]));
+        ]))
+        .expect("valid compression options");
 
         assert!(algorithms.brotli);
         assert!(!algorithms.deflate);
         assert!(algorithms.gzip);
         assert!(algorithms.zstd);
-        assert_eq!(quality.brotli, 11);
-        assert_eq!(quality.gzip, 5);
-        assert_eq!(quality.zstd, -3);
+        assert_eq!(quality.get(Algorithm::Brotli), 11);
+        assert_eq!(quality.get(Algorithm::Gzip), 5);
+        assert_eq!(quality.get(Algorithm::Zstd), -3);
+        assert_eq!(quality.zopfli(Algorithm::Gzip), None);
+    }
+
+    #[test]
+    fn parse_compression_supports_zopfli() {
+        let (algorithms, quality) =
+            parse_compression(Some(vec![String::from("gz:zopfli,deflate:zopfli=30")]))
+                .expect("valid compression options");
+
+        assert!(algorithms.gzip);
+        assert!(algorithms.deflate);
+        assert_eq!(
+            quality.zopfli(Algorithm::Gzip),
+            Some(DEFAULT_ZOPFLI_ITERATIONS)
+        );
+        assert_eq!(
+            quality.zopfli(Algorithm::Deflate).map(|n| n.get()),
+            Some(30)
+        );
+    }
+
+    #[test]
+    fn parse_compression_reports_the_invalid_token() {
+        let parse = |s: &str| {
+            parse_compression(Some(vec![String::from(s)])).expect_err("invalid compression options")
+        };
+
+        assert_eq!(
+            parse("br,gzp:5"),
+            ParseCompressionError::UnknownAlgorithm {
+                token: String::from("gzp"),
+                suggestion: Some("gz"),
+            }
+        );
+        assert_eq!(
+            parse("gzp").to_string(),
+            r#"unknown compression algorithm "gzp"; did you mean "gz"?"#
+        );
+        assert_eq!(
+            parse("lzma").to_string(),
+            "unknown compression algorithm \"lzma\"; \
+             expected one of br, brotli, de, deflate, gz, gzip, zst, zstd"
+        );
+        assert_eq!(
+            parse("br:12").to_string(),
+            r#"invalid brotli quality "12": expected 0 to 11"#
+        );
+        assert_eq!(
+            parse("gz:fast").to_string(),
+            r#"invalid gzip quality "fast": expected 1 to 9, or zopfli[=ITERATIONS]"#
+        );
+        assert_eq!(
+            parse("zstd:zopfli"),
+            ParseCompressionError::InvalidQuality {
+                algorithm: Algorithm::Zstd,
+                token: String::from("zopfli"),
+            }
+        );
+        assert_eq!(
+            parse("de:zopfli=0").to_string(),
+            r#"invalid deflate zopfli iteration count "zopfli=0": expected a positive integer"#
+        );
     }
 
     #[test]
     fn parse_compression_defaults_match_enabled_algorithms() {
-        let (algorithms, quality) = parse_compression(None);
+        let (algorithms, quality) = parse_compression(None).expect("defaults are valid");
         let enabled = algorithms
             .iter()
             .map(|alg| alg.to_string())
@@ -303,15 +1488,175 @@ mod tests {
                 Algorithm::Zstd.to_string(),
             ]
         );
-        assert_eq!(quality.brotli, 10);
-        assert_eq!(quality.gzip, 7);
-        assert_eq!(quality.zstd, 19);
+        assert_eq!(quality.get(Algorithm::Brotli), 10);
+        assert_eq!(quality.get(Algorithm::Gzip), 7);
+        assert_eq!(quality.get(Algorithm::Zstd), 19);
+    }
+
+    #[test]
+    fn args_parse_cache_subcommand() {
+        let args = Args::parse_from(["precompress", "cache", "prune", "--max-size", "2M"]);
+        assert!(args.path.is_none());
+        match args.command {
+            Some(Command::Cache {
+                action: Some(CacheAction::Prune { max_size }),
+                cache_dir: None,
+            }) => assert_eq!(max_size, 2 << 20),
+            other => panic!("unexpected command: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn args_parse_clean_subcommand_with_file_options() {
+        let args = Args::parse_from([
+            "precompress",
+            "clean",
+            "--dry-run",
+            "--exclude",
+            "*.min.js",
+            "-c",
+            "br",
+            "--config",
+            "rules.toml",
+            "site",
+        ]);
+        match args.command {
+            Some(Command::Clean {
+                path,
+                dry_run,
+                files,
+                config,
+            }) => {
+                assert_eq!(path, PathBuf::from("site"));
+                assert!(dry_run);
+                assert_eq!(config, Some(PathBuf::from("rules.toml")));
+                assert_eq!(files.exclude, Some(vec![String::from("*.min.js")]));
+                assert_eq!(files.compression, Some(vec![String::from("br")]));
+            }
+            other => panic!("unexpected command: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn args_parse_train_dict_subcommand() {
+        let args = Args::parse_from([
+            "precompress",
+            "train-dict",
+            "--size",
+            "64K",
+            "-e",
+            "json",
+            "site",
+            "site.dict",
+        ]);
+        match args.command {
+            Some(Command::TrainDict {
+                path,
+                dictionary,
+                files,
+                size,
+                max_samples,
+                ..
+            }) => {
+                assert_eq!(path, PathBuf::from("site"));
+                assert_eq!(dictionary, PathBuf::from("site.dict"));
+                assert_eq!(files.extensions, Some(vec![String::from("json")]));
+                assert_eq!(size, 64 << 10);
+                assert_eq!(max_samples, DEFAULT_MAX_SAMPLES);
+            }
+            other => panic!("unexpected command: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn format_progress_shows_counts_eta_and_current_rates() {
+        let mut baseline = Progress::default();
+        baseline.files_discovered = 10;
+        baseline.bytes_discovered = 40 << 20;
+        baseline.walk_done = true;
+        baseline.elapsed = Duration::from_secs(1);
+        baseline.algorithms = vec![AlgProgress::new(Algorithm::Gzip, 0, 0)];
+        let mut progress = baseline.clone();
+        progress.files_done = 3;
+        progress.bytes_done = 10 << 20;
+        progress.bytes_written = 2 << 20;
+        progress.elapsed = Duration::from_secs(3);
+        progress.algorithms = vec![AlgProgress::new(Algorithm::Gzip, 10 << 20, 2 << 20)];
+        assert_eq!(
+            format_progress(&progress, Some(&baseline)),
+            "3/10 files, ETA 9s, 10.0 MiB read, 2.0 MiB written, gzip 5.0 MiB/s"
+        );
+
+        let mut walking = progress;
+        walking.walk_done = false;
+        assert_eq!(
+            format_progress(&walking, None),
+            "3/10+ files, 10.0 MiB read, 2.0 MiB written"
+        );
+        assert_eq!(format_eta(Duration::from_secs(3725)), "1h 2m");
+        assert_eq!(format_eta(Duration::from_secs(72)), "1m 12s");
+    }
+
+    #[test]
+    fn parse_min_savings_applies_to_all_or_one_algorithm() {
+        let min_savings = parse_min_savings(
+            Some(vec![String::from("5%,br=0.25")]),
+            Some(vec![String::from("gz=1K")]),
+        )
+        .expect("valid thresholds");
+        assert_eq!(min_savings.get(Algorithm::Gzip).ratio, Some(0.05));
+        assert_eq!(min_savings.get(Algorithm::Gzip).bytes, Some(1024));
+        assert_eq!(min_savings.get(Algorithm::Brotli).ratio, Some(0.25));
+        assert_eq!(min_savings.get(Algorithm::Brotli).bytes, None);
+
+        assert_eq!(
+            parse_min_savings(None, None).expect("no thresholds"),
+            MinSavings::default()
+        );
+        assert!(parse_min_savings(Some(vec![String::from("150%")]), None).is_err());
+        assert!(parse_min_savings(Some(vec![String::from("gzp=0.1")]), None).is_err());
+        assert!(parse_min_savings(None, Some(vec![String::from("1X")])).is_err());
+    }
+
+    #[test]
+    fn parse_keep_best_accepts_an_optional_margin() {
+        assert_eq!(parse_keep_best("gz"), Ok(KeepBest::new(Algorithm::Gzip)));
+        let keep_best = parse_keep_best("zstd:2%").expect("valid baseline");
+        assert_eq!(keep_best.baseline, Algorithm::Zstd);
+        assert_eq!(keep_best.margin, 0.02);
+        assert!(parse_keep_best("gz:-1").is_err());
+        assert!(parse_keep_best("lz4").is_err());
     }
 
     #[test]
-    fn calc_savings_handles_zero_positive_and_negative_values() {
-        assert_eq!(calc_savings(0, 0), 0);
-        assert_eq!(calc_savings(50, 50), 50);
-        assert_eq!(calc_savings(-50, 100), 0);
+    fn parse_size_supports_units() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("1gib"), Ok(1 << 30));
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn parse_naming_sets_templates_per_algorithm() {
+        let naming = parse_naming(
+            Naming::default(),
+            Some(vec![
+                String::from("zstd={name}.zstd"),
+                String::from("gz={stem}.{enc}.{ext}"),
+            ]),
+        )
+        .expect("valid templates");
+        assert_eq!(naming.get(Algorithm::Zstd).to_string(), "{name}.zstd");
+        assert_eq!(
+            naming.get(Algorithm::Gzip).to_string(),
+            "{stem}.{enc}.{ext}"
+        );
+        assert_eq!(naming.get(Algorithm::Brotli).to_string(), "{name}.{enc}");
+
+        assert!(parse_naming(Naming::default(), Some(vec![String::from("gz")])).is_err());
+        assert!(
+            parse_naming(Naming::default(), Some(vec![String::from("gzp={name}.gz")])).is_err()
+        );
     }
 }
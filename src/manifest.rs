@@ -0,0 +1,259 @@
+//! An asset manifest listing the variants of every compressed source file.
+//!
+//! A [`Manifest`] is fed the [`FileReport`]s of a run's events and written
+//! once the run is over, so sizes and hashes describe the files as they were
+//! left on disk. Entries are keyed by path and sorted, so the manifests of two
+//! runs diff cleanly.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::cache::{hash_file, to_hex};
+use crate::output::Output;
+use crate::precompress::{Algorithm, write_atomic};
+use crate::report::{FileReport, VariantOutcome};
+use crate::serve::content_type;
+
+/// Collects the variants kept for each source file during a run.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    root: PathBuf,
+    /// The directory that variant paths are relative to.
+    base: PathBuf,
+    sources: BTreeMap<String, Source>,
+}
+
+#[derive(Debug, Clone)]
+struct Source {
+    path: PathBuf,
+    /// Variant paths by content coding.
+    variants: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    files: BTreeMap<&'a str, Entry<'a>>,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    size: u64,
+    sha256: String,
+    etag: String,
+    content_type: &'static str,
+    variants: BTreeMap<&'a str, VariantEntry>,
+}
+
+#[derive(Serialize)]
+struct VariantEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+    etag: String,
+}
+
+impl Manifest {
+    /// Creates an empty manifest for a run over `root` with `output`.
+    /// Variant paths are relative to the output directory when there is one,
+    /// and to `root` otherwise.
+    pub fn new(root: impl Into<PathBuf>, output: &Output) -> Self {
+        let root = root.into();
+        Manifest {
+            base: output.out_dir.clone().unwrap_or_else(|| root.clone()),
+            root,
+            sources: BTreeMap::new(),
+        }
+    }
+
+    /// Records the variants of a source file that the run left on disk.
+    /// Variants that failed, or were discarded or pruned, are left out, and
+    /// so are source files that could not be read.
+    pub fn add(&mut self, report: &FileReport) {
+        if report.error.is_some() {
+            return;
+        }
+        let path = PathBuf::from(&report.path);
+        let variants = report
+            .variants
+            .iter()
+            .filter(|variant| {
+                matches!(
+                    variant.status,
+                    VariantOutcome::Written | VariantOutcome::Cached | VariantOutcome::UpToDate
+                )
+            })
+            .filter_map(|variant| {
+                let encoding = match variant.algorithm.parse::<Algorithm>() {
+                    Ok(alg) => alg.encoding().to_owned(),
+                    // Dictionary formats are named by their encoding.
+                    Err(_) => variant.algorithm.clone(),
+                };
+                Some((encoding, PathBuf::from(variant.path.as_ref()?)))
+            })
+            .collect();
+        self.sources
+            .insert(relative(&path, &self.root), Source { path, variants });
+    }
+
+    /// Hashes every recorded file and writes the manifest as pretty-printed
+    /// JSON.
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        let mut files = BTreeMap::new();
+        for (rel, source) in &self.sources {
+            let (size, sha256) = hash(&source.path, &mut buf)?;
+            let mut variants = BTreeMap::new();
+            for (encoding, path) in &source.variants {
+                let (size, sha256) = hash(path, &mut buf)?;
+                variants.insert(
+                    encoding.as_str(),
+                    VariantEntry {
+                        path: relative(path, &self.base),
+                        size,
+                        etag: etag(&sha256),
+                        sha256,
+                    },
+                );
+            }
+            files.insert(
+                rel.as_str(),
+                Entry {
+                    size,
+                    etag: etag(&sha256),
+                    sha256,
+                    content_type: content_type(&source.path),
+                    variants,
+                },
+            );
+        }
+        serde_json::to_writer_pretty(&mut out, &Document { files })?;
+        out.write_all(b"\n")?;
+        out.flush()
+    }
+
+    /// Writes the manifest to `path` through a temporary file, so that a
+    /// failed run never leaves a truncated manifest behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_atomic(path, |file| self.write(BufWriter::new(file))).map(drop)
+    }
+}
+
+/// Returns the size and hex SHA-256 digest of the file at `path`.
+fn hash(path: &Path, buf: &mut [u8]) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+    let size = file.metadata()?.len();
+    Ok((size, to_hex(&hash_file(&mut file, buf)?)))
+}
+
+/// A strong ETag derived from a file's digest, so that every representation
+/// of a resource gets a different one.
+fn etag(sha256: &str) -> String {
+    format!("\"{}\"", &sha256[0..32])
+}
+
+/// Returns `path` relative to `base`, with `/` separators on every platform.
+fn relative(path: &Path, base: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use anyhow::Result;
+    use serde_json::Value;
+
+    use super::Manifest;
+    use crate::output::Output;
+    use crate::report::{FileReport, VariantOutcome, VariantReport};
+    use crate::test_util::test_dir;
+
+    fn variant(algorithm: &str, path: PathBuf, status: VariantOutcome) -> VariantReport {
+        let mut variant = VariantReport::failed(String::from(algorithm));
+        variant.path = Some(path.display().to_string());
+        variant.status = status;
+        variant
+    }
+
+    #[test]
+    fn manifest_lists_kept_variants_sorted_by_path() -> Result<()> {
+        let root = test_dir("manifest");
+        fs::create_dir_all(root.join("js"))?;
+        fs::write(root.join("js/app.js"), "const x = 1;\n")?;
+        fs::write(root.join("js/app.js.br"), "br")?;
+        fs::write(root.join("js/app.js.gz"), "gz")?;
+        fs::write(root.join("index.html"), "<!doctype html>\n")?;
+
+        let mut manifest = Manifest::new(&root, &Output::default());
+        manifest.add(&FileReport {
+            path: root.join("js/app.js").display().to_string(),
+            size: 13,
+            variants: vec![
+                variant("gzip", root.join("js/app.js.gz"), VariantOutcome::Written),
+                variant(
+                    "brotli",
+                    root.join("js/app.js.br"),
+                    VariantOutcome::UpToDate,
+                ),
+                variant("zstd", root.join("js/app.js.zst"), VariantOutcome::Pruned),
+            ],
+            error: None,
+        });
+        manifest.add(&FileReport {
+            path: root.join("index.html").display().to_string(),
+            size: 16,
+            variants: Vec::new(),
+            error: None,
+        });
+        manifest.add(&FileReport {
+            path: root.join("unreadable.js").display().to_string(),
+            size: 0,
+            variants: Vec::new(),
+            error: Some(String::from("permission denied")),
+        });
+        let manifest_path = root.join("manifest.json");
+        manifest.save(&manifest_path)?;
+
+        let text = fs::read_to_string(&manifest_path)?;
+        assert!(text.find("\"index.html\"") < text.find("\"js/app.js\""));
+        let value: Value = serde_json::from_str(&text)?;
+        let app = &value["files"]["js/app.js"];
+        assert_eq!(app["size"], 13);
+        assert_eq!(app["content_type"], "text/javascript; charset=utf-8");
+        let variants = app["variants"].as_object().expect("variants object");
+        assert_eq!(variants.keys().collect::<Vec<_>>(), ["br", "gzip"]);
+        let gzip = &variants["gzip"];
+        assert_eq!(gzip["path"], "js/app.js.gz");
+        assert_eq!(gzip["size"], 2);
+        // The SHA-256 digest of "gz".
+        assert_eq!(
+            gzip["sha256"],
+            "2ed534af191bc3baaf1e09263808bf1bdd68db6241bd8f81e2bb014653dcbb36"
+        );
+        assert_eq!(gzip["etag"], "\"2ed534af191bc3baaf1e09263808bf1b\"");
+        assert_ne!(variants["br"]["etag"], gzip["etag"]);
+        assert_eq!(
+            value["files"]["index.html"]["variants"],
+            Value::Object(Default::default())
+        );
+        assert!(value["files"].get("unreadable.js").is_none());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    io::{self, Read},
+    sync::Arc,
+};
+
+use crate::dictionary::Dictionary;
+use crate::encode::{Context, Quality};
+use crate::precompress::{Algorithm, Algorithms};
+
+/// Compresses contents held in memory with every enabled algorithm, without
+/// touching the filesystem.
+///
+/// The encoder buffers are reused between calls, so a single instance should
+/// be kept around to compress many inputs.
+pub struct MemoryCompressor {
+    ctx: Context,
+    algorithms: Algorithms,
+    input: Vec<u8>,
+}
+
+impl MemoryCompressor {
+    /// Returns a compressor that writes a variant with each of `algorithms`
+    /// at `quality`.
+    pub fn new(algorithms: Algorithms, quality: Quality) -> Self {
+        MemoryCompressor {
+            ctx: Context::new(1 << 14, quality),
+            algorithms,
+            input: Vec::new(),
+        }
+    }
+
+    /// Compresses zstd variants using `dictionary`.
+    pub fn with_zstd_dictionary(mut self, dictionary: impl Into<Option<Arc<Dictionary>>>) -> Self {
+        self.ctx = self.ctx.with_zstd_dictionary(dictionary.into());
+        self
+    }
+
+    /// Returns `data` encoded with each enabled algorithm, in the order of
+    /// [`Algorithms::iter`].
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<(Algorithm, Vec<u8>)>> {
+        let mut variants = Vec::new();
+        for alg in self.algorithms.iter() {
+            let mut out = Vec::new();
+            self.ctx.write(alg, &mut &data[..], &mut out)?;
+            variants.push((alg, out));
+        }
+        Ok(variants)
+    }
+
+    /// Reads `input` to the end and returns its contents encoded with each
+    /// enabled algorithm.
+    pub fn compress_reader(
+        &mut self,
+        mut input: impl Read,
+    ) -> io::Result<Vec<(Algorithm, Vec<u8>)>> {
+        let mut data = std::mem::take(&mut self.input);
+        data.clear();
+        let result = input
+            .read_to_end(&mut data)
+            .and_then(|_| self.compress(&data));
+        self.input = data;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use anyhow::Result;
+    use brotli::Decompressor;
+    use flate2::read::GzDecoder;
+
+    use super::MemoryCompressor;
+    use crate::encode::Quality;
+    use crate::precompress::{Algorithm, Algorithms};
+
+    #[test]
+    fn compresses_slices_and_readers_with_each_algorithm() -> Result<()> {
+        let page = "<p>Hello, world!</p>\n".repeat(200);
+        let mut compressor = MemoryCompressor::new(Algorithms::default(), Quality::default());
+
+        let variants = compressor.compress(page.as_bytes())?;
+        let algorithms = variants.iter().map(|(alg, _)| *alg).collect::<Vec<_>>();
+        assert_eq!(
+            algorithms,
+            vec![Algorithm::Brotli, Algorithm::Gzip, Algorithm::Zstd]
+        );
+        for (alg, encoded) in &variants {
+            assert!(encoded.len() < page.len(), "{alg} did not compress");
+            let mut decoded = Vec::new();
+            match alg {
+                Algorithm::Brotli => {
+                    Decompressor::new(encoded.as_slice(), 4096).read_to_end(&mut decoded)?
+                }
+                Algorithm::Gzip => GzDecoder::new(encoded.as_slice()).read_to_end(&mut decoded)?,
+                _ => {
+                    decoded = zstd::decode_all(encoded.as_slice())?;
+                    decoded.len()
+                }
+            };
+            assert_eq!(decoded, page.as_bytes());
+        }
+
+        assert_eq!(compressor.compress_reader(page.as_bytes())?, variants);
+        assert!(compressor.compress(b"")?.iter().all(|(_, v)| !v.is_empty()));
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+//! File name templates for compressed variants.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    path::Path,
+    str::FromStr,
+};
+
+use crate::precompress::{Algorithm, Algorithms, is_compressible};
+
+/// A file name template for compressed variants.
+///
+/// Templates are made of literal text and the placeholders `{name}` (the
+/// source file name), `{stem}` (the file name without its extension), `{ext}`
+/// (the source extension) and `{enc}` (the algorithm's default suffix, e.g.
+/// `br` or `zst`). The default template for every algorithm is `{name}.{enc}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    Name,
+    Stem,
+    Ext,
+    Enc,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Template {
+            tokens: vec![Token::Name, Token::Literal(String::from(".")), Token::Enc],
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('{') {
+                let Some((placeholder, after)) = after.split_once('}') else {
+                    return Err(format!("unclosed placeholder in template: {s}"));
+                };
+                tokens.push(match placeholder {
+                    "name" => Token::Name,
+                    "stem" => Token::Stem,
+                    "ext" => Token::Ext,
+                    "enc" => Token::Enc,
+                    _ => {
+                        return Err(format!(
+                            "unknown placeholder {{{placeholder}}} in template: {s}"
+                        ));
+                    }
+                });
+                rest = after;
+            } else {
+                let end = rest.find('{').unwrap_or(rest.len());
+                let literal = &rest[0..end];
+                if literal.contains('}') {
+                    return Err(format!("unmatched '}}' in template: {s}"));
+                }
+                if literal.contains(['/', '\\']) {
+                    return Err(format!("template must not contain path separators: {s}"));
+                }
+                tokens.push(Token::Literal(literal.to_owned()));
+                rest = &rest[end..];
+            }
+        }
+
+        let has = |token: &Token| tokens.contains(token);
+        if !(has(&Token::Name) || (has(&Token::Stem) && has(&Token::Ext))) {
+            return Err(format!(
+                "template must contain {{name}}, or both {{stem}} and {{ext}}: {s}"
+            ));
+        }
+        Ok(Template { tokens })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in &self.tokens {
+            match token {
+                Token::Literal(literal) => f.write_str(literal)?,
+                Token::Name => f.write_str("{name}")?,
+                Token::Stem => f.write_str("{stem}")?,
+                Token::Ext => f.write_str("{ext}")?,
+                Token::Enc => f.write_str("{enc}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Template {
+    /// Renders the variant file name of `file_name` for `alg`.
+    pub(crate) fn render(&self, file_name: &OsStr, alg: Algorithm) -> OsString {
+        let path = Path::new(file_name);
+        let mut out = OsString::new();
+        for token in &self.tokens {
+            match token {
+                Token::Literal(literal) => out.push(literal),
+                Token::Name => out.push(file_name),
+                Token::Stem => out.push(path.file_stem().unwrap_or(file_name)),
+                Token::Ext => out.push(path.extension().unwrap_or_default()),
+                Token::Enc => out.push(enc(alg)),
+            }
+        }
+        out
+    }
+
+    /// Returns the text this template appends to a source file name for
+    /// `alg`, when every variant name is the source name plus a fixed suffix.
+    pub(crate) fn suffix(&self, alg: Algorithm) -> Option<String> {
+        let (Token::Name, rest) = self.tokens.split_first()? else {
+            return None;
+        };
+        rest.iter()
+            .map(|token| match token {
+                Token::Literal(literal) => Some(literal.as_str()),
+                Token::Enc => Some(enc(alg)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Reports whether the variant names this template renders are told
+    /// apart from source file names: they contain the algorithm's suffix, or
+    /// end in fixed text that is not the extension of a compressible file.
+    pub(crate) fn is_distinct(&self) -> bool {
+        if self.tokens.contains(&Token::Enc) {
+            return true;
+        }
+        match self.tokens.last() {
+            Some(Token::Literal(literal)) => {
+                !is_compressible(Path::new(&format!("variant{literal}")), None)
+            }
+            _ => false,
+        }
+    }
+
+    /// Recovers the source file name from a variant file name rendered by
+    /// this template, if it could have been.
+    pub(crate) fn source_name(&self, variant: &str, alg: Algorithm) -> Option<String> {
+        let mut captures = Captures::default();
+        if !self.matches(&self.tokens, variant, alg, &mut captures) {
+            return None;
+        }
+        let source = match (captures.name, captures.stem, captures.ext) {
+            (Some(name), _, _) => name,
+            (None, Some(stem), Some(ext)) => format!("{stem}.{ext}"),
+            _ => return None,
+        };
+        // Rendering the recovered source must reproduce the variant exactly,
+        // which also rejects inconsistent {name}/{stem}/{ext} captures.
+        (self.render(OsStr::new(&source), alg) == OsStr::new(variant)).then_some(source)
+    }
+
+    fn matches(&self, tokens: &[Token], s: &str, alg: Algorithm, captures: &mut Captures) -> bool {
+        let Some((token, rest)) = tokens.split_first() else {
+            return s.is_empty();
+        };
+        let slot: fn(&mut Captures) -> &mut Option<String> = match token {
+            Token::Literal(literal) => {
+                return s
+                    .strip_prefix(literal.as_str())
+                    .is_some_and(|s| self.matches(rest, s, alg, captures));
+            }
+            Token::Enc => {
+                return s
+                    .strip_prefix(enc(alg))
+                    .is_some_and(|s| self.matches(rest, s, alg, captures));
+            }
+            Token::Name => |c| &mut c.name,
+            Token::Stem => |c| &mut c.stem,
+            Token::Ext => |c| &mut c.ext,
+        };
+        for end in (1..=s.len()).filter(|end| s.is_char_boundary(*end)) {
+            let previous = slot(captures).replace(s[0..end].to_owned());
+            if self.matches(rest, &s[end..], alg, captures) {
+                return true;
+            }
+            *slot(captures) = previous;
+        }
+        false
+    }
+}
+
+#[derive(Debug, Default)]
+struct Captures {
+    name: Option<String>,
+    stem: Option<String>,
+    ext: Option<String>,
+}
+
+fn enc(alg: Algorithm) -> &'static str {
+    &alg.extension()[1..]
+}
+
+/// The variant file name template used for each algorithm.
+#[derive(Debug, Clone, Default)]
+pub struct Naming {
+    pub(crate) brotli: Template,
+    pub(crate) deflate: Template,
+    pub(crate) gzip: Template,
+    pub(crate) zstd: Template,
+}
+
+impl Naming {
+    /// Returns the template that names variants compressed with `alg`.
+    pub fn get(&self, alg: Algorithm) -> &Template {
+        match alg {
+            Algorithm::Brotli => &self.brotli,
+            Algorithm::Deflate => &self.deflate,
+            Algorithm::Gzip => &self.gzip,
+            Algorithm::Zstd => &self.zstd,
+        }
+    }
+
+    /// Sets the template that names variants compressed with `alg`.
+    pub fn set(&mut self, alg: Algorithm, template: Template) {
+        match alg {
+            Algorithm::Brotli => self.brotli = template,
+            Algorithm::Deflate => self.deflate = template,
+            Algorithm::Gzip => self.gzip = template,
+            Algorithm::Zstd => self.zstd = template,
+        }
+    }
+
+    /// Checks that no template renders a name that a source file could have,
+    /// and that no two enabled algorithms render the same variant name.
+    pub fn validate(&self, algorithms: Algorithms) -> Result<(), String> {
+        for alg in algorithms.iter() {
+            if !self.get(alg).is_distinct() {
+                return Err(format!(
+                    "{alg} template {} could overwrite a source file; it must contain {{enc}} \
+                     or end with a suffix that is not a compressible extension",
+                    self.get(alg)
+                ));
+            }
+        }
+        const PROBES: [&str; 3] = ["index.html", "app.min.js", "data.json.gz"];
+        for probe in PROBES {
+            let probe = OsStr::new(probe);
+            let mut rendered: Vec<(Algorithm, OsString)> = Vec::new();
+            for alg in algorithms.iter() {
+                let name = self.get(alg).render(probe, alg);
+                if name == probe {
+                    return Err(format!(
+                        "{alg} template {} would overwrite its source file",
+                        self.get(alg)
+                    ));
+                }
+                if let Some((other, _)) = rendered.iter().find(|(_, n)| *n == name) {
+                    return Err(format!(
+                        "{other} template {} and {alg} template {} produce the same file name",
+                        self.get(*other),
+                        self.get(alg)
+                    ));
+                }
+                rendered.push((alg, name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the algorithm and source file name when `variant` is a file
+    /// name that one of `algorithms` would have produced.
+    pub(crate) fn source_name(
+        &self,
+        variant: &OsStr,
+        algorithms: Algorithms,
+    ) -> Option<(Algorithm, String)> {
+        let variant = variant.to_str()?;
+        algorithms
+            .iter()
+            .find_map(|alg| Some((alg, self.get(alg).source_name(variant, alg)?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use super::{Naming, Template};
+    use crate::precompress::{Algorithm, Algorithms};
+
+    #[test]
+    fn default_template_appends_the_algorithm_suffix() {
+        let template = Template::default();
+        assert_eq!(template.to_string(), "{name}.{enc}");
+        assert_eq!(
+            template.render(OsStr::new("app.js"), Algorithm::Brotli),
+            "app.js.br"
+        );
+        assert_eq!(
+            template.render(OsStr::new("app.js"), Algorithm::Deflate),
+            "app.js.zz"
+        );
+        assert_eq!(template.suffix(Algorithm::Zstd).as_deref(), Some(".zst"));
+        let template: Template = "{stem}.{enc}.{ext}".parse().expect("valid template");
+        assert_eq!(template.suffix(Algorithm::Zstd), None);
+    }
+
+    #[test]
+    fn templates_render_and_reverse() {
+        let template: Template = "{stem}.{enc}.{ext}".parse().expect("valid template");
+        let rendered = template.render(OsStr::new("app.min.js"), Algorithm::Gzip);
+        assert_eq!(rendered, "app.min.gz.js");
+        assert_eq!(
+            template.source_name("app.min.gz.js", Algorithm::Gzip),
+            Some(String::from("app.min.js"))
+        );
+        assert_eq!(template.source_name("app.min.js", Algorithm::Gzip), None);
+
+        let template: Template = "{name}.zstd".parse().expect("valid template");
+        assert_eq!(
+            template.source_name("app.js.zstd", Algorithm::Zstd),
+            Some(String::from("app.js"))
+        );
+        assert_eq!(template.source_name("app.js.zst", Algorithm::Zstd), None);
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        assert!("{stem}.br".parse::<Template>().is_err());
+        assert!("{name}.{foo}".parse::<Template>().is_err());
+        assert!("{name".parse::<Template>().is_err());
+        assert!("gz/{name}".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn validate_detects_collisions() {
+        let mut naming = Naming::default();
+        assert!(naming.validate(Algorithms::default()).is_ok());
+
+        naming.set(
+            Algorithm::Zstd,
+            "{name}.gz".parse().expect("valid template"),
+        );
+        assert!(naming.validate(Algorithms::default()).is_err());
+
+        naming.set(Algorithm::Zstd, "{name}".parse().expect("valid template"));
+        assert!(naming.validate(Algorithms::default()).is_err());
+
+        naming.set(
+            Algorithm::Zstd,
+            "{name}.zstd".parse().expect("valid template"),
+        );
+        assert!(naming.validate(Algorithms::default()).is_ok());
+        assert_eq!(
+            naming.source_name(OsStr::new("app.js.zstd"), Algorithms::default()),
+            Some((Algorithm::Zstd, String::from("app.js")))
+        );
+    }
+
+    #[test]
+    fn validate_rejects_templates_that_could_name_a_source() {
+        let mut naming = Naming::default();
+        // The variant of `app.js` would overwrite `app.min.js`.
+        naming.set(
+            Algorithm::Gzip,
+            "{stem}.min.{ext}".parse().expect("valid template"),
+        );
+        assert!(naming.validate(Algorithms::default()).is_err());
+
+        naming.set(
+            Algorithm::Gzip,
+            "{name}.js".parse().expect("valid template"),
+        );
+        assert!(naming.validate(Algorithms::default()).is_err());
+
+        naming.set(
+            Algorithm::Gzip,
+            "{stem}.{enc}.{ext}".parse().expect("valid template"),
+        );
+        assert!(naming.validate(Algorithms::default()).is_ok());
+    }
+}
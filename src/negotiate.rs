@@ -0,0 +1,321 @@
+//! Content negotiation for servers that serve precompressed variants.
+//!
+//! A [`Negotiator`] uses the same naming rules as a [`Compressor`] run, so a
+//! server embedding it can never disagree with the tool about file names.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use precompress::negotiate::{Negotiated, Negotiator};
+//!
+//! let negotiator = Negotiator::new("public");
+//! match negotiator.negotiate(Path::new("app.js"), "gzip, br;q=0.9") {
+//!     Negotiated::Variant { path, encoding, .. } => {
+//!         // Serve `path` with `Content-Encoding: {encoding}`.
+//!     }
+//!     Negotiated::Identity => {
+//!         // Serve the original.
+//!     }
+//!     Negotiated::NotAcceptable => {
+//!         // Respond with 406 Not Acceptable.
+//!     }
+//! }
+//! ```
+//!
+//! [`Compressor`]: crate::Compressor
+
+use std::{
+    cmp::Reverse,
+    path::{Path, PathBuf},
+};
+
+use crate::output::Output;
+use crate::precompress::Algorithm;
+
+/// The order in which encodings are preferred by default when a client
+/// accepts several of them equally.
+pub const DEFAULT_PREFERENCE: [Algorithm; 4] = [
+    Algorithm::Brotli,
+    Algorithm::Zstd,
+    Algorithm::Gzip,
+    Algorithm::Deflate,
+];
+
+/// The representation of a file to serve for a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Negotiated {
+    /// Serve the variant at `path` with `Content-Encoding: {encoding}`.
+    Variant {
+        /// The path of the variant file.
+        path: PathBuf,
+        /// The algorithm the variant is compressed with.
+        algorithm: Algorithm,
+        /// The `Content-Encoding` token of the algorithm.
+        encoding: &'static str,
+    },
+    /// Serve the original.
+    Identity,
+    /// The client accepts neither an existing variant nor the original.
+    NotAcceptable,
+}
+
+/// Chooses the variant of a file under a root to serve for a request's
+/// `Accept-Encoding` header.
+#[derive(Debug, Clone)]
+pub struct Negotiator {
+    root: PathBuf,
+    output: Output,
+    preference: Vec<Algorithm>,
+    /// Whether zstd variants were compressed with a dictionary.
+    zstd_dictionary: bool,
+}
+
+impl Negotiator {
+    /// Negotiates the variants of files under `root`, named as a run with
+    /// the default output options names them.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Negotiator {
+            root: root.into(),
+            output: Output::default(),
+            preference: DEFAULT_PREFERENCE.to_vec(),
+            zstd_dictionary: false,
+        }
+    }
+
+    /// Finds variants where a run with these output options writes them.
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Only serves the variants of these algorithms, preferring earlier ones
+    /// when a client accepts several equally.
+    pub fn preference(mut self, algorithms: impl IntoIterator<Item = Algorithm>) -> Self {
+        self.preference = algorithms.into_iter().collect();
+        self
+    }
+
+    /// Never serves zstd if the variants were compressed with a dictionary,
+    /// as with `--zstd-dict`, since clients cannot decode them without it.
+    pub fn zstd_dictionary(mut self, zstd_dictionary: bool) -> Self {
+        self.zstd_dictionary = zstd_dictionary;
+        self
+    }
+
+    /// Returns the best existing variant of the original at `path`, either
+    /// under the root or relative to it, for an `Accept-Encoding` header
+    /// value.
+    pub fn negotiate(&self, path: &Path, accept_encoding: &str) -> Negotiated {
+        self.negotiate_parsed(path, &AcceptEncoding::parse(accept_encoding))
+    }
+
+    /// Like [`Negotiator::negotiate`], with a header that is already parsed.
+    pub fn negotiate_parsed(&self, path: &Path, accept: &AcceptEncoding) -> Negotiated {
+        let (path, rel) = match path.strip_prefix(&self.root) {
+            Ok(rel) => (path.to_path_buf(), rel.to_path_buf()),
+            Err(_) => (self.root.join(path), path.to_path_buf()),
+        };
+        for algorithm in self.rank(accept) {
+            if let Ok(variant) = self.output.variant_path(&path, &rel, algorithm)
+                && variant.is_file()
+            {
+                return Negotiated::Variant {
+                    path: variant,
+                    algorithm,
+                    encoding: algorithm.encoding(),
+                };
+            }
+        }
+        if accept.quality("identity").is_none_or(|q| q > 0) {
+            Negotiated::Identity
+        } else {
+            Negotiated::NotAcceptable
+        }
+    }
+
+    /// Returns the algorithms the client accepts, best first. Encodings are
+    /// ranked by q-value and then by preference, and those the client ranks
+    /// below `identity` are left out.
+    pub fn rank(&self, accept: &AcceptEncoding) -> Vec<Algorithm> {
+        let identity = accept.quality("identity");
+        let mut ranked = self
+            .preference
+            .iter()
+            .enumerate()
+            .filter(|(_, alg)| !(self.zstd_dictionary && **alg == Algorithm::Zstd))
+            .filter_map(|(i, alg)| Some((accept.quality(alg.encoding())?, i, *alg)))
+            .filter(|(q, _, _)| *q > 0 && identity.is_none_or(|identity| *q >= identity))
+            .collect::<Vec<_>>();
+        ranked.sort_by_key(|(q, i, _)| (Reverse(*q), *i));
+        ranked.into_iter().map(|(_, _, alg)| alg).collect()
+    }
+}
+
+/// A parsed `Accept-Encoding` header, with q-values in thousandths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AcceptEncoding {
+    codings: Vec<(String, u16)>,
+}
+
+impl AcceptEncoding {
+    /// Parses a header value such as "gzip;q=0.8, br, *;q=0". Codings with
+    /// malformed parameters are ignored.
+    pub fn parse(header: &str) -> Self {
+        let codings = header
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let coding = params.next()?.trim().to_ascii_lowercase();
+                if coding.is_empty() {
+                    return None;
+                }
+                let mut q = 1000;
+                for param in params {
+                    let (name, value) = param.split_once('=')?;
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        q = parse_qvalue(value.trim())?;
+                    }
+                }
+                Some((coding, q))
+            })
+            .collect();
+        AcceptEncoding { codings }
+    }
+
+    /// Returns the q-value, in thousandths, that the header gives `coding`
+    /// either by name or through `*`, or `None` when it does not mention it.
+    pub fn quality(&self, coding: &str) -> Option<u16> {
+        let find = |name: &str| {
+            self.codings
+                .iter()
+                .find(|(c, _)| c == name)
+                .map(|(_, q)| *q)
+        };
+        find(coding)
+            .or_else(|| (coding == "gzip").then(|| find("x-gzip")).flatten())
+            .or_else(|| find("*"))
+    }
+}
+
+/// Parses a q-value, which has at most three decimals and is at most 1.
+fn parse_qvalue(value: &str) -> Option<u16> {
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let frac = format!("{frac:0<3}").parse::<u16>().ok()?;
+    match int {
+        "0" => Some(frac),
+        "1" if frac == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use anyhow::Result;
+
+    use super::{AcceptEncoding, Negotiated, Negotiator};
+    use crate::naming::Naming;
+    use crate::output::{Layout, Output};
+    use crate::precompress::Algorithm;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn accept_encoding_parses_q_values_and_wildcards() {
+        let accept = AcceptEncoding::parse("GZIP;q=0.5, br ;q=1.0, zstd;q=1.5, *;q=0.001");
+        assert_eq!(accept.quality("gzip"), Some(500));
+        assert_eq!(accept.quality("br"), Some(1000));
+        // An invalid q-value drops the coding, leaving the wildcard.
+        assert_eq!(accept.quality("zstd"), Some(1));
+        assert_eq!(accept.quality("identity"), Some(1));
+
+        let accept = AcceptEncoding::parse("x-gzip, identity;q=0");
+        assert_eq!(accept.quality("gzip"), Some(1000));
+        assert_eq!(accept.quality("identity"), Some(0));
+        assert_eq!(accept.quality("br"), None);
+        assert_eq!(AcceptEncoding::parse("").quality("gzip"), None);
+    }
+
+    #[test]
+    fn negotiate_ranks_by_q_value_then_preference() -> Result<()> {
+        let root = test_dir("negotiate");
+        fs::write(root.join("app.js"), "const x = 1;\n")?;
+        fs::write(root.join("app.js.br"), "br")?;
+        fs::write(root.join("app.js.gz"), "gz")?;
+        let negotiator = Negotiator::new(&root);
+        let negotiate = |accept: &str| negotiator.negotiate(Path::new("app.js"), accept);
+        let variant = |algorithm: Algorithm| Negotiated::Variant {
+            path: root.join(format!("app.js{}", algorithm.extension())),
+            algorithm,
+            encoding: algorithm.encoding(),
+        };
+
+        assert_eq!(negotiate("gzip, deflate, br"), variant(Algorithm::Brotli));
+        assert_eq!(negotiate("gzip, br;q=0.9"), variant(Algorithm::Gzip));
+        assert_eq!(negotiate("*"), variant(Algorithm::Brotli));
+        // Missing variants are skipped.
+        assert_eq!(negotiate("zstd, gzip;q=0.5"), variant(Algorithm::Gzip));
+        assert_eq!(negotiate("zstd"), Negotiated::Identity);
+        assert_eq!(negotiate("gzip;q=0.5, identity"), Negotiated::Identity);
+        assert_eq!(negotiate(""), Negotiated::Identity);
+        assert_eq!(negotiate("zstd, identity;q=0"), Negotiated::NotAcceptable);
+        assert_eq!(negotiate("*;q=0"), Negotiated::NotAcceptable);
+        assert_eq!(
+            negotiator.negotiate(&root.join("app.js"), "gzip"),
+            variant(Algorithm::Gzip)
+        );
+
+        let gzip_first = Negotiator::new(&root).preference([Algorithm::Gzip, Algorithm::Brotli]);
+        assert_eq!(
+            gzip_first.negotiate(Path::new("app.js"), "br, gzip"),
+            variant(Algorithm::Gzip)
+        );
+        let brotli_only = Negotiator::new(&root).preference([Algorithm::Brotli]);
+        assert_eq!(
+            brotli_only.negotiate(Path::new("app.js"), "gzip"),
+            Negotiated::Identity
+        );
+
+        fs::write(root.join("app.js.zst"), "zstd")?;
+        let zstd_dictionary = Negotiator::new(&root).zstd_dictionary(true);
+        assert_eq!(
+            zstd_dictionary.negotiate(Path::new("app.js"), "zstd, gzip;q=0.5"),
+            variant(Algorithm::Gzip)
+        );
+        assert_eq!(
+            zstd_dictionary.negotiate(Path::new("app.js"), "zstd"),
+            Negotiated::Identity
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn negotiate_follows_the_output_layout() -> Result<()> {
+        let root = test_dir("negotiate-layout");
+        let out_dir = root.join("out");
+        fs::create_dir_all(out_dir.join("zstd/js"))?;
+        fs::write(out_dir.join("zstd/js/app.js"), "zstd")?;
+        let negotiator = Negotiator::new(root.join("src")).output(Output {
+            out_dir: Some(out_dir.clone()),
+            layout: Layout::PerEncoding,
+            naming: Naming::default(),
+            ..Output::default()
+        });
+        assert_eq!(
+            negotiator.negotiate(Path::new("js/app.js"), "gzip, zstd"),
+            Negotiated::Variant {
+                path: out_dir.join("zstd/js/app.js"),
+                algorithm: Algorithm::Zstd,
+                encoding: "zstd",
+            }
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
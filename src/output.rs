@@ -0,0 +1,293 @@
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    path::{self, Component, Path, PathBuf},
+};
+
+use crate::dictionary::DictionaryFormat;
+use crate::naming::Naming;
+use crate::precompress::{Algorithm, Algorithms};
+
+/// Where compressed variants (and optionally the originals) are written.
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct Output {
+    /// Root of a separate output tree mirroring the source tree. Variants are
+    /// written beside their sources when unset.
+    pub out_dir: Option<PathBuf>,
+    /// How originals are placed into `out_dir`, if at all.
+    pub originals: Originals,
+    /// How variants are laid out relative to their sources.
+    pub layout: Layout,
+    /// File name templates for variants in the sibling layout.
+    pub naming: Naming,
+}
+
+/// How variants are laid out relative to their sources.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Layout {
+    // `app.js.br` beside `app.js`.
+    #[default]
+    Sibling,
+    // `br/app.js`, `gzip/app.js`, ... under the output directory, with
+    // originals placed under `identity/`.
+    PerEncoding,
+}
+
+/// How originals are placed into a separate output directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Originals {
+    #[default]
+    None,
+    Copy,
+    Hardlink,
+}
+
+impl Output {
+    /// Returns an output writing into `out_dir`, or beside the sources when
+    /// unset.
+    pub fn new(
+        out_dir: Option<PathBuf>,
+        originals: Originals,
+        layout: Layout,
+        naming: Naming,
+    ) -> Self {
+        Output {
+            out_dir,
+            originals,
+            layout,
+            naming,
+        }
+    }
+
+    /// Returns the path of the `alg` variant for the source at `path`, which
+    /// is `rel` relative to the root being compressed.
+    pub(crate) fn variant_path(
+        &self,
+        path: &Path,
+        rel: &Path,
+        alg: Algorithm,
+    ) -> io::Result<PathBuf> {
+        self.encoded_path(path, rel, alg.encoding(), |file_name| {
+            self.naming.get(alg).render(file_name, alg)
+        })
+    }
+
+    /// Returns the path of the `format` variant for the source at `path`.
+    /// Naming templates do not apply to dictionary-compressed variants.
+    pub(crate) fn dictionary_variant_path(
+        &self,
+        path: &Path,
+        rel: &Path,
+        format: DictionaryFormat,
+    ) -> io::Result<PathBuf> {
+        self.encoded_path(path, rel, format.encoding(), |file_name| {
+            let mut name = file_name.to_os_string();
+            name.push(format.extension());
+            name
+        })
+    }
+
+    fn encoded_path(
+        &self,
+        path: &Path,
+        rel: &Path,
+        encoding: &str,
+        file_name: impl FnOnce(&OsStr) -> OsString,
+    ) -> io::Result<PathBuf> {
+        if self.layout == Layout::PerEncoding {
+            let Some(out_dir) = &self.out_dir else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "per-encoding layout requires an output directory",
+                ));
+            };
+            return Ok(out_dir.join(encoding).join(rel));
+        }
+
+        let base = match &self.out_dir {
+            None => path,
+            Some(_) => &self.original_path(path, rel),
+        };
+        let Some(name) = base.file_name() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "path has no file name",
+            ));
+        };
+        Ok(base.with_file_name(file_name(name)))
+    }
+
+    /// Returns where the original of `path` lives in the output tree.
+    pub(crate) fn original_path(&self, path: &Path, rel: &Path) -> PathBuf {
+        match (&self.out_dir, self.layout) {
+            (None, _) => path.to_path_buf(),
+            (Some(out_dir), Layout::Sibling) => out_dir.join(rel),
+            (Some(out_dir), Layout::PerEncoding) => out_dir.join("identity").join(rel),
+        }
+    }
+
+    /// Returns whether originals are placed into a separate output tree.
+    pub(crate) fn places_originals(&self) -> bool {
+        self.out_dir.is_some() && self.originals != Originals::None
+    }
+
+    /// Returns whether `path` in the source tree is a variant written beside
+    /// a source that still exists, so that it is not compressed again.
+    pub(crate) fn is_sibling_variant(&self, path: &Path, algorithms: Algorithms) -> bool {
+        if self.out_dir.is_some() {
+            return false;
+        }
+        path.file_name()
+            .and_then(|name| self.naming.source_name(name, algorithms))
+            .is_some_and(|(_, source)| path.with_file_name(source).is_file())
+    }
+
+    /// Returns the output directory when it lives inside `root`, expressed
+    /// as a path under `root`, so that it can be excluded from the walk. The
+    /// output directory need not exist yet, as it is created during the walk.
+    pub(crate) fn nested_out_dir(&self, root: &Path) -> Option<PathBuf> {
+        let out_dir = resolve(self.out_dir.as_ref()?)?;
+        let rel = out_dir.strip_prefix(resolve(root)?).ok()?;
+        Some(root.join(rel))
+    }
+}
+
+/// Canonicalizes the longest existing ancestor of `path` and appends the
+/// rest of it, so that paths which do not exist yet can be compared.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let path = path::absolute(path).ok()?;
+    let mut existing = path.as_path();
+    let mut rest = Vec::new();
+    let mut resolved = loop {
+        if let Ok(resolved) = existing.canonicalize() {
+            break resolved;
+        }
+        rest.push(existing.components().next_back()?);
+        existing = existing.parent()?;
+    };
+    for component in rest.into_iter().rev() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => resolved.push(name),
+            _ => {}
+        }
+    }
+    Some(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{Layout, Originals, Output};
+    use crate::dictionary::DictionaryFormat;
+    use crate::naming::Naming;
+    use crate::precompress::Algorithm;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn variants_are_written_beside_sources_by_default() {
+        let output = Output::default();
+        let path = Path::new("site/css/app.css");
+        let variant = output
+            .variant_path(path, Path::new("css/app.css"), Algorithm::Brotli)
+            .expect("path has a file name");
+        assert_eq!(variant, Path::new("site/css/app.css.br"));
+        let variant = output
+            .dictionary_variant_path(path, Path::new("css/app.css"), DictionaryFormat::Dcz)
+            .expect("path has a file name");
+        assert_eq!(variant, Path::new("site/css/app.css.dcz"));
+        assert_eq!(output.original_path(path, Path::new("css/app.css")), path);
+        assert!(!output.places_originals());
+    }
+
+    #[test]
+    fn variants_mirror_the_source_tree_under_out_dir() {
+        let output = Output {
+            out_dir: Some(PathBuf::from("dist")),
+            originals: Originals::Copy,
+            layout: Layout::Sibling,
+            naming: Naming::default(),
+        };
+        let path = Path::new("site/css/app.css");
+        let rel = Path::new("css/app.css");
+        assert_eq!(
+            output
+                .variant_path(path, rel, Algorithm::Zstd)
+                .expect("path has a file name"),
+            Path::new("dist/css/app.css.zst")
+        );
+        assert_eq!(
+            output.original_path(path, rel),
+            Path::new("dist/css/app.css")
+        );
+        assert!(output.places_originals());
+    }
+
+    #[test]
+    fn per_encoding_layout_keeps_original_file_names() {
+        let output = Output {
+            out_dir: Some(PathBuf::from("out")),
+            originals: Originals::Hardlink,
+            layout: Layout::PerEncoding,
+            naming: Naming::default(),
+        };
+        let path = Path::new("site/js/app.js");
+        let rel = Path::new("js/app.js");
+        let variant = |alg| output.variant_path(path, rel, alg).expect("out dir is set");
+        assert_eq!(variant(Algorithm::Brotli), Path::new("out/br/js/app.js"));
+        assert_eq!(variant(Algorithm::Gzip), Path::new("out/gzip/js/app.js"));
+        assert_eq!(variant(Algorithm::Zstd), Path::new("out/zstd/js/app.js"));
+        assert_eq!(
+            output
+                .dictionary_variant_path(path, rel, DictionaryFormat::Dcz)
+                .expect("out dir is set"),
+            Path::new("out/dcz/js/app.js")
+        );
+        assert_eq!(
+            output.original_path(path, rel),
+            Path::new("out/identity/js/app.js")
+        );
+
+        let output = Output {
+            layout: Layout::PerEncoding,
+            ..Output::default()
+        };
+        assert!(output.variant_path(path, rel, Algorithm::Gzip).is_err());
+    }
+
+    #[test]
+    fn sibling_variants_use_naming_templates() {
+        let mut output = Output::default();
+        output.naming.set(
+            Algorithm::Gzip,
+            "{stem}.gz.{ext}".parse().expect("valid template"),
+        );
+        let path = Path::new("site/app.js");
+        assert_eq!(
+            output
+                .variant_path(path, Path::new("app.js"), Algorithm::Gzip)
+                .expect("path has a file name"),
+            Path::new("site/app.gz.js")
+        );
+    }
+
+    #[test]
+    fn nested_out_dir_need_not_exist() {
+        let root = test_dir("nested-missing-out-dir");
+        let output = Output {
+            out_dir: Some(root.join("build/../dist/assets")),
+            ..Output::default()
+        };
+        assert_eq!(output.nested_out_dir(&root), Some(root.join("dist/assets")));
+        let output = Output {
+            out_dir: Some(root.with_file_name("elsewhere")),
+            ..Output::default()
+        };
+        assert_eq!(output.nested_out_dir(&root), None);
+        std::fs::remove_dir_all(root).expect("remove test dir");
+    }
+}
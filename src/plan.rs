@@ -0,0 +1,401 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+use crate::config::FileSettings;
+use crate::encode::Context;
+use crate::precompress::{
+    Algorithm, Algorithms, CompressOptions, Mode, WalkOptions, build_excludes, build_walk,
+    is_compressible, is_up_to_date, walk_builder,
+};
+use crate::state::State;
+
+/// Number of leading bytes of each file compressed to estimate output sizes.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// What a run would do with a single path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanEntry {
+    /// The file, or the ignored or excluded directory.
+    pub path: PathBuf,
+    /// The size of the file, or zero for a directory.
+    pub size: u64,
+    /// Whether the file would be compressed.
+    pub decision: Decision,
+    /// Estimated variant sizes, when requested.
+    pub estimates: Vec<(Algorithm, u64)>,
+    /// Why the sizes could not be estimated, e.g. the file was unreadable.
+    pub estimate_error: Option<String>,
+}
+
+/// Whether a run would compress a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Variants of the file would be written.
+    Compress,
+    /// The file would be skipped for the given reason.
+    Skip(SkipReason),
+}
+
+/// Why a run would skip a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The extension is not selected for compression.
+    Extension,
+    /// The file is smaller than the minimum size.
+    TooSmall,
+    /// The path matches an `--exclude` pattern.
+    Excluded,
+    /// The path is hidden by an ignore file.
+    Ignored,
+    /// The file is a compressed variant of another source.
+    Variant,
+    /// A config rule excludes the file.
+    Rule,
+    /// An incremental run would find every variant up to date.
+    UpToDate,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SkipReason::Extension => "extension not selected",
+            SkipReason::TooSmall => "below minimum size",
+            SkipReason::Excluded => "excluded",
+            SkipReason::Ignored => "ignored",
+            SkipReason::Variant => "compressed variant",
+            SkipReason::Rule => "excluded by config rule",
+            SkipReason::UpToDate => "up to date",
+        })
+    }
+}
+
+/// Decides what compressing `root` with `options` would do for every path,
+/// without writing anything. Ignored and excluded directories are reported
+/// once rather than per file. Entries are sorted by path.
+pub(crate) fn plan(
+    root: &Path,
+    options: &CompressOptions,
+    walk_options: &WalkOptions,
+    estimate: bool,
+) -> Result<Vec<PlanEntry>> {
+    let nested_out_dir = options.output.nested_out_dir(root);
+    let selected = build_walk(root, walk_options)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .collect::<HashSet<_>>();
+
+    // Walk again without any filtering, pruning the subtrees that the
+    // filtered walk did not visit so that they can be reported.
+    let unfiltered = WalkOptions {
+        respect_ignore: false,
+        exclude: Vec::new(),
+    };
+    let pruned = Arc::new(Mutex::new(Vec::new()));
+    let walk = {
+        let pruned = pruned.clone();
+        walk_builder(root, &unfiltered)?
+            .filter_entry(move |entry| {
+                if selected.contains(entry.path()) {
+                    return true;
+                }
+                let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                pruned
+                    .lock()
+                    .expect("lock poisoned")
+                    .push((entry.path().to_path_buf(), is_dir));
+                false
+            })
+            .build()
+    };
+
+    let state = (options.incremental && options.mode == Mode::Compress)
+        .then(|| State::load(options.output.out_dir.as_deref().unwrap_or(root)));
+    let mut ctx = (estimate || state.is_some()).then(|| {
+        Context::new(1 << 14, options.quality).with_zstd_dictionary(options.zstd_dictionary.clone())
+    });
+    let base = FileSettings {
+        algorithms: options.algorithms,
+        quality: options.quality,
+        min_size: options.min_size,
+        include: None,
+    };
+    let variant_algorithms = options.config.all_algorithms(options.algorithms);
+    let mut entries = Vec::new();
+    for entry in walk {
+        let Ok(entry) = entry else { continue };
+        let path = entry.path();
+        if nested_out_dir
+            .as_ref()
+            .is_some_and(|out_dir| path.starts_with(out_dir))
+            || path.is_symlink()
+            || !path.is_file()
+        {
+            continue;
+        }
+        let size = entry.metadata().map(|m| m.len()).unwrap_or_default();
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        let settings = options.config.resolve(rel, base);
+        let mut decision = match settings.include {
+            Some(false) => Decision::Skip(SkipReason::Rule),
+            None if !is_compressible(path, options.extensions.as_ref()) => {
+                Decision::Skip(SkipReason::Extension)
+            }
+            _ if options.output.is_sibling_variant(path, variant_algorithms) => {
+                Decision::Skip(SkipReason::Variant)
+            }
+            _ if size < settings.min_size => Decision::Skip(SkipReason::TooSmall),
+            _ => Decision::Compress,
+        };
+        if decision == Decision::Compress
+            && let (Some(state), Some(ctx)) = (&state, &mut ctx)
+        {
+            ctx.set_quality(settings.quality);
+            if is_up_to_date(ctx, path, rel, &settings, state, options) {
+                decision = Decision::Skip(SkipReason::UpToDate);
+            }
+        }
+        // A file that cannot be sampled is still planned, without estimates.
+        let (estimates, estimate_error) = match (&mut ctx, decision) {
+            (Some(ctx), Decision::Compress) if estimate => {
+                ctx.set_quality(settings.quality);
+                match estimate_sizes(ctx, path, size, settings.algorithms) {
+                    Ok(estimates) => (estimates, None),
+                    Err(err) => (Vec::new(), Some(err.to_string())),
+                }
+            }
+            _ => (Vec::new(), None),
+        };
+        entries.push(PlanEntry {
+            path: path.to_path_buf(),
+            size,
+            decision,
+            estimates,
+            estimate_error,
+        });
+    }
+
+    let excludes = build_excludes(root, walk_options)?;
+    let pruned = std::mem::take(&mut *pruned.lock().expect("lock poisoned"));
+    for (path, is_dir) in pruned {
+        let excluded = excludes
+            .as_ref()
+            .is_some_and(|excludes| excludes.matched(&path, is_dir).is_ignore());
+        let reason = if excluded {
+            SkipReason::Excluded
+        } else {
+            SkipReason::Ignored
+        };
+        entries.push(PlanEntry {
+            path,
+            size: 0,
+            decision: Decision::Skip(reason),
+            estimates: Vec::new(),
+            estimate_error: None,
+        });
+    }
+
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// Estimates the size of each variant by compressing a leading sample of
+/// the file and scaling the result to its full size.
+fn estimate_sizes(
+    ctx: &mut Context,
+    path: &Path,
+    size: u64,
+    algorithms: Algorithms,
+) -> io::Result<Vec<(Algorithm, u64)>> {
+    let mut sample = Vec::new();
+    File::open(path)?
+        .take(SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut estimates = Vec::new();
+    let mut out = Vec::new();
+    for alg in algorithms.iter() {
+        out.clear();
+        ctx.write(alg, &mut sample.as_slice(), &mut out)?;
+        let estimate = out.len() as u128 * size as u128 / sample.len() as u128;
+        estimates.push((alg, estimate as u64));
+    }
+    Ok(estimates)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs, path::Path};
+
+    use anyhow::Result;
+
+    use super::{Decision, SkipReason, plan};
+    use crate::config::Config;
+    use crate::precompress::{Algorithm, Algorithms, CompressOptions, Compressor, WalkOptions};
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn plan_reports_each_decision_without_writing() -> Result<()> {
+        let root = test_dir("plan");
+        fs::write(root.join(".ignore"), "vendor/\n")?;
+        fs::create_dir(root.join("vendor"))?;
+        fs::write(root.join("vendor/lib.js"), "x".repeat(4096))?;
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(512))?;
+        fs::write(root.join("small.js"), "x")?;
+        fs::write(root.join("logo.png"), [0; 4096])?;
+        fs::write(root.join("skip.js"), "x".repeat(4096))?;
+
+        let options = CompressOptions {
+            algorithms: Algorithms {
+                brotli: false,
+                deflate: false,
+                gzip: true,
+                zstd: false,
+            },
+            extensions: Some(HashSet::from([String::from("js")])),
+            ..CompressOptions::default()
+        };
+        let walk_options = WalkOptions {
+            respect_ignore: true,
+            exclude: vec![String::from("skip.js")],
+        };
+        let entries = plan(&root, &options, &walk_options, true)?;
+        let decisions = entries
+            .iter()
+            .map(|entry| (relative(&root, &entry.path), entry.decision))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            decisions,
+            vec![
+                (String::from("app.js"), Decision::Compress),
+                (
+                    String::from("logo.png"),
+                    Decision::Skip(SkipReason::Extension)
+                ),
+                (
+                    String::from("skip.js"),
+                    Decision::Skip(SkipReason::Excluded)
+                ),
+                (
+                    String::from("small.js"),
+                    Decision::Skip(SkipReason::TooSmall)
+                ),
+                (String::from("vendor"), Decision::Skip(SkipReason::Ignored)),
+            ]
+        );
+        let app = &entries[0];
+        assert_eq!(app.estimates.len(), 1);
+        assert!(app.estimates[0].1 > 0 && app.estimates[0].1 < app.size);
+        assert!(!root.join("app.js.gz").exists());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn plan_applies_config_rules() -> Result<()> {
+        let root = test_dir("plan-config");
+        fs::create_dir(root.join("private"))?;
+        fs::write(root.join("private/keys.js"), "x".repeat(4096))?;
+        fs::write(root.join("LICENSE"), "x".repeat(4096))?;
+        fs::write(root.join("index.html"), "x".repeat(512))?;
+
+        let options = CompressOptions {
+            config: Config::parse(
+                r#"
+                [[rule]]
+                glob = "/private/**"
+                include = false
+
+                [[rule]]
+                glob = "LICENSE"
+                include = true
+                algorithms = ["br"]
+
+                [[rule]]
+                glob = "*.html"
+                min_size = 256
+                "#,
+            )?,
+            ..CompressOptions::default()
+        };
+        let entries = plan(&root, &options, &WalkOptions::default(), true)?;
+        let decisions = entries
+            .iter()
+            .map(|entry| (relative(&root, &entry.path), entry.decision))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decisions,
+            vec![
+                (String::from("LICENSE"), Decision::Compress),
+                (String::from("index.html"), Decision::Compress),
+                (
+                    String::from("private/keys.js"),
+                    Decision::Skip(SkipReason::Rule)
+                ),
+            ]
+        );
+        assert_eq!(entries[0].estimates.len(), 1);
+        assert_eq!(entries[0].estimates[0].0, Algorithm::Brotli);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn incremental_plan_skips_up_to_date_files() -> Result<()> {
+        let root = test_dir("plan-incremental");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(512))?;
+        fs::write(root.join("lib.js"), "const y = 2;\n".repeat(512))?;
+
+        let options = CompressOptions {
+            algorithms: Algorithms {
+                brotli: false,
+                deflate: false,
+                gzip: true,
+                zstd: false,
+            },
+            incremental: true,
+            ..CompressOptions::default()
+        };
+        let compressor = Compressor::new(1, options.clone());
+        compressor.precompress(&root, &WalkOptions::default())?;
+        compressor.finish();
+        fs::write(root.join("lib.js"), "const y = 3;\n".repeat(512))?;
+
+        let entries = plan(&root, &options, &WalkOptions::default(), false)?;
+        let decisions = entries
+            .iter()
+            .filter(|entry| entry.path.extension().is_some_and(|ext| ext == "js"))
+            .map(|entry| (relative(&root, &entry.path), entry.decision))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decisions,
+            vec![
+                (String::from("app.js"), Decision::Skip(SkipReason::UpToDate)),
+                (String::from("lib.js"), Decision::Compress),
+            ]
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    fn relative(root: &Path, path: &Path) -> String {
+        path.strip_prefix(root)
+            .expect("path should be under root")
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+}
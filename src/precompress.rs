@@ -5,21 +5,34 @@ use std::{
     io::{self, Seek},
     mem::take,
     path::{Path, PathBuf},
-    thread::{JoinHandle, spawn},
-    time::{Duration, Instant},
+    str::FromStr,
+    sync::{Arc, Mutex, mpsc::Sender},
+    thread::{JoinHandle, available_parallelism, spawn},
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::Result;
-use crossbeam::channel::{Receiver, Sender, bounded};
-use ignore::overrides::OverrideBuilder;
+use anyhow::{Result, anyhow};
+use crossbeam::channel::{self, Receiver, bounded};
+use ignore::overrides::{Override, OverrideBuilder};
+use serde::Serialize;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
-use crate::encode::{Context, Quality};
-use crate::{calc_savings, format_bytes};
+use crate::cache::{Cache, hash_file};
+use crate::config::{Config, FileSettings};
+use crate::dictionary::{Dictionaries, Dictionary, DictionaryFormat};
+use crate::encode::{Context, ParseCompressionError, Quality};
+use crate::output::{Originals, Output};
+use crate::plan::{PlanEntry, plan};
+use crate::progress::{Counters, Progress, ProgressCallback};
+use crate::report::{
+    Event, FileReport, VariantOutcome, VariantReport, calc_savings, serialize_secs,
+};
+use crate::state::{Record, State, settings_digest};
 
-#[derive(Debug, Clone, Copy, EnumIter)]
-pub(crate) enum Algorithm {
+/// A compression algorithm that variants are written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter)]
+pub enum Algorithm {
     Brotli,
     Deflate,
     Gzip,
@@ -38,8 +51,41 @@ impl std::fmt::Display for Algorithm {
     }
 }
 
+impl serde::Serialize for Algorithm {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = ParseCompressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Algorithm::NAMES.iter().find(|(name, _)| *name == s) {
+            Some((_, alg)) => Ok(*alg),
+            None => Err(ParseCompressionError::UnknownAlgorithm {
+                token: s.to_owned(),
+                suggestion: closest_name(s),
+            }),
+        }
+    }
+}
+
 impl Algorithm {
-    fn extension(self) -> &'static str {
+    /// The names accepted for each algorithm, including short aliases.
+    pub const NAMES: [(&'static str, Algorithm); 8] = [
+        ("br", Algorithm::Brotli),
+        ("brotli", Algorithm::Brotli),
+        ("de", Algorithm::Deflate),
+        ("deflate", Algorithm::Deflate),
+        ("gz", Algorithm::Gzip),
+        ("gzip", Algorithm::Gzip),
+        ("zst", Algorithm::Zstd),
+        ("zstd", Algorithm::Zstd),
+    ];
+
+    /// Returns the extension appended to the names of variants by default.
+    pub fn extension(self) -> &'static str {
         match self {
             Self::Brotli => ".br",
             Self::Deflate => ".zz",
@@ -47,14 +93,115 @@ impl Algorithm {
             Self::Zstd => ".zst",
         }
     }
+
+    /// Returns the HTTP `Content-Encoding` token for the algorithm.
+    pub fn encoding(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Deflate => "deflate",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Returns the algorithm name closest to `s`, if it is close enough to be a
+/// likely typo.
+fn closest_name(s: &str) -> Option<&'static str> {
+    let s = s.to_ascii_lowercase();
+    let max_distance = (s.chars().count() / 2).max(1);
+    Algorithm::NAMES
+        .iter()
+        .map(|(name, _)| (edit_distance(&s, name), *name))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+/// Returns the Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != *cb);
+            cur[j + 1] = substitute.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
 }
 
+/// What a variant is encoded with.
 #[derive(Debug, Clone, Copy)]
-pub(crate) struct Algorithms {
-    pub(crate) brotli: bool,
-    pub(crate) deflate: bool,
-    pub(crate) gzip: bool,
-    pub(crate) zstd: bool,
+enum Encoding<'a> {
+    Algorithm(Algorithm),
+    Dictionary(DictionaryFormat, &'a Dictionary),
+}
+
+impl std::fmt::Display for Encoding<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Algorithm(alg) => alg.fmt(f),
+            Encoding::Dictionary(format, _) => format.fmt(f),
+        }
+    }
+}
+
+impl Encoding<'_> {
+    fn variant_path(self, output: &Output, path: &Path, rel: &Path) -> io::Result<PathBuf> {
+        match self {
+            Encoding::Algorithm(alg) => output.variant_path(path, rel, alg),
+            Encoding::Dictionary(format, _) => output.dictionary_variant_path(path, rel, format),
+        }
+    }
+
+    fn write(self, ctx: &mut Context, input: &mut File, output: &mut File) -> io::Result<()> {
+        match self {
+            Encoding::Algorithm(alg) => ctx.write(alg, input, output),
+            Encoding::Dictionary(format, dictionary) => {
+                ctx.write_dictionary(format, dictionary, input, output)
+            }
+        }
+    }
+
+    fn decodes_to(self, ctx: &mut Context, encoded: &mut File, src: &mut File) -> io::Result<bool> {
+        match self {
+            Encoding::Algorithm(alg) => ctx.decodes_to(alg, encoded, src),
+            Encoding::Dictionary(format, dictionary) => {
+                ctx.dictionary_decodes_to(format, dictionary, encoded, src)
+            }
+        }
+    }
+
+    /// Reports whether the header of `encoded` matches this encoding, which
+    /// only identifies anything for zstd and dictionary-compressed variants.
+    fn header_matches(self, ctx: &Context, encoded: &mut File) -> bool {
+        let matches = match self {
+            Encoding::Algorithm(Algorithm::Zstd) => {
+                ctx.zstd_header_matches(encoded).unwrap_or(false)
+            }
+            Encoding::Algorithm(_) => return true,
+            Encoding::Dictionary(format, dictionary) => {
+                format.read_header(encoded, dictionary).unwrap_or(false)
+            }
+        };
+        encoded.rewind().is_ok() && matches
+    }
+}
+
+/// The algorithms to write variants with. Start from [`Algorithms::all`],
+/// [`Algorithms::empty`] or the default and [`enable`](Algorithms::enable) or
+/// [`disable`](Algorithms::disable) the others, or collect them from an
+/// iterator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Algorithms {
+    pub brotli: bool,
+    pub deflate: bool,
+    pub gzip: bool,
+    pub zstd: bool,
 }
 
 impl Default for Algorithms {
@@ -69,7 +216,18 @@ impl Default for Algorithms {
 }
 
 impl Algorithms {
-    pub(crate) fn empty() -> Self {
+    /// Returns every algorithm.
+    pub fn all() -> Self {
+        Self {
+            brotli: true,
+            deflate: true,
+            gzip: true,
+            zstd: true,
+        }
+    }
+
+    /// Returns no algorithms.
+    pub fn empty() -> Self {
         Self {
             brotli: false,
             deflate: false,
@@ -78,11 +236,94 @@ impl Algorithms {
         }
     }
 
-    pub(crate) fn iter(self) -> impl Iterator<Item = Algorithm> {
+    /// Returns the enabled algorithms in a fixed order.
+    pub fn iter(self) -> impl Iterator<Item = Algorithm> {
         Algorithm::iter().filter(move |algorithm| self.is_enabled(*algorithm))
     }
 
-    fn is_enabled(&self, algorithm: Algorithm) -> bool {
+    /// Reports whether `algorithm` is enabled.
+    pub fn is_enabled(&self, algorithm: Algorithm) -> bool {
+        match algorithm {
+            Algorithm::Brotli => self.brotli,
+            Algorithm::Deflate => self.deflate,
+            Algorithm::Gzip => self.gzip,
+            Algorithm::Zstd => self.zstd,
+        }
+    }
+
+    /// Enables `algorithm`.
+    pub fn enable(&mut self, algorithm: Algorithm) {
+        match algorithm {
+            Algorithm::Brotli => self.brotli = true,
+            Algorithm::Deflate => self.deflate = true,
+            Algorithm::Gzip => self.gzip = true,
+            Algorithm::Zstd => self.zstd = true,
+        }
+    }
+
+    /// Disables `algorithm`.
+    pub fn disable(&mut self, algorithm: Algorithm) {
+        match algorithm {
+            Algorithm::Brotli => self.brotli = false,
+            Algorithm::Deflate => self.deflate = false,
+            Algorithm::Gzip => self.gzip = false,
+            Algorithm::Zstd => self.zstd = false,
+        }
+    }
+}
+
+/// The minimum savings a variant must achieve over its source to be kept.
+/// Either limit may be unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[non_exhaustive]
+pub struct Threshold {
+    /// The fraction of the source size that must be saved, e.g. 0.05.
+    pub ratio: Option<f64>,
+    /// The number of bytes that must be saved.
+    pub bytes: Option<u64>,
+}
+
+impl Threshold {
+    /// Returns a threshold with the given limits.
+    pub fn new(ratio: Option<f64>, bytes: Option<u64>) -> Self {
+        Threshold { ratio, bytes }
+    }
+
+    /// Reports whether a variant of `size` bytes saves enough over a source of
+    /// `src_size` bytes.
+    pub fn is_met(&self, src_size: u64, size: u64) -> bool {
+        let saved = src_size as i64 - size as i64;
+        self.bytes.is_none_or(|bytes| saved >= bytes as i64)
+            && self
+                .ratio
+                .is_none_or(|ratio| saved as f64 >= ratio * src_size as f64)
+    }
+}
+
+/// The minimum savings for each algorithm. Dictionary-compressed variants use
+/// the threshold of the algorithm they are compared against.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[non_exhaustive]
+pub struct MinSavings {
+    pub brotli: Threshold,
+    pub deflate: Threshold,
+    pub gzip: Threshold,
+    pub zstd: Threshold,
+}
+
+impl MinSavings {
+    /// Returns the same `threshold` for every algorithm.
+    pub fn new(threshold: Threshold) -> Self {
+        MinSavings {
+            brotli: threshold,
+            deflate: threshold,
+            gzip: threshold,
+            zstd: threshold,
+        }
+    }
+
+    /// Returns the threshold for variants compressed with `algorithm`.
+    pub fn get(&self, algorithm: Algorithm) -> Threshold {
         match algorithm {
             Algorithm::Brotli => self.brotli,
             Algorithm::Deflate => self.deflate,
@@ -90,21 +331,104 @@ impl Algorithms {
             Algorithm::Zstd => self.zstd,
         }
     }
+
+    /// Returns the threshold for `algorithm` for changing it in place.
+    pub fn get_mut(&mut self, algorithm: Algorithm) -> &mut Threshold {
+        match algorithm {
+            Algorithm::Brotli => &mut self.brotli,
+            Algorithm::Deflate => &mut self.deflate,
+            Algorithm::Gzip => &mut self.gzip,
+            Algorithm::Zstd => &mut self.zstd,
+        }
+    }
+}
+
+/// Prunes the variants of a file that are not smaller than the variant of a
+/// baseline algorithm, which clients that accept them also accept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct KeepBest {
+    /// The algorithm whose variant every other variant must beat.
+    pub baseline: Algorithm,
+    /// The fraction of the baseline variant's size that another variant
+    /// must save to be kept, e.g. 0.02.
+    pub margin: f64,
+}
+
+impl KeepBest {
+    /// Returns a policy that keeps any variant smaller than the `baseline`
+    /// variant.
+    pub fn new(baseline: Algorithm) -> Self {
+        KeepBest {
+            baseline,
+            margin: 0.0,
+        }
+    }
+
+    /// Sets the fraction of the baseline variant's size that another variant
+    /// must save to be kept.
+    pub fn with_margin(mut self, margin: f64) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    /// Reports whether a variant of `size` bytes beats a baseline variant of
+    /// `baseline_size` bytes by the margin.
+    pub fn beats(&self, size: u64, baseline_size: u64) -> bool {
+        (size as f64) < baseline_size as f64 * (1.0 - self.margin)
+    }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-pub(crate) struct Stats {
-    pub(crate) num_source_files: u64,
-    pub(crate) num_errors: u64,
+impl FromIterator<Algorithm> for Algorithms {
+    fn from_iter<I: IntoIterator<Item = Algorithm>>(iter: I) -> Self {
+        let mut algorithms = Algorithms::empty();
+        for algorithm in iter {
+            algorithms.enable(algorithm);
+        }
+        algorithms
+    }
+}
 
-    pub(crate) brotli: AlgStat,
-    pub(crate) deflate: AlgStat,
-    pub(crate) gzip: AlgStat,
-    pub(crate) zstd: AlgStat,
+/// The totals of a run. More counters may be added, so create one with
+/// [`Default`] rather than a struct expression.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Source files that at least one variant was written for.
+    pub num_source_files: u64,
+    /// Source files whose variants were all up to date.
+    pub num_up_to_date: u64,
+    /// Variants copied from the cache instead of being compressed.
+    pub num_cache_hits: u64,
+    /// Originals copied or linked into the output directory.
+    pub num_originals: u64,
+    /// Variants that a check found missing.
+    pub num_missing: u64,
+    /// Variants that a check found not to match their source.
+    pub num_stale: u64,
+    /// Variants that a check could not decode.
+    pub num_corrupt: u64,
+    /// Files or variants that could not be read or written.
+    pub num_errors: u64,
+    /// Variants discarded, or removed, for not meeting the minimum savings.
+    pub num_discarded: u64,
+    /// Variants removed for not beating the keep-best baseline.
+    pub num_pruned: u64,
+
+    pub brotli: AlgStat,
+    pub deflate: AlgStat,
+    pub gzip: AlgStat,
+    pub zstd: AlgStat,
+    pub dcz: DictStat,
+    pub dcb: DictStat,
+    /// With a zstd dictionary, the total size the zstd variants would have
+    /// without it.
+    pub zstd_baseline_bytes: u64,
 }
 
 impl Stats {
-    pub(crate) fn for_algorithm(&self, alg: Algorithm) -> AlgStat {
+    /// Returns the totals of the variants compressed with `alg`.
+    pub fn for_algorithm(&self, alg: Algorithm) -> AlgStat {
         match alg {
             Algorithm::Brotli => self.brotli,
             Algorithm::Deflate => self.deflate,
@@ -112,6 +436,14 @@ impl Stats {
             Algorithm::Zstd => self.zstd,
         }
     }
+
+    /// Returns the totals of the variants in the dictionary `format`.
+    pub fn for_dictionary(&self, format: DictionaryFormat) -> DictStat {
+        match format {
+            DictionaryFormat::Dcz => self.dcz,
+            DictionaryFormat::Dcb => self.dcb,
+        }
+    }
 }
 
 impl std::ops::Add<Stats> for Stats {
@@ -120,20 +452,48 @@ impl std::ops::Add<Stats> for Stats {
     fn add(self, rhs: Stats) -> Stats {
         Stats {
             num_source_files: self.num_source_files + rhs.num_source_files,
+            num_up_to_date: self.num_up_to_date + rhs.num_up_to_date,
+            num_cache_hits: self.num_cache_hits + rhs.num_cache_hits,
+            num_originals: self.num_originals + rhs.num_originals,
+            num_missing: self.num_missing + rhs.num_missing,
+            num_stale: self.num_stale + rhs.num_stale,
+            num_corrupt: self.num_corrupt + rhs.num_corrupt,
             num_errors: self.num_errors + rhs.num_errors,
+            num_discarded: self.num_discarded + rhs.num_discarded,
+            num_pruned: self.num_pruned + rhs.num_pruned,
             brotli: self.brotli + rhs.brotli,
             deflate: self.deflate + rhs.deflate,
             gzip: self.gzip + rhs.gzip,
             zstd: self.zstd + rhs.zstd,
+            dcz: self.dcz + rhs.dcz,
+            dcb: self.dcb + rhs.dcb,
+            zstd_baseline_bytes: self.zstd_baseline_bytes + rhs.zstd_baseline_bytes,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-pub(crate) struct AlgStat {
-    pub(crate) total_time: Duration,
-    pub(crate) total_bytes: u64,
-    pub(crate) saved_bytes: i64,
+/// Totals for the variants compressed with one algorithm.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[non_exhaustive]
+pub struct AlgStat {
+    /// The time spent compressing.
+    #[serde(serialize_with = "serialize_secs")]
+    pub total_time: Duration,
+    /// The total size of the variants.
+    pub total_bytes: u64,
+    /// The total size of their sources less `total_bytes`.
+    pub saved_bytes: i64,
+}
+
+impl AlgStat {
+    /// Returns the given totals.
+    pub fn new(total_time: Duration, total_bytes: u64, saved_bytes: i64) -> Self {
+        AlgStat {
+            total_time,
+            total_bytes,
+            saved_bytes,
+        }
+    }
 }
 
 impl std::ops::Add<AlgStat> for AlgStat {
@@ -148,18 +508,195 @@ impl std::ops::Add<AlgStat> for AlgStat {
     }
 }
 
-pub(crate) struct Compressor {
-    tx: Sender<Unit>,
+/// Totals for a dictionary-compressed format, along with the size of the
+/// plain variants of the same files for comparison.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[non_exhaustive]
+pub struct DictStat {
+    /// The time spent compressing.
+    #[serde(serialize_with = "serialize_secs")]
+    pub total_time: Duration,
+    /// The total size of the variants.
+    pub total_bytes: u64,
+    /// The total size of their sources less `total_bytes`.
+    pub saved_bytes: i64,
+    /// The total size of the baseline algorithm's variants of the same files.
+    pub baseline_bytes: u64,
+}
+
+impl DictStat {
+    /// Returns the given totals.
+    pub fn new(
+        total_time: Duration,
+        total_bytes: u64,
+        saved_bytes: i64,
+        baseline_bytes: u64,
+    ) -> Self {
+        DictStat {
+            total_time,
+            total_bytes,
+            saved_bytes,
+            baseline_bytes,
+        }
+    }
+}
+
+impl std::ops::Add<DictStat> for DictStat {
+    type Output = DictStat;
+
+    fn add(self, rhs: DictStat) -> Self::Output {
+        DictStat {
+            total_time: self.total_time + rhs.total_time,
+            total_bytes: self.total_bytes + rhs.total_bytes,
+            saved_bytes: self.saved_bytes + rhs.saved_bytes,
+            baseline_bytes: self.baseline_bytes + rhs.baseline_bytes,
+        }
+    }
+}
+
+/// Compresses every selected file in a directory tree on a pool of worker
+/// threads. Create one with [`Compressor::builder`].
+pub struct Compressor {
+    tx: channel::Sender<Unit>,
     handles: Vec<JoinHandle<Stats>>,
     extensions: Option<HashSet<String>>,
-    #[allow(dead_code)]
-    verbose: bool,
+    /// The settings of files that no config rule matches.
+    settings: FileSettings,
+    config: Config,
+    /// Every algorithm that any file may be compressed with.
+    variant_algorithms: Algorithms,
+    output: Output,
+    events: Option<Sender<Event>>,
+    progress: Arc<Counters>,
+    /// Whether variants are only rewritten when out of date, in which case
+    /// each run's state is loaded before walking and saved when finishing.
+    incremental: bool,
+    states: Mutex<Vec<Arc<State>>>,
+}
+
+/// What a run does with the variants of each file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Write compressed variants.
+    #[default]
+    Compress,
+    /// Report missing, stale and corrupt variants without writing anything.
+    Check,
+}
+
+/// The state of an existing variant relative to its source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VariantStatus {
+    Current,
+    Missing,
+    Stale,
+    Corrupt,
+}
+
+impl std::fmt::Display for VariantStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VariantStatus::Current => "current",
+            VariantStatus::Missing => "missing",
+            VariantStatus::Stale => "stale",
+            VariantStatus::Corrupt => "corrupt",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CompressOptions {
+    pub(crate) mode: Mode,
+    pub(crate) min_size: u64,
+    pub(crate) quality: Quality,
+    pub(crate) min_savings: MinSavings,
+    pub(crate) keep_best: Option<KeepBest>,
+    /// A dictionary used to compress and decode the zstd variants.
+    pub(crate) zstd_dictionary: Option<Arc<Dictionary>>,
+    pub(crate) algorithms: Algorithms,
+    pub(crate) extensions: Option<HashSet<String>>,
+    /// Skip variants that already decode to the current source contents.
+    pub(crate) incremental: bool,
+    /// Decode every written variant and compare it against its source
+    /// before replacing the existing output.
+    pub(crate) verify: bool,
+    /// Reuse previously compressed variants of identical source contents.
+    pub(crate) cache: Option<Cache>,
+    pub(crate) output: Output,
+    /// Dictionaries to write dictionary-compressed variants against, if any.
+    pub(crate) dictionaries: Option<Dictionaries>,
+    pub(crate) dictionary_formats: Vec<DictionaryFormat>,
+    /// Rules overriding the settings above for matching files.
+    pub(crate) config: Config,
+    /// Receives the results for each source file and any warnings.
+    pub(crate) events: Option<Sender<Event>>,
+    pub(crate) on_progress: Option<ProgressCallback>,
+    /// Counters shared with the other workers, replaced for every run.
+    pub(crate) progress: Arc<Counters>,
+}
+
+impl CompressOptions {
+    /// Reports whether the file at `path`, at `rel` in the tree, is one that
+    /// runs compress.
+    fn is_source(&self, path: &Path, rel: &Path) -> bool {
+        let base = FileSettings {
+            algorithms: self.algorithms,
+            quality: self.quality,
+            min_size: self.min_size,
+            include: None,
+        };
+        self.config
+            .resolve(rel, base)
+            .include
+            .unwrap_or_else(|| is_compressible(path, self.extensions.as_ref()))
+    }
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            min_size: 1024,
+            quality: Quality::default(),
+            min_savings: MinSavings::default(),
+            keep_best: None,
+            zstd_dictionary: None,
+            algorithms: Algorithms::default(),
+            extensions: None,
+            incremental: false,
+            verify: false,
+            cache: None,
+            output: Output::default(),
+            dictionaries: None,
+            dictionary_formats: vec![DictionaryFormat::Dcz],
+            events: None,
+            on_progress: None,
+            config: Config::default(),
+            progress: Arc::default(),
+        }
+    }
 }
 
+/// Which paths under the root a run visits.
 #[derive(Debug, Clone)]
-pub(crate) struct WalkOptions {
-    pub(crate) respect_ignore: bool,
-    pub(crate) exclude: Vec<String>,
+#[non_exhaustive]
+pub struct WalkOptions {
+    /// Whether to skip paths hidden by `.gitignore`, `.ignore` and similar
+    /// files.
+    pub respect_ignore: bool,
+    /// Globs of paths to skip.
+    pub exclude: Vec<String>,
+}
+
+impl WalkOptions {
+    /// Walks honoring `.gitignore` and similar files if `respect_ignore`,
+    /// skipping paths matched by the `exclude` globs.
+    pub fn new(respect_ignore: bool, exclude: Vec<String>) -> Self {
+        WalkOptions {
+            respect_ignore,
+            exclude,
+        }
+    }
 }
 
 impl Default for WalkOptions {
@@ -171,106 +708,490 @@ impl Default for WalkOptions {
     }
 }
 
-type Unit = PathBuf;
+struct Unit {
+    path: PathBuf,
+    /// The path relative to the root being compressed.
+    rel: PathBuf,
+    /// The size of the file when it was discovered.
+    size: u64,
+    /// Whether the file should be compressed, as opposed to only having its
+    /// original placed into the output tree.
+    compress: bool,
+    settings: FileSettings,
+    /// The state of incremental runs, if this is one.
+    state: Option<Arc<State>>,
+}
+
+struct Source {
+    file: File,
+    size: u64,
+    modified: SystemTime,
+    digest: Option<[u8; 32]>,
+    /// The algorithms enabled for this file.
+    algorithms: Algorithms,
+}
+
+impl Source {
+    fn digest(&mut self) -> io::Result<[u8; 32]> {
+        if let Some(digest) = self.digest {
+            return Ok(digest);
+        }
+        self.file.rewind()?;
+        let digest = hash_file(&mut self.file, &mut [0; 1 << 14])?;
+        self.digest = Some(digest);
+        Ok(digest)
+    }
+}
+
+/// Configures a [`Compressor`].
+///
+/// The defaults compress files with the default extensions of at least
+/// 1 KiB using brotli, gzip and zstd at their default qualities, on one
+/// thread per CPU, writing each variant beside its source.
+#[derive(Debug, Clone, Default)]
+pub struct CompressorBuilder {
+    threads: usize,
+    options: CompressOptions,
+}
+
+impl CompressorBuilder {
+    /// Sets the number of worker threads; "0" uses the number of CPUs.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Sets whether variants are written or only checked.
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.options.mode = mode;
+        self
+    }
+
+    /// Sets the minimum size of files to be compressed in bytes.
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.options.min_size = min_size;
+        self
+    }
+
+    /// Sets the algorithms to write variants with.
+    pub fn algorithms(mut self, algorithms: Algorithms) -> Self {
+        self.options.algorithms = algorithms;
+        self
+    }
+
+    /// Sets the compression level of each algorithm.
+    pub fn quality(mut self, quality: Quality) -> Self {
+        self.options.quality = quality;
+        self
+    }
+
+    /// Discards variants that do not save at least this much over their
+    /// source, removing any existing variant in their place.
+    pub fn min_savings(mut self, min_savings: MinSavings) -> Self {
+        self.options.min_savings = min_savings;
+        self
+    }
+
+    /// After writing the variants of a file, removes those that do not beat
+    /// the variant of the baseline algorithm.
+    pub fn keep_best(mut self, keep_best: impl Into<Option<KeepBest>>) -> Self {
+        self.options.keep_best = keep_best.into();
+        self
+    }
+
+    /// Compresses files with these extensions instead of the defaults.
+    pub fn extensions(mut self, extensions: impl Into<Option<HashSet<String>>>) -> Self {
+        self.options.extensions = extensions.into();
+        self
+    }
+
+    /// Skips variants that are already up to date with their source.
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.options.incremental = incremental;
+        self
+    }
+
+    /// Decodes every written variant and compares it against its source
+    /// before replacing the existing output.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.options.verify = verify;
+        self
+    }
+
+    /// Reuses previously compressed variants of identical source contents.
+    pub fn cache(mut self, cache: impl Into<Option<Cache>>) -> Self {
+        self.options.cache = cache.into();
+        self
+    }
+
+    /// Sets where variants are written and how they are named.
+    pub fn output(mut self, output: Output) -> Self {
+        self.options.output = output;
+        self
+    }
+
+    /// Compresses and decodes the zstd variants using `dictionary`.
+    pub fn zstd_dictionary(mut self, dictionary: impl Into<Option<Arc<Dictionary>>>) -> Self {
+        self.options.zstd_dictionary = dictionary.into();
+        self
+    }
+
+    /// Also writes dictionary-compressed variants using `dictionaries`.
+    pub fn dictionaries(mut self, dictionaries: impl Into<Option<Dictionaries>>) -> Self {
+        self.options.dictionaries = dictionaries.into();
+        self
+    }
+
+    /// Sets the dictionary-compressed formats to write; only `dcz` by
+    /// default.
+    pub fn dictionary_formats(mut self, formats: Vec<DictionaryFormat>) -> Self {
+        self.options.dictionary_formats = formats;
+        self
+    }
+
+    /// Sends the results for each source file, and any warnings, to
+    /// `events`. Nothing is printed by the compressor itself.
+    pub fn events(mut self, events: impl Into<Option<Sender<Event>>>) -> Self {
+        self.options.events = events.into();
+        self
+    }
+
+    /// Decides what compressing `root` would do for every path, without
+    /// writing anything. See [`PlanEntry`].
+    pub fn plan(
+        &self,
+        root: &Path,
+        walk_options: &WalkOptions,
+        estimate: bool,
+    ) -> Result<Vec<PlanEntry>> {
+        plan(root, &self.options, walk_options, estimate)
+    }
+
+    /// Overrides the settings above for the files matched by the rules in
+    /// `config`.
+    pub fn config(mut self, config: Config) -> Self {
+        self.options.config = config;
+        self
+    }
+
+    /// Calls `callback` from the walking and worker threads as files are
+    /// found and done, at most every 100ms, and once more when the run
+    /// finishes.
+    pub fn on_progress(mut self, callback: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.options.on_progress = Some(ProgressCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Starts the worker threads.
+    pub fn build(self) -> Compressor {
+        let threads = match self.threads {
+            0 => available_parallelism().map(|v| v.get()).unwrap_or(1),
+            threads => threads,
+        };
+        Compressor::new(threads, self.options)
+    }
+}
 
 impl Compressor {
-    pub(crate) fn new(
-        threads: usize,
-        min_size: u64,
-        quality: Quality,
-        algorithms: Algorithms,
-        extensions: Option<HashSet<String>>,
-        verbose: bool,
-    ) -> Self {
+    /// Returns a builder with the default settings.
+    pub fn builder() -> CompressorBuilder {
+        CompressorBuilder::default()
+    }
+
+    pub(crate) fn new(threads: usize, mut options: CompressOptions) -> Self {
+        options.progress = Arc::new(Counters::new(
+            options.config.all_algorithms(options.algorithms),
+            options.on_progress.clone(),
+        ));
         let cap = max(threads * 2, 128);
-        let (tx, rx): (Sender<Unit>, Receiver<Unit>) = bounded(cap);
+        let (tx, rx): (channel::Sender<Unit>, Receiver<Unit>) = bounded(cap);
 
         let handles = (0..threads)
             .map(|_| {
                 let rx = rx.clone();
-                let algorithms: Vec<_> = algorithms.iter().collect();
-                spawn(move || Compressor::worker(rx, min_size, quality, algorithms, verbose))
+                let options = options.clone();
+                spawn(move || Compressor::worker(rx, options))
             })
             .collect();
 
         Compressor {
             tx,
             handles,
-            extensions,
-            verbose,
+            extensions: options.extensions,
+            settings: FileSettings {
+                algorithms: options.algorithms,
+                quality: options.quality,
+                min_size: options.min_size,
+                include: None,
+            },
+            variant_algorithms: options.config.all_algorithms(options.algorithms),
+            config: options.config,
+            output: options.output,
+            events: options.events,
+            progress: options.progress,
+            incremental: options.incremental && options.mode == Mode::Compress,
+            states: Mutex::default(),
         }
     }
 
-    pub(crate) fn precompress(&self, path: &Path, walk_options: &WalkOptions) -> Result<()> {
-        let walk = build_walk(path, walk_options)?;
+    /// Walks `root` and queues every file to be compressed or checked by the
+    /// worker threads. May be called for several roots before finishing.
+    pub fn precompress(&self, root: &Path, walk_options: &WalkOptions) -> Result<()> {
+        let nested_out_dir = self.output.nested_out_dir(root);
+        let state = self.incremental.then(|| {
+            let state = Arc::new(State::load(self.output.out_dir.as_deref().unwrap_or(root)));
+            self.states
+                .lock()
+                .expect("state lock poisoned")
+                .push(Arc::clone(&state));
+            state
+        });
+        let walk = build_walk(root, walk_options)?;
         for entry in walk {
             let entry = match entry {
                 Ok(entry) => entry,
                 Err(err) => {
-                    eprintln!("Warning: {err}");
+                    if let Some(events) = &self.events {
+                        _ = events.send(Event::Warning(err.to_string()));
+                    }
                     continue;
                 }
             };
             let path = entry.path();
-            if self.should_compress(path) && !path.is_symlink() && path.is_file() {
-                self.tx
-                    .send(path.to_path_buf())
-                    .expect("unable to send on channel");
+            if nested_out_dir
+                .as_ref()
+                .is_some_and(|out_dir| path.starts_with(out_dir))
+            {
+                continue;
+            }
+            let rel = path.strip_prefix(root).unwrap_or(path);
+            let settings = self.config.resolve(rel, self.settings);
+            let compress = self.should_compress(path, &settings)
+                && !self
+                    .output
+                    .is_sibling_variant(path, self.variant_algorithms);
+            if (compress || self.output.places_originals()) && !path.is_symlink() && path.is_file()
+            {
+                let rel = rel.to_path_buf();
+                let size = entry.metadata().map(|m| m.len()).unwrap_or_default();
+                self.progress.discovered(size);
+                let unit = Unit {
+                    path: path.to_path_buf(),
+                    rel,
+                    size,
+                    compress,
+                    settings,
+                    state: state.clone(),
+                };
+                self.tx.send(unit).expect("unable to send on channel");
             }
         }
+        self.progress.walk_done();
 
         Ok(())
     }
 
-    pub(crate) fn finish(mut self) -> Stats {
+    /// Returns how far the run has got, for callers that poll rather than
+    /// passing [`CompressorBuilder::on_progress`].
+    pub fn progress(&self) -> Progress {
+        self.progress.snapshot()
+    }
+
+    /// Waits for the queued files to be done, saves the incremental state and
+    /// returns the totals of the run.
+    pub fn finish(mut self) -> Stats {
         let handles = take(&mut self.handles);
+        let progress = Arc::clone(&self.progress);
+        let states = take(&mut *self.states.lock().expect("state lock poisoned"));
+        let events = self.events.clone();
         drop(self);
 
-        handles.into_iter().fold(Stats::default(), |stats, handle| {
+        let mut stats = handles.into_iter().fold(Stats::default(), |stats, handle| {
             stats + handle.join().expect("unable to join worker thread")
-        })
+        });
+        for state in states {
+            if let Err(err) = state.save() {
+                stats.num_errors += 1;
+                if let Some(events) = &events {
+                    _ = events.send(Event::Warning(format!(
+                        "unable to save incremental state: {err}"
+                    )));
+                }
+            }
+        }
+        progress.notify(true);
+        stats
     }
 
-    fn worker(
-        rx: Receiver<Unit>,
-        min_size: u64,
-        quality: Quality,
-        algorithms: Vec<Algorithm>,
-        verbose: bool,
-    ) -> Stats {
+    fn worker(rx: Receiver<Unit>, options: CompressOptions) -> Stats {
         let mut stats = Stats::default();
-        let mut ctx = Context::new(1 << 14, quality);
+        let mut ctx = Context::new(1 << 14, options.quality)
+            .with_zstd_dictionary(options.zstd_dictionary.clone());
+
+        while let Ok(unit) = rx.recv() {
+            let Unit {
+                path: pathbuf,
+                rel,
+                size,
+                compress,
+                settings,
+                state,
+            } = unit;
+            let mut report = FileReport {
+                path: pathbuf.display().to_string(),
+                size: 0,
+                variants: Vec::new(),
+                error: None,
+            };
+            if options.mode == Mode::Compress && options.output.places_originals() {
+                match Compressor::place_original(&options.output, &pathbuf, &rel) {
+                    Err(err) => {
+                        stats.num_errors += 1;
+                        report.error = Some(err.to_string());
+                    }
+                    Ok(placed) => stats.num_originals += placed as u64,
+                }
+            }
+            if !compress {
+                Compressor::file_done(&options, report, size, true);
+                continue;
+            }
 
-        while let Ok(pathbuf) = rx.recv() {
-            match Compressor::open_source_file(min_size, &pathbuf) {
+            ctx.set_quality(settings.quality);
+            let algorithms = settings.algorithms.iter().collect::<Vec<_>>();
+            match Compressor::open_source_file(settings.min_size, settings.algorithms, &pathbuf) {
                 Err(err) => {
-                    eprintln!("Warning: {}: {}", pathbuf.display(), err);
                     stats.num_errors += 1;
+                    report.error = Some(err.to_string());
+                    Compressor::file_done(&options, report, size, false);
+                }
+                Ok(None) => Compressor::file_done(&options, report, size, true),
+                Ok(Some(mut src)) if options.mode == Mode::Check => {
+                    report.size = src.size;
+                    for algorithm in &algorithms {
+                        let mut variant = VariantReport::failed(algorithm.to_string());
+                        let status = match options.output.variant_path(&pathbuf, &rel, *algorithm) {
+                            Ok(dst_path) => {
+                                variant.path = Some(dst_path.display().to_string());
+                                Compressor::variant_status(
+                                    &mut ctx,
+                                    &mut src,
+                                    Encoding::Algorithm(*algorithm),
+                                    &dst_path,
+                                    None,
+                                    false,
+                                )
+                            }
+                            Err(_) => VariantStatus::Missing,
+                        };
+                        variant.status = Compressor::check_outcome(status, &mut stats, || {
+                            Compressor::dropped_outcome(
+                                &mut ctx, &mut src, &pathbuf, &rel, *algorithm, &options,
+                            )
+                        });
+                        report.variants.push(variant);
+                    }
+                    report
+                        .variants
+                        .extend(Compressor::check_dictionary_variants(
+                            &mut ctx, &mut src, &pathbuf, &rel, &options, &mut stats,
+                        ));
+                    stats.num_source_files += 1;
+                    Compressor::file_done(&options, report, size, false);
                 }
-                Ok(None) => {}
-                Ok(Some((mut src, src_size))) => {
+                Ok(Some(mut src)) => {
+                    report.size = src.size;
+                    let record = state.as_ref().and_then(|state| state.get(&rel));
+                    let digest =
+                        settings_digest(settings.quality, &options.min_savings, options.keep_best);
+                    // The record of how variants were dropped only holds while
+                    // neither the source nor the settings have changed.
+                    let unchanged = record.filter(|record| {
+                        record.matches(src.size, src.modified) && record.settings == digest
+                    });
                     let mut compressed = false;
+                    let mut up_to_date = 0;
                     for algorithm in &algorithms {
                         let start = Instant::now();
-                        match Compressor::encode_file(&mut ctx, &mut src, *algorithm, &pathbuf) {
+                        let mut variant = VariantReport::failed(algorithm.to_string());
+                        let dst_path = match Compressor::prepare_variant_path(
+                            &options,
+                            &pathbuf,
+                            &rel,
+                            Encoding::Algorithm(*algorithm),
+                        ) {
+                            Ok(dst_path) => dst_path,
                             Err(err) => {
-                                eprintln!("Warning: {}: {}", pathbuf.display(), err);
                                 stats.num_errors += 1;
+                                variant.error = Some(err.to_string());
+                                report.variants.push(variant);
+                                continue;
                             }
-                            Ok(dst) => {
-                                let dur = start.elapsed();
-                                let saved = src_size as i64 - dst as i64;
-                                if verbose {
-                                    let sign = if saved < 0 { "-" } else { "" };
-                                    eprintln!(
-                                        "{}: {} ({}%, {}{})",
-                                        algorithm,
-                                        pathbuf.display(),
-                                        calc_savings(saved, dst),
-                                        sign,
-                                        format_bytes(saved.unsigned_abs()),
-                                    );
+                        };
+                        variant.path = Some(dst_path.display().to_string());
+                        if let Some(dropped) = unchanged.and_then(|record| record.dropped(&variant))
+                            && !dst_path.exists()
+                        {
+                            variant.status = dropped;
+                            up_to_date += 1;
+                            report.variants.push(variant);
+                            continue;
+                        }
+                        // Variants written with other settings are stale even
+                        // when they still decode to the source.
+                        if options.incremental
+                            && unchanged.is_some()
+                            && Compressor::variant_status(
+                                &mut ctx,
+                                &mut src,
+                                Encoding::Algorithm(*algorithm),
+                                &dst_path,
+                                unchanged,
+                                true,
+                            ) == VariantStatus::Current
+                        {
+                            variant.status = VariantOutcome::UpToDate;
+                            variant.size = fs::metadata(&dst_path).map(|m| m.len()).ok();
+                            if let Some(size) = variant.size
+                                && !options.min_savings.get(*algorithm).is_met(src.size, size)
+                            {
+                                match fs::remove_file(&dst_path) {
+                                    Ok(()) => {
+                                        stats.num_discarded += 1;
+                                        compressed = true;
+                                        variant.status = VariantOutcome::Discarded;
+                                        variant.size = None;
+                                    }
+                                    Err(err) => {
+                                        stats.num_errors += 1;
+                                        variant.error = Some(err.to_string());
+                                    }
                                 }
+                            } else {
+                                up_to_date += 1;
+                            }
+                            variant.time = start.elapsed();
+                            report.variants.push(variant);
+                            continue;
+                        }
+
+                        let start = Instant::now();
+                        let cache_hits = stats.num_cache_hits;
+                        match Compressor::write_variant(
+                            &mut ctx, &mut src, *algorithm, &dst_path, &options, &mut stats,
+                        ) {
+                            Err(err) => {
+                                stats.num_errors += 1;
+                                variant.error = Some(err.to_string());
+                            }
+                            Ok(None) => {
+                                stats.num_discarded += 1;
+                                compressed = true;
+                                variant.status = VariantOutcome::Discarded;
+                            }
+                            Ok(Some(dst)) => {
+                                let dur = start.elapsed();
+                                let saved = src.size as i64 - dst as i64;
                                 let s = match algorithm {
                                     Algorithm::Brotli => &mut stats.brotli,
                                     Algorithm::Deflate => &mut stats.deflate,
@@ -281,13 +1202,82 @@ impl Compressor {
                                 s.saved_bytes += saved;
                                 s.total_bytes += dst;
                                 compressed = true;
+                                variant.status = if stats.num_cache_hits > cache_hits {
+                                    VariantOutcome::Cached
+                                } else {
+                                    VariantOutcome::Written
+                                };
+                                variant.size = Some(dst);
+                                variant.saved_bytes = Some(saved);
+                                variant.savings = Some(calc_savings(saved, dst));
+                                options.progress.encoded(*algorithm, src.size, dst);
+                                if *algorithm == Algorithm::Zstd
+                                    && options.zstd_dictionary.is_some()
+                                {
+                                    match Compressor::plain_zstd_size(&mut ctx, &mut src) {
+                                        Ok(baseline) => {
+                                            stats.zstd_baseline_bytes += baseline;
+                                            variant.baseline_size = Some(baseline);
+                                        }
+                                        Err(err) => Compressor::warn(
+                                            &options,
+                                            format!("{}: {}", pathbuf.display(), err),
+                                        ),
+                                    }
+                                }
+                            }
+                        }
+                        variant.time = start.elapsed();
+                        report.variants.push(variant);
+                    }
+
+                    if let Some(keep_best) = options.keep_best {
+                        Compressor::prune_variants(
+                            &mut report.variants,
+                            &algorithms,
+                            keep_best,
+                            &mut stats,
+                        );
+                    }
+
+                    let mut expected = algorithms.len();
+                    for (variant, recorded) in Compressor::dictionary_variants(
+                        &mut ctx, &mut src, &pathbuf, &rel, unchanged, &options, &mut stats,
+                    ) {
+                        expected += 1;
+                        match variant.status {
+                            _ if recorded => up_to_date += 1,
+                            VariantOutcome::UpToDate => up_to_date += 1,
+                            VariantOutcome::Written => compressed = true,
+                            VariantOutcome::Discarded => {
+                                stats.num_discarded += 1;
+                                compressed = true;
                             }
+                            _ => {}
                         }
+                        report.variants.push(variant);
                     }
 
                     if compressed {
                         stats.num_source_files += 1;
+                    } else if up_to_date == expected {
+                        stats.num_up_to_date += 1;
                     }
+                    // A variant that failed to be rewritten may be stale, so
+                    // it must not be trusted on the next run.
+                    if let Some(state) = &state
+                        && report
+                            .variants
+                            .iter()
+                            .all(|variant| variant.error.is_none())
+                    {
+                        state.set(
+                            &rel,
+                            Record::new(src.size, src.modified)
+                                .with_dropped(digest, &report.variants),
+                        );
+                    }
+                    Compressor::file_done(&options, report, size, false);
                 }
             }
         }
@@ -295,64 +1285,799 @@ impl Compressor {
         stats
     }
 
-    fn open_source_file(min_size: u64, path: &Path) -> Result<Option<(File, u64)>> {
-        let src = File::open(path)?;
-        let src_size = src.metadata()?.len();
-        if src_size < min_size {
-            return Ok(None);
-        }
-        Ok(Some((src, src_size)))
-    }
-
-    fn encode_file(ctx: &mut Context, src: &mut File, alg: Algorithm, path: &Path) -> Result<u64> {
-        src.rewind()?;
-        let mut file_name = match path.file_name() {
-            None => {
-                return Err(
-                    io::Error::new(io::ErrorKind::InvalidInput, "path has no file name").into(),
-                );
+    /// Removes the variants in `variants`, one for each of `algorithms`, that
+    /// do not beat the variant of the baseline algorithm. Nothing is removed
+    /// when the file has no baseline variant.
+    fn prune_variants(
+        variants: &mut [VariantReport],
+        algorithms: &[Algorithm],
+        keep_best: KeepBest,
+        stats: &mut Stats,
+    ) {
+        let kept = |variant: &VariantReport| match variant.status {
+            VariantOutcome::Written | VariantOutcome::Cached | VariantOutcome::UpToDate => {
+                variant.size
             }
-            Some(name) => name.to_os_string(),
+            _ => None,
+        };
+        let Some(baseline_size) = algorithms
+            .iter()
+            .position(|alg| *alg == keep_best.baseline)
+            .and_then(|i| kept(&variants[i]))
+        else {
+            return;
         };
-        file_name.push(alg.extension());
-        let dst_path = path.with_file_name(file_name);
-
-        let dst_size = write_atomic(&dst_path, |dst| match alg {
-            Algorithm::Brotli => ctx.write_brotli(src, dst),
-            Algorithm::Deflate => ctx.write_deflate(src, dst),
-            Algorithm::Gzip => ctx.write_gzip(src, dst),
-            Algorithm::Zstd => ctx.write_zstd(src, dst),
-        })?;
-        Ok(dst_size)
-    }
 
-    fn should_compress(&self, path: &Path) -> bool {
-        if let Some(ext) = path.extension()
-            && let Some(ext) = ext.to_str()
-        {
-            return if let Some(exts) = &self.extensions {
-                exts.contains(ext)
-            } else {
-                EXTENSIONS.contains(ext)
+        for (alg, variant) in algorithms.iter().zip(variants.iter_mut()) {
+            if *alg == keep_best.baseline {
+                continue;
+            }
+            let Some(size) = kept(variant) else {
+                continue;
+            };
+            if keep_best.beats(size, baseline_size) {
+                continue;
+            }
+            let Some(path) = &variant.path else {
+                continue;
             };
+            match remove_if_exists(Path::new(path)) {
+                Ok(()) => {
+                    stats.num_pruned += 1;
+                    variant.status = VariantOutcome::Pruned;
+                    variant.size = None;
+                    variant.saved_bytes = None;
+                    variant.savings = None;
+                }
+                Err(err) => {
+                    stats.num_errors += 1;
+                    variant.error = Some(err.to_string());
+                }
+            }
+        }
+    }
+
+    /// When checking, returns how a missing `alg` variant of `src` would have
+    /// been dropped by a run, if it would.
+    fn dropped_outcome(
+        ctx: &mut Context,
+        src: &mut Source,
+        path: &Path,
+        rel: &Path,
+        alg: Algorithm,
+        options: &CompressOptions,
+    ) -> Option<VariantOutcome> {
+        let min_savings = options.min_savings.get(alg);
+        let keep_best = options
+            .keep_best
+            .filter(|keep_best| keep_best.baseline != alg)
+            .filter(|keep_best| src.algorithms.is_enabled(keep_best.baseline));
+        if min_savings == Threshold::default() && keep_best.is_none() {
+            return None;
+        }
+
+        let size = Compressor::encoded_size(ctx, src, alg).ok()?;
+        if !min_savings.is_met(src.size, size) {
+            return Some(VariantOutcome::Discarded);
+        }
+        let keep_best = keep_best?;
+        let baseline_size =
+            Compressor::baseline_size(ctx, src, path, rel, keep_best.baseline, options).ok()?;
+        let baseline_kept = options
+            .min_savings
+            .get(keep_best.baseline)
+            .is_met(src.size, baseline_size);
+        (baseline_kept && !keep_best.beats(size, baseline_size)).then_some(VariantOutcome::Pruned)
+    }
+
+    /// Counts the status of a checked variant and returns its outcome. A
+    /// missing variant is expected if `dropped` reports that a run would
+    /// discard or prune it.
+    fn check_outcome(
+        status: VariantStatus,
+        stats: &mut Stats,
+        dropped: impl FnOnce() -> Option<VariantOutcome>,
+    ) -> VariantOutcome {
+        match status {
+            VariantStatus::Current => VariantOutcome::UpToDate,
+            VariantStatus::Missing => dropped().unwrap_or_else(|| {
+                stats.num_missing += 1;
+                VariantOutcome::Missing
+            }),
+            VariantStatus::Stale => {
+                stats.num_stale += 1;
+                VariantOutcome::Stale
+            }
+            VariantStatus::Corrupt => {
+                stats.num_corrupt += 1;
+                VariantOutcome::Corrupt
+            }
         }
-        false
+    }
+
+    /// Checks the dictionary-compressed variants of `src`, returning nothing
+    /// when dictionaries are disabled or the file has none.
+    fn check_dictionary_variants(
+        ctx: &mut Context,
+        src: &mut Source,
+        path: &Path,
+        rel: &Path,
+        options: &CompressOptions,
+        stats: &mut Stats,
+    ) -> Vec<VariantReport> {
+        let Some(dictionaries) = &options.dictionaries else {
+            return Vec::new();
+        };
+        let dictionary = match dictionaries.get(rel) {
+            Ok(Some(dictionary)) => Ok(dictionary),
+            Ok(None) => return Vec::new(),
+            Err(err) => Err(err),
+        };
+
+        let mut variants = Vec::new();
+        for format in &options.dictionary_formats {
+            let mut variant = VariantReport::failed(format.to_string());
+            let dictionary = match &dictionary {
+                Ok(dictionary) => dictionary,
+                Err(err) => {
+                    stats.num_errors += 1;
+                    variant.error = Some(format!("unable to read dictionary: {err}"));
+                    variants.push(variant);
+                    continue;
+                }
+            };
+            variant.dictionary_hash = Some(dictionary.structured_hash());
+            let encoding = Encoding::Dictionary(*format, dictionary);
+            let status = match encoding.variant_path(&options.output, path, rel) {
+                Ok(dst_path) => {
+                    variant.path = Some(dst_path.display().to_string());
+                    Compressor::variant_status(ctx, src, encoding, &dst_path, None, false)
+                }
+                Err(_) => VariantStatus::Missing,
+            };
+            let min_savings = options.min_savings.get(format.baseline());
+            variant.status = Compressor::check_outcome(status, stats, || {
+                if min_savings == Threshold::default() {
+                    return None;
+                }
+                src.file.rewind().ok()?;
+                let mut counter = ByteCounter(0);
+                ctx.write_dictionary(*format, dictionary, &mut src.file, &mut counter)
+                    .ok()?;
+                (!min_savings.is_met(src.size, counter.0)).then_some(VariantOutcome::Discarded)
+            });
+            variants.push(variant);
+        }
+        variants
+    }
+
+    /// Writes the dictionary-compressed variants of `src`, returning nothing
+    /// when dictionaries are disabled or the file has none. Each variant is
+    /// paired with whether it was left dropped as `unchanged` records.
+    #[allow(clippy::too_many_arguments)]
+    fn dictionary_variants(
+        ctx: &mut Context,
+        src: &mut Source,
+        path: &Path,
+        rel: &Path,
+        unchanged: Option<&Record>,
+        options: &CompressOptions,
+        stats: &mut Stats,
+    ) -> Vec<(VariantReport, bool)> {
+        let Some(dictionaries) = &options.dictionaries else {
+            return Vec::new();
+        };
+        let dictionary = match dictionaries.get(rel) {
+            Ok(Some(dictionary)) => Ok(dictionary),
+            Ok(None) => return Vec::new(),
+            Err(err) => Err(err),
+        };
+
+        let mut variants = Vec::new();
+        for format in &options.dictionary_formats {
+            if let Ok(dictionary) = &dictionary
+                && let Some(variant) =
+                    Compressor::recorded_drop(path, rel, unchanged, *format, dictionary, options)
+            {
+                variants.push((variant, true));
+                continue;
+            }
+            let start = Instant::now();
+            let result = match &dictionary {
+                Ok(dictionary) => Compressor::write_dictionary_variant(
+                    ctx, src, path, rel, unchanged, *format, dictionary, options,
+                ),
+                Err(err) => Err(anyhow!("unable to read dictionary: {err}")),
+            };
+            let mut variant = match result {
+                Ok(variant) => variant,
+                Err(err) => {
+                    stats.num_errors += 1;
+                    let mut variant = VariantReport::failed(format.to_string());
+                    variant.error = Some(err.to_string());
+                    variant
+                }
+            };
+            variant.time = start.elapsed();
+
+            if let (VariantOutcome::Written, Some(size), Some(baseline)) =
+                (variant.status, variant.size, variant.baseline_size)
+            {
+                let saved = src.size as i64 - size as i64;
+                let s = match format {
+                    DictionaryFormat::Dcz => &mut stats.dcz,
+                    DictionaryFormat::Dcb => &mut stats.dcb,
+                };
+                s.total_time += variant.time;
+                s.total_bytes += size;
+                s.saved_bytes += saved;
+                s.baseline_bytes += baseline;
+            }
+            variants.push((variant, false));
+        }
+        variants
+    }
+
+    /// Returns the report of a dictionary-compressed variant that `unchanged`
+    /// records as dropped, if it is still missing.
+    fn recorded_drop(
+        path: &Path,
+        rel: &Path,
+        unchanged: Option<&Record>,
+        format: DictionaryFormat,
+        dictionary: &Dictionary,
+        options: &CompressOptions,
+    ) -> Option<VariantReport> {
+        let unchanged = unchanged?;
+        let dst_path = Encoding::Dictionary(format, dictionary)
+            .variant_path(&options.output, path, rel)
+            .ok()?;
+        let mut variant = VariantReport::failed(format.to_string());
+        variant.path = Some(dst_path.display().to_string());
+        variant.dictionary_hash = Some(dictionary.structured_hash());
+        variant.status = unchanged.dropped(&variant)?;
+        (!dst_path.exists()).then_some(variant)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn write_dictionary_variant(
+        ctx: &mut Context,
+        src: &mut Source,
+        path: &Path,
+        rel: &Path,
+        unchanged: Option<&Record>,
+        format: DictionaryFormat,
+        dictionary: &Dictionary,
+        options: &CompressOptions,
+    ) -> Result<VariantReport> {
+        let encoding = Encoding::Dictionary(format, dictionary);
+        let dst_path = Compressor::prepare_variant_path(options, path, rel, encoding)?;
+        let mut variant = VariantReport::failed(format.to_string());
+        variant.path = Some(dst_path.display().to_string());
+        variant.dictionary_hash = Some(dictionary.structured_hash());
+        let min_savings = options.min_savings.get(format.baseline());
+        if options.incremental
+            && unchanged.is_some()
+            && Compressor::variant_status(ctx, src, encoding, &dst_path, unchanged, true)
+                == VariantStatus::Current
+        {
+            let size = fs::metadata(&dst_path)?.len();
+            if !min_savings.is_met(src.size, size) {
+                fs::remove_file(&dst_path)?;
+                variant.status = VariantOutcome::Discarded;
+                return Ok(variant);
+            }
+            variant.status = VariantOutcome::UpToDate;
+            variant.size = Some(size);
+            return Ok(variant);
+        }
+
+        let Some(size) =
+            Compressor::encode_file(ctx, src, encoding, &dst_path, options.verify, min_savings)?
+        else {
+            variant.status = VariantOutcome::Discarded;
+            return Ok(variant);
+        };
+        let saved = src.size as i64 - size as i64;
+        variant.status = VariantOutcome::Written;
+        variant.size = Some(size);
+        variant.saved_bytes = Some(saved);
+        variant.savings = Some(calc_savings(saved, size));
+        variant.baseline_size = Some(Compressor::baseline_size(
+            ctx,
+            src,
+            path,
+            rel,
+            format.baseline(),
+            options,
+        )?);
+        Ok(variant)
+    }
+
+    /// Returns the size of the `alg` variant of `src`, reading it from the
+    /// output when it is being written in this run and encoding it otherwise.
+    fn baseline_size(
+        ctx: &mut Context,
+        src: &mut Source,
+        path: &Path,
+        rel: &Path,
+        alg: Algorithm,
+        options: &CompressOptions,
+    ) -> Result<u64> {
+        if src.algorithms.is_enabled(alg)
+            && let Ok(dst_path) = options.output.variant_path(path, rel, alg)
+            && let Ok(metadata) = fs::metadata(dst_path)
+        {
+            return Ok(metadata.len());
+        }
+        Ok(Compressor::encoded_size(ctx, src, alg)?)
+    }
+
+    fn encoded_size(ctx: &mut Context, src: &mut Source, alg: Algorithm) -> io::Result<u64> {
+        src.file.rewind()?;
+        let mut counter = ByteCounter(0);
+        ctx.write(alg, &mut src.file, &mut counter)?;
+        Ok(counter.0)
+    }
+
+    /// Returns the size of the zstd variant of `src` without the zstd
+    /// dictionary, to show how much the dictionary saves.
+    fn plain_zstd_size(ctx: &mut Context, src: &mut Source) -> io::Result<u64> {
+        src.file.rewind()?;
+        let mut counter = ByteCounter(0);
+        ctx.write_plain_zstd(&mut src.file, &mut counter)?;
+        Ok(counter.0)
+    }
+
+    /// Counts a source file of `size` bytes as done and sends its results to
+    /// the caller, if it asked for events. Files that only had their original
+    /// placed are reported when placing it failed.
+    fn file_done(options: &CompressOptions, report: FileReport, size: u64, only_original: bool) {
+        options.progress.file_done(size);
+        if let Some(tx) = &options.events
+            && (!only_original || report.error.is_some())
+        {
+            _ = tx.send(Event::File(report));
+        }
+    }
+
+    /// Sends a warning that is not tied to a variant's result to the caller,
+    /// if it asked for events.
+    fn warn(options: &CompressOptions, warning: String) {
+        if let Some(tx) = &options.events {
+            _ = tx.send(Event::Warning(warning));
+        }
+    }
+
+    fn open_source_file(
+        min_size: u64,
+        algorithms: Algorithms,
+        path: &Path,
+    ) -> Result<Option<Source>> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if metadata.len() < min_size {
+            return Ok(None);
+        }
+        Ok(Some(Source {
+            file,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            digest: None,
+            algorithms,
+        }))
+    }
+
+    /// Returns where the `encoding` variant of `path` is written, creating
+    /// its directory. A template that does not set variant names apart from
+    /// source names may render the name of an existing source file, which is
+    /// refused rather than overwritten.
+    fn prepare_variant_path(
+        options: &CompressOptions,
+        path: &Path,
+        rel: &Path,
+        encoding: Encoding<'_>,
+    ) -> io::Result<PathBuf> {
+        let output = &options.output;
+        let dst_path = encoding.variant_path(output, path, rel)?;
+        if let Encoding::Algorithm(alg) = encoding
+            && output.out_dir.is_none()
+            && !output.naming.get(alg).is_distinct()
+            && dst_path.is_file()
+            && let Some(name) = dst_path.file_name()
+            && options.is_source(&dst_path, &rel.with_file_name(name))
+        {
+            return Err(io::Error::other(format!(
+                "refusing to overwrite source file {} with the {alg} variant of {}",
+                dst_path.display(),
+                path.display()
+            )));
+        }
+        if output.out_dir.is_some()
+            && let Some(parent) = dst_path.parent()
+        {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(dst_path)
+    }
+
+    /// Places the original of `path` into the output tree, returning whether
+    /// anything was written.
+    fn place_original(output: &Output, path: &Path, rel: &Path) -> io::Result<bool> {
+        let dst_path = output.original_path(path, rel);
+        if let Some(parent) = dst_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match output.originals {
+            Originals::None => Ok(false),
+            Originals::Copy => {
+                let mut src = File::open(path)?;
+                let metadata = src.metadata()?;
+                let modified = metadata.modified()?;
+                if let Ok(existing) = fs::metadata(&dst_path)
+                    && existing.is_file()
+                    && existing.len() == metadata.len()
+                    && existing.modified().ok() == Some(modified)
+                {
+                    return Ok(false);
+                }
+                write_atomic(&dst_path, |dst| {
+                    io::copy(&mut src, dst)?;
+                    dst.set_modified(modified)
+                })?;
+                Ok(true)
+            }
+            Originals::Hardlink => {
+                let tmp_path = tmp_output_path(&dst_path);
+                _ = fs::remove_file(&tmp_path);
+                fs::hard_link(path, &tmp_path)?;
+                if let Err(err) = fs::rename(&tmp_path, &dst_path) {
+                    _ = fs::remove_file(&tmp_path);
+                    return Err(err);
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    /// Reports whether the variant at `dst_path` is up to date with `src`.
+    ///
+    /// Variants are stamped with the modification time of their source when
+    /// written, so a matching mtime is trusted when `record` shows that the
+    /// source still has the size and mtime it had when the variant was last
+    /// brought up to date. Otherwise the variant is decoded and compared
+    /// against the source, and restamped if it matches and `restamp` is set.
+    fn variant_status(
+        ctx: &mut Context,
+        src: &mut Source,
+        encoding: Encoding<'_>,
+        dst_path: &Path,
+        record: Option<&Record>,
+        restamp: bool,
+    ) -> VariantStatus {
+        let mut dst = match File::options().read(true).write(restamp).open(dst_path) {
+            Ok(dst) => dst,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return VariantStatus::Missing,
+            Err(_) => return VariantStatus::Corrupt,
+        };
+        match dst.metadata() {
+            Ok(metadata) if metadata.is_file() => {
+                if record.is_some_and(|record| record.matches(src.size, src.modified))
+                    && metadata.modified().ok() == Some(src.modified)
+                    && encoding.header_matches(ctx, &mut dst)
+                {
+                    return VariantStatus::Current;
+                }
+            }
+            _ => return VariantStatus::Missing,
+        }
+
+        if src.file.rewind().is_err() {
+            return VariantStatus::Stale;
+        }
+        match encoding.decodes_to(ctx, &mut dst, &mut src.file) {
+            Ok(true) if !restamp || dst.set_modified(src.modified).is_ok() => {
+                VariantStatus::Current
+            }
+            Ok(_) => VariantStatus::Stale,
+            Err(_) => VariantStatus::Corrupt,
+        }
+    }
+
+    /// Encodes `src` into `dst_path`, returning `None` when the variant does
+    /// not meet `min_savings` and was discarded.
+    fn encode_file(
+        ctx: &mut Context,
+        src: &mut Source,
+        encoding: Encoding<'_>,
+        dst_path: &Path,
+        verify: bool,
+        min_savings: Threshold,
+    ) -> Result<Option<u64>> {
+        src.file.rewind()?;
+        let src_size = src.size;
+        let dst_size = write_atomic_if(
+            dst_path,
+            |dst| {
+                encoding.write(ctx, &mut src.file, dst)?;
+                dst.set_modified(src.modified)?;
+                if verify {
+                    verify_variant(ctx, encoding, dst, &mut src.file)?;
+                }
+                Ok(())
+            },
+            |dst_size| min_savings.is_met(src_size, dst_size),
+        )?;
+        Ok(dst_size)
+    }
+
+    /// Writes the `alg` variant of `src`, copying it from the cache when
+    /// possible and storing newly encoded variants there otherwise. Returns
+    /// `None` when the variant was discarded for not saving enough.
+    fn write_variant(
+        ctx: &mut Context,
+        src: &mut Source,
+        alg: Algorithm,
+        dst_path: &Path,
+        options: &CompressOptions,
+        stats: &mut Stats,
+    ) -> Result<Option<u64>> {
+        let min_savings = options.min_savings.get(alg);
+        let Some(cache) = &options.cache else {
+            return Compressor::encode_file(
+                ctx,
+                src,
+                Encoding::Algorithm(alg),
+                dst_path,
+                options.verify,
+                min_savings,
+            );
+        };
+
+        let key = Cache::key(
+            &src.digest()?,
+            alg,
+            ctx.quality(),
+            options.zstd_dictionary.as_deref(),
+        );
+        if let Some(mut entry) = cache.get(&key) {
+            let src_size = src.size;
+            let dst_size = write_atomic_if(
+                dst_path,
+                |dst| {
+                    io::copy(&mut entry, dst)?;
+                    dst.set_modified(src.modified)?;
+                    if options.verify {
+                        verify_variant(ctx, Encoding::Algorithm(alg), dst, &mut src.file)?;
+                    }
+                    Ok(())
+                },
+                |dst_size| min_savings.is_met(src_size, dst_size),
+            )?;
+            stats.num_cache_hits += 1;
+            return Ok(dst_size);
+        }
+
+        let dst_size = Compressor::encode_file(
+            ctx,
+            src,
+            Encoding::Algorithm(alg),
+            dst_path,
+            options.verify,
+            min_savings,
+        )?;
+        if dst_size.is_some()
+            && let Err(err) = cache.insert(&key, dst_path)
+        {
+            Compressor::warn(
+                options,
+                format!("{}: unable to update cache: {}", dst_path.display(), err),
+            );
+        }
+        Ok(dst_size)
+    }
+
+    fn should_compress(&self, path: &Path, settings: &FileSettings) -> bool {
+        settings
+            .include
+            .unwrap_or_else(|| is_compressible(path, self.extensions.as_ref()))
     }
 }
 
-fn write_atomic(
+/// A writer that discards its input, counting the bytes written.
+struct ByteCounter(u64);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reports whether `path` has one of `extensions`, or one of the default
+/// extensions when unset.
+pub(crate) fn is_compressible(path: &Path, extensions: Option<&HashSet<String>>) -> bool {
+    if let Some(ext) = path.extension()
+        && let Some(ext) = ext.to_str()
+    {
+        return if let Some(exts) = extensions {
+            exts.contains(ext)
+        } else {
+            EXTENSIONS.contains(ext)
+        };
+    }
+    false
+}
+
+/// Returns `extensions` sorted, or the default extensions when unset.
+pub(crate) fn compressible_extensions(extensions: Option<&HashSet<String>>) -> Vec<&str> {
+    let mut extensions: Vec<&str> = match extensions {
+        Some(exts) => exts.iter().map(String::as_str).collect(),
+        None => EXTENSIONS.iter().copied().collect(),
+    };
+    extensions.sort_unstable();
+    extensions
+}
+
+/// Reports whether the `alg` variant at `dst_path` is up to date with the
+/// source at `src_path`, without modifying either file. `record` is the
+/// incremental state of the source, if any.
+pub(crate) fn is_current(
+    ctx: &mut Context,
+    src_path: &Path,
+    record: Option<&Record>,
+    alg: Algorithm,
+    dst_path: &Path,
+) -> bool {
+    match Compressor::open_source_file(0, Algorithms::empty(), src_path) {
+        Ok(Some(mut src)) => {
+            Compressor::variant_status(
+                ctx,
+                &mut src,
+                Encoding::Algorithm(alg),
+                dst_path,
+                record,
+                false,
+            ) == VariantStatus::Current
+        }
+        _ => false,
+    }
+}
+
+/// Reports whether an incremental run would leave every variant of the
+/// source at `path` as it is. Dictionary-compressed variants are not
+/// checked, so a source is never up to date while dictionaries are in use.
+pub(crate) fn is_up_to_date(
+    ctx: &mut Context,
+    path: &Path,
+    rel: &Path,
+    settings: &FileSettings,
+    state: &State,
+    options: &CompressOptions,
+) -> bool {
+    if options.dictionaries.is_some() {
+        return false;
+    }
+    let Ok(Some(mut src)) = Compressor::open_source_file(0, settings.algorithms, path) else {
+        return false;
+    };
+    let record = state.get(rel);
+    let digest = settings_digest(settings.quality, &options.min_savings, options.keep_best);
+    let Some(unchanged) =
+        record.filter(|record| record.matches(src.size, src.modified) && record.settings == digest)
+    else {
+        // Variants of a changed source or written with other settings are
+        // rewritten whether or not they still decode to the source.
+        return false;
+    };
+    let mut sizes = Vec::new();
+    for alg in settings.algorithms.iter() {
+        let Ok(dst_path) = options.output.variant_path(path, rel, alg) else {
+            return false;
+        };
+        let variant = VariantReport::failed(alg.to_string());
+        if unchanged.dropped(&variant).is_some() && !dst_path.exists() {
+            continue;
+        }
+        let status = Compressor::variant_status(
+            ctx,
+            &mut src,
+            Encoding::Algorithm(alg),
+            &dst_path,
+            Some(unchanged),
+            false,
+        );
+        // A current variant that does not save enough is removed.
+        match fs::metadata(&dst_path) {
+            Ok(metadata)
+                if status == VariantStatus::Current
+                    && options
+                        .min_savings
+                        .get(alg)
+                        .is_met(src.size, metadata.len()) =>
+            {
+                sizes.push((alg, metadata.len()));
+            }
+            _ => return false,
+        }
+    }
+    // Nor is a variant that does not beat the keep-best baseline left.
+    let Some(keep_best) = options.keep_best else {
+        return true;
+    };
+    let Some(&(_, baseline_size)) = sizes.iter().find(|(alg, _)| *alg == keep_best.baseline) else {
+        return true;
+    };
+    sizes
+        .iter()
+        .all(|(alg, size)| *alg == keep_best.baseline || keep_best.beats(*size, baseline_size))
+}
+
+/// Syncs the variant being written to `dst` and checks that it decodes to
+/// exactly the contents of `src`.
+fn verify_variant(
+    ctx: &mut Context,
+    encoding: Encoding<'_>,
+    dst: &mut File,
+    src: &mut File,
+) -> io::Result<()> {
+    dst.sync_all()?;
+    dst.rewind()?;
+    src.rewind()?;
+    let matches = encoding.decodes_to(ctx, dst, src).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{encoding} output failed verification: {err}"),
+        )
+    })?;
+    if !matches {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{encoding} output does not match source"),
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn write_atomic(
     dst_path: &Path,
     write: impl FnOnce(&mut File) -> io::Result<()>,
 ) -> io::Result<u64> {
+    write_atomic_if(dst_path, write, |_| true).map(|dst_size| dst_size.unwrap_or_default())
+}
+
+/// Writes `dst_path` like `write_atomic`, unless `keep` rejects the size of
+/// the output. Rejected output is discarded before the rename, and any
+/// existing file at `dst_path` is removed, returning `None`.
+fn write_atomic_if(
+    dst_path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+    keep: impl FnOnce(u64) -> bool,
+) -> io::Result<Option<u64>> {
     let tmp_path = tmp_output_path(dst_path);
     let result = (|| {
-        let mut dst = File::create(&tmp_path)?;
+        let mut dst = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
         write(&mut dst)?;
-        dst.sync_all()?;
         let dst_size = dst.metadata()?.len();
+        if !keep(dst_size) {
+            drop(dst);
+            fs::remove_file(&tmp_path)?;
+            remove_if_exists(dst_path)?;
+            return Ok(None);
+        }
+        dst.sync_all()?;
         drop(dst);
         fs::rename(&tmp_path, dst_path)?;
-        Ok(dst_size)
+        Ok(Some(dst_size))
     })();
 
     if result.is_err() {
@@ -362,13 +2087,31 @@ fn write_atomic(
     result
 }
 
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
 fn tmp_output_path(dst_path: &Path) -> PathBuf {
     let mut file_name = dst_path.file_name().unwrap_or_default().to_os_string();
     file_name.push(".tmp");
     dst_path.with_file_name(file_name)
 }
 
-fn build_walk(path: &Path, walk_options: &WalkOptions) -> Result<ignore::Walk> {
+/// Returns the output path a temporary file was written for, if `path` was
+/// produced by `tmp_output_path`.
+pub(crate) fn tmp_target_path(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?.strip_suffix(".tmp")?;
+    (!file_name.is_empty()).then(|| path.with_file_name(file_name))
+}
+
+pub(crate) fn build_walk(path: &Path, walk_options: &WalkOptions) -> Result<ignore::Walk> {
+    Ok(walk_builder(path, walk_options)?.build())
+}
+
+pub(crate) fn walk_builder(path: &Path, walk_options: &WalkOptions) -> Result<ignore::WalkBuilder> {
     let mut builder = ignore::WalkBuilder::new(path);
     builder.follow_links(false);
     builder.require_git(false);
@@ -382,15 +2125,24 @@ fn build_walk(path: &Path, walk_options: &WalkOptions) -> Result<ignore::Walk> {
             .git_ignore(false);
     }
 
-    if !walk_options.exclude.is_empty() {
-        let mut overrides = OverrideBuilder::new(path);
-        for pattern in &walk_options.exclude {
-            overrides.add(&format!("!{pattern}"))?;
-        }
-        builder.overrides(overrides.build()?);
+    if let Some(overrides) = build_excludes(path, walk_options)? {
+        builder.overrides(overrides);
     }
 
-    Ok(builder.build())
+    Ok(builder)
+}
+
+/// Builds the matcher for `--exclude` patterns, where excluded paths match
+/// as ignored.
+pub(crate) fn build_excludes(path: &Path, walk_options: &WalkOptions) -> Result<Option<Override>> {
+    if walk_options.exclude.is_empty() {
+        return Ok(None);
+    }
+    let mut overrides = OverrideBuilder::new(path);
+    for pattern in &walk_options.exclude {
+        overrides.add(&format!("!{pattern}"))?;
+    }
+    Ok(Some(overrides.build()?))
 }
 
 static EXTENSIONS: phf::Set<&'static str> = phf::phf_set! {
@@ -443,15 +2195,42 @@ mod tests {
         collections::HashSet,
         fs,
         io::{self, Write},
-        path::{Path, PathBuf},
-        time::{SystemTime, UNIX_EPOCH},
+        num::NonZeroU64,
+        path::Path,
+        sync::{Arc, mpsc::channel},
+        time::{Duration, SystemTime},
     };
 
     use anyhow::Result;
 
-    use crate::encode::Quality;
+    use crate::cache::Cache;
+    use crate::config::{Config, FileSettings};
+    use crate::dictionary::{Dictionaries, Dictionary, DictionaryFormat};
+    use crate::encode::{Context, Quality};
+    use crate::naming::Naming;
+    use crate::output::{Layout, Originals, Output};
+    use crate::report::{Event, VariantOutcome};
+    use crate::state::STATE_FILE_NAME;
+    use crate::test_util::test_dir;
+    use crate::train::train;
+
+    use super::{
+        Algorithm, Algorithms, CompressOptions, Compressor, Encoding, KeepBest, Mode, Stats,
+        Threshold, WalkOptions, build_walk, closest_name, edit_distance, tmp_output_path,
+        verify_variant, write_atomic,
+    };
 
-    use super::{Algorithms, Compressor, WalkOptions, build_walk, tmp_output_path, write_atomic};
+    #[test]
+    fn algorithm_parses_names_and_suggests_close_ones() {
+        for (name, alg) in Algorithm::NAMES {
+            assert_eq!(name.parse::<Algorithm>(), Ok(alg));
+        }
+        assert_eq!(closest_name("ZSTD"), Some("zstd"));
+        assert_eq!(closest_name("defalte"), Some("deflate"));
+        assert_eq!(closest_name("b"), Some("br"));
+        assert_eq!(closest_name("lzma"), None);
+        assert_eq!(edit_distance("gzip", "gz"), 2);
+    }
 
     #[test]
     fn walk_respects_ignore_files_by_default() -> Result<()> {
@@ -540,7 +2319,14 @@ mod tests {
             gzip: true,
             zstd: false,
         };
-        let compressor = Compressor::new(1, 1, Quality::default(), algorithms, None, false);
+        let compressor = Compressor::new(
+            1,
+            CompressOptions {
+                min_size: 1,
+                algorithms,
+                ..CompressOptions::default()
+            },
+        );
         compressor.precompress(&root, &WalkOptions::default())?;
         let stats = compressor.finish();
 
@@ -558,21 +2344,106 @@ mod tests {
 
     #[test]
     fn compressor_uses_default_and_custom_extension_filters() {
-        let default = Compressor::new(1, 1, Quality::default(), Algorithms::default(), None, false);
+        let default = Compressor::new(1, CompressOptions::default());
         let custom = Compressor::new(
             1,
-            1,
-            Quality::default(),
-            Algorithms::default(),
-            Some(HashSet::from([String::from("bin")])),
-            false,
+            CompressOptions {
+                extensions: Some(HashSet::from([String::from("bin")])),
+                ..CompressOptions::default()
+            },
         );
 
-        assert!(default.should_compress(Path::new("asset.js")));
-        assert!(!default.should_compress(Path::new("asset.bin")));
-        assert!(!default.should_compress(Path::new("LICENSE")));
-        assert!(custom.should_compress(Path::new("asset.bin")));
-        assert!(!custom.should_compress(Path::new("asset.js")));
+        let settings = default.settings;
+        assert!(default.should_compress(Path::new("asset.js"), &settings));
+        assert!(!default.should_compress(Path::new("asset.bin"), &settings));
+        assert!(!default.should_compress(Path::new("LICENSE"), &settings));
+        assert!(custom.should_compress(Path::new("asset.bin"), &settings));
+        assert!(!custom.should_compress(Path::new("asset.js"), &settings));
+
+        let included = FileSettings {
+            include: Some(true),
+            ..settings
+        };
+        assert!(default.should_compress(Path::new("LICENSE"), &included));
+    }
+
+    #[test]
+    fn compressor_applies_config_rules_per_file() -> Result<()> {
+        let root = test_dir("config");
+        fs::create_dir_all(root.join("data/private"))?;
+        let payload = "const x = 1;\n".repeat(256);
+        for name in [
+            "app.js",
+            "app.wasm",
+            "data/items.json",
+            "data/private/keys.json",
+        ] {
+            fs::write(root.join(name), &payload)?;
+        }
+        fs::write(root.join("index.html"), "<p>Hello, world!</p>\n".repeat(20))?;
+        let config = Config::parse(
+            r#"
+            [[rule]]
+            glob = "*.wasm"
+            algorithms = ["zstd"]
+            quality = { zstd = 22 }
+
+            [[rule]]
+            glob = "data/**"
+            algorithms = ["gzip"]
+
+            [[rule]]
+            glob = "data/private/**"
+            include = false
+
+            [[rule]]
+            glob = "*.html"
+            min_size = 256
+            "#,
+        )?;
+        let options = CompressOptions {
+            algorithms: gzip_only(),
+            quality: Quality::default(),
+            config,
+            ..CompressOptions::default()
+        };
+        let compressor = Compressor::new(1, options);
+        compressor.precompress(&root, &WalkOptions::default())?;
+        // Progress covers the algorithms that only rules enable.
+        let progress = compressor.progress();
+        let stats = compressor.finish();
+        assert_eq!(
+            progress
+                .algorithms
+                .iter()
+                .map(|alg| alg.algorithm)
+                .collect::<Vec<_>>(),
+            [Algorithm::Gzip, Algorithm::Zstd]
+        );
+        assert_eq!(stats.num_source_files, 4);
+
+        let variants = |name: &str| {
+            ["br", "gz", "zst"]
+                .into_iter()
+                .filter(|ext| root.join(format!("{name}.{ext}")).exists())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(variants("app.js"), vec!["gz"]);
+        assert_eq!(variants("app.wasm"), vec!["zst"]);
+        assert_eq!(variants("data/items.json"), vec!["gz"]);
+        assert_eq!(variants("data/private/keys.json"), Vec::<&str>::new());
+        assert_eq!(variants("index.html"), vec!["gz"]);
+
+        let mut expected = Vec::new();
+        let mut ctx = Context::new(1 << 14, Quality::default());
+        let mut quality = Quality::default();
+        assert!(quality.set(Algorithm::Zstd, 22));
+        ctx.set_quality(quality);
+        ctx.write(Algorithm::Zstd, &mut payload.as_bytes(), &mut expected)?;
+        assert_eq!(fs::read(root.join("app.wasm.zst"))?, expected);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
     }
 
     #[test]
@@ -583,16 +2454,12 @@ mod tests {
 
         let compressor = Compressor::new(
             1,
-            32,
-            Quality::default(),
-            Algorithms {
-                brotli: false,
-                deflate: false,
-                gzip: true,
-                zstd: false,
+            CompressOptions {
+                min_size: 32,
+                algorithms: gzip_only(),
+                extensions: Some(HashSet::from([String::from("js")])),
+                ..CompressOptions::default()
             },
-            Some(HashSet::from([String::from("js")])),
-            false,
         );
         compressor.precompress(&root, &WalkOptions::default())?;
         let stats = compressor.finish();
@@ -617,16 +2484,11 @@ mod tests {
         let original = fs::read(&dst_path)?;
         let compressor = Compressor::new(
             1,
-            1,
-            Quality::default(),
-            Algorithms {
-                brotli: false,
-                deflate: false,
-                gzip: true,
-                zstd: false,
+            CompressOptions {
+                min_size: 1,
+                algorithms: gzip_only(),
+                ..CompressOptions::default()
             },
-            None,
-            false,
         );
         compressor.precompress(&root, &WalkOptions::default())?;
         let stats = compressor.finish();
@@ -650,16 +2512,11 @@ mod tests {
 
         let compressor = Compressor::new(
             1,
-            1,
-            Quality::default(),
-            Algorithms {
-                brotli: false,
-                deflate: false,
-                gzip: true,
-                zstd: false,
+            CompressOptions {
+                min_size: 1,
+                algorithms: gzip_only(),
+                ..CompressOptions::default()
             },
-            None,
-            false,
         );
         compressor.precompress(&root, &WalkOptions::default())?;
         let stats = compressor.finish();
@@ -673,6 +2530,767 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn incremental_skips_up_to_date_variants() -> Result<()> {
+        let root = test_dir("incremental");
+        let src_path = root.join("asset.js");
+        fs::write(&src_path, "const payload = 'hello world';\n".repeat(256))?;
+        let dst_path = root.join("asset.js.gz");
+        let options = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            incremental: true,
+            ..CompressOptions::default()
+        };
+
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_up_to_date, 0);
+        assert_eq!(
+            fs::metadata(&dst_path)?.modified()?,
+            fs::metadata(&src_path)?.modified()?
+        );
+
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_source_files, 0);
+        assert_eq!(stats.num_up_to_date, 1);
+
+        // A fresh mtime no longer matches the record, so it is rewritten.
+        let touched = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&src_path)?
+            .set_modified(touched)?;
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_up_to_date, 0);
+        assert_eq!(fs::metadata(&dst_path)?.modified()?, touched);
+
+        fs::write(&src_path, "const payload = 'changed';\n".repeat(256))?;
+        let stats = run(&root, options)?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_up_to_date, 0);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn incremental_rewrites_variants_when_the_quality_changes() -> Result<()> {
+        let root = test_dir("incremental-quality");
+        let src_path = root.join("asset.js");
+        fs::write(&src_path, "const payload = 'hello world';\n".repeat(256))?;
+        let dst_path = root.join("asset.js.gz");
+        let mut options = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            incremental: true,
+            ..CompressOptions::default()
+        };
+        options.quality.set(Algorithm::Gzip, 1);
+        run(&root, options.clone())?;
+        let fast = fs::read(&dst_path)?;
+
+        // The old variant still decodes to the source, but is rewritten.
+        options.quality.set(Algorithm::Gzip, 9);
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_up_to_date, 0);
+        assert_ne!(fs::read(&dst_path)?, fast);
+
+        let stats = run(&root, options)?;
+        assert_eq!(stats.num_source_files, 0);
+        assert_eq!(stats.num_up_to_date, 1);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn incremental_detects_a_replaced_source_with_the_same_mtime() -> Result<()> {
+        let root = test_dir("incremental-restore");
+        let src_path = root.join("asset.js");
+        fs::write(&src_path, "const payload = 'hello world';\n".repeat(256))?;
+        let options = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            incremental: true,
+            ..CompressOptions::default()
+        };
+        run(&root, options.clone())?;
+        assert!(root.join(STATE_FILE_NAME).is_file());
+
+        // Restoring a tree with `tar` or `rsync -t` keeps the old mtime.
+        let modified = fs::metadata(&src_path)?.modified()?;
+        fs::write(&src_path, "const payload = 'restored';\n".repeat(256))?;
+        fs::File::options()
+            .write(true)
+            .open(&src_path)?
+            .set_modified(modified)?;
+        let stats = run(&root, options)?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_up_to_date, 0);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn incremental_runs_do_not_reencode_dropped_variants() -> Result<()> {
+        let root = test_dir("incremental-dropped");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(256))?;
+        // Pseudo-random bytes that neither algorithm can shrink.
+        let mut state = 0x2545_f491_u32;
+        let noise = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        fs::write(root.join("noise.js"), noise)?;
+        let mut options = CompressOptions {
+            algorithms: Algorithms {
+                brotli: false,
+                deflate: true,
+                gzip: true,
+                zstd: false,
+            },
+            keep_best: Some(KeepBest::new(Algorithm::Deflate)),
+            incremental: true,
+            ..CompressOptions::default()
+        };
+        options.min_savings.deflate.ratio = Some(0.1);
+        options.min_savings.gzip.ratio = Some(0.1);
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_source_files, 2);
+        assert_eq!(stats.num_discarded, 2);
+        assert_eq!(stats.num_pruned, 1);
+
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_source_files, 0);
+        assert_eq!(stats.num_up_to_date, 2);
+        assert_eq!(stats.num_discarded, 0);
+        assert_eq!(stats.num_pruned, 0);
+        assert!(!root.join("app.js.gz").exists());
+
+        // Changing the settings that dropped a variant reconsiders it.
+        options.keep_best = None;
+        let stats = run(&root, options)?;
+        assert_eq!(stats.num_source_files, 2);
+        assert_eq!(stats.num_discarded, 2);
+        assert!(root.join("app.js.gz").exists());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn variants_below_the_minimum_savings_are_discarded() -> Result<()> {
+        let root = test_dir("min-savings");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(256))?;
+        // Pseudo-random bytes that gzip cannot shrink.
+        let mut state = 0x2545_f491_u32;
+        let noise = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        fs::write(root.join("noise.js"), noise)?;
+        let mut options = CompressOptions {
+            algorithms: gzip_only(),
+            incremental: true,
+            ..CompressOptions::default()
+        };
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_discarded, 0);
+        assert!(root.join("noise.js.gz").exists());
+
+        // New settings rewrite both, and the one that does not save enough
+        // is removed.
+        options.min_savings.gzip.ratio = Some(0.1);
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_discarded, 1);
+        assert_eq!(stats.num_up_to_date, 0);
+        assert_eq!(stats.num_source_files, 2);
+        assert!(!root.join("noise.js.gz").exists());
+
+        options.incremental = false;
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_discarded, 1);
+        assert_eq!(
+            stats.gzip.total_bytes,
+            fs::metadata(root.join("app.js.gz"))?.len()
+        );
+        assert!(!root.join("noise.js.gz").exists());
+        assert!(!tmp_output_path(&root.join("noise.js.gz")).exists());
+
+        // Checking expects the discarded variant to be missing.
+        options.mode = Mode::Check;
+        let stats = run(&root, options)?;
+        assert_eq!(stats.num_missing, 0);
+
+        let threshold = Threshold {
+            ratio: Some(0.5),
+            bytes: Some(100),
+        };
+        assert!(threshold.is_met(1000, 500));
+        assert!(!threshold.is_met(1000, 501));
+        assert!(!threshold.is_met(150, 60));
+        assert!(Threshold::default().is_met(10, 20));
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn keep_best_prunes_variants_that_do_not_beat_the_baseline() -> Result<()> {
+        let root = test_dir("keep-best");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(256))?;
+        let (tx, rx) = channel();
+        let mut options = CompressOptions {
+            algorithms: Algorithms {
+                brotli: true,
+                deflate: true,
+                gzip: true,
+                zstd: false,
+            },
+            keep_best: Some(KeepBest::new(Algorithm::Deflate)),
+            events: Some(tx),
+            ..CompressOptions::default()
+        };
+        let stats = run(&root, options.clone())?;
+
+        // Gzip is deflate with a header and trailer, so it never beats it.
+        assert_eq!(stats.num_pruned, 1);
+        assert!(root.join("app.js.br").exists());
+        assert!(root.join("app.js.zz").exists());
+        assert!(!root.join("app.js.gz").exists());
+        options.events = None;
+        let pruned = rx
+            .iter()
+            .filter_map(|event| match event {
+                Event::File(report) => Some(report),
+                Event::Warning(_) => None,
+            })
+            .flat_map(|report| report.variants)
+            .filter(|variant| variant.status == VariantOutcome::Pruned)
+            .map(|variant| variant.algorithm)
+            .collect::<Vec<_>>();
+        assert_eq!(pruned, vec!["gzip"]);
+
+        // Checking expects the pruned variant to be missing.
+        options.mode = Mode::Check;
+        let stats = run(&root, options)?;
+        assert_eq!(stats.num_missing, 0);
+
+        let keep_best = KeepBest {
+            baseline: Algorithm::Gzip,
+            margin: 0.1,
+        };
+        assert!(keep_best.beats(89, 100));
+        assert!(!keep_best.beats(90, 100));
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn cache_reuses_variants_of_identical_contents() -> Result<()> {
+        let root = test_dir("cache-reuse");
+        let payload = "const payload = 'hello world';\n".repeat(256);
+        fs::create_dir(root.join("a"))?;
+        fs::create_dir(root.join("b"))?;
+        fs::write(root.join("a/vendor.js"), &payload)?;
+        let options = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            cache: Some(Cache::new(test_dir("cache-reuse-store"))),
+            ..CompressOptions::default()
+        };
+
+        let stats = run(&root.join("a"), options.clone())?;
+        assert_eq!(stats.num_cache_hits, 0);
+
+        fs::write(root.join("b/vendor.js"), &payload)?;
+        let stats = run(&root.join("b"), options.clone())?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_cache_hits, 1);
+        assert_eq!(
+            fs::read(root.join("a/vendor.js.gz"))?,
+            fs::read(root.join("b/vendor.js.gz"))?
+        );
+
+        fs::remove_dir_all(root)?;
+        if let Some(cache) = options.cache {
+            fs::remove_dir_all(cache.dir())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compressor_reports_each_source_file() -> Result<()> {
+        let root = test_dir("report");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(256))?;
+        let (tx, rx) = channel();
+        let options = CompressOptions {
+            algorithms: gzip_only(),
+            incremental: true,
+            events: Some(tx),
+            ..CompressOptions::default()
+        };
+
+        run(&root, options.clone())?;
+        run(&root, options)?;
+        let reports = rx
+            .iter()
+            .filter_map(|event| match event {
+                Event::File(report) => Some(report),
+                Event::Warning(_) => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].size, 256 * 13);
+        let statuses = reports
+            .iter()
+            .map(|report| report.variants[0].status)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![VariantOutcome::Written, VariantOutcome::UpToDate]
+        );
+        assert_eq!(reports[0].variants[0].size, reports[1].variants[0].size);
+        assert!(
+            reports[0].variants[0]
+                .saved_bytes
+                .is_some_and(|saved| saved > 0)
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn builder_checks_without_writing_and_sends_events() -> Result<()> {
+        let root = test_dir("builder");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(256))?;
+        let (tx, rx) = channel();
+        let compressor = Compressor::builder()
+            .threads(2)
+            .mode(Mode::Check)
+            .algorithms(gzip_only())
+            .events(tx)
+            .build();
+        compressor.precompress(&root, &WalkOptions::default())?;
+        let stats = compressor.finish();
+        assert_eq!(stats.num_missing, 1);
+        assert!(!root.join("app.js.gz").exists());
+
+        let events = rx.iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        let Event::File(report) = &events[0] else {
+            panic!("unexpected event: {:?}", events[0]);
+        };
+        assert_eq!(report.variants[0].status, VariantOutcome::Missing);
+        assert_eq!(
+            report.variants[0].path,
+            Some(root.join("app.js.gz").display().to_string())
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compressor_writes_variants_and_originals_to_out_dir() -> Result<()> {
+        let root = test_dir("out-dir");
+        let src = root.join("src");
+        let out = root.join("out");
+        fs::create_dir_all(src.join("css"))?;
+        fs::write(
+            src.join("css/app.css"),
+            "body { color: red; }\n".repeat(256),
+        )?;
+        fs::write(src.join("logo.png"), [0; 2048])?;
+
+        let options = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            output: Output {
+                out_dir: Some(out.clone()),
+                originals: Originals::Copy,
+                layout: Layout::Sibling,
+                naming: Naming::default(),
+            },
+            ..CompressOptions::default()
+        };
+        let stats = run(&src, options.clone())?;
+
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_originals, 2);
+        assert!(out.join("css/app.css.gz").is_file());
+        assert!(!src.join("css/app.css.gz").exists());
+        assert_eq!(
+            fs::read(out.join("css/app.css"))?,
+            fs::read(src.join("css/app.css"))?
+        );
+        assert!(out.join("logo.png").is_file());
+        assert!(!out.join("logo.png.gz").exists());
+
+        // Unchanged originals are not copied again.
+        let stats = run(&src, options)?;
+        assert_eq!(stats.num_originals, 0);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compressor_skips_out_dir_nested_in_source_tree() -> Result<()> {
+        let root = test_dir("nested-out-dir");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(256))?;
+
+        let options = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            output: Output {
+                out_dir: Some(root.join("dist")),
+                originals: Originals::Hardlink,
+                layout: Layout::Sibling,
+                naming: Naming::default(),
+            },
+            ..CompressOptions::default()
+        };
+        // The output directory is created during the first walk.
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_originals, 1);
+        let stats = run(&root, options)?;
+
+        assert_eq!(stats.num_source_files, 1);
+        assert!(root.join("dist/app.js").is_file());
+        assert!(root.join("dist/app.js.gz").is_file());
+        assert!(!root.join("dist/dist").exists());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compressor_does_not_recompress_templated_variants() -> Result<()> {
+        let root = test_dir("templated-variants");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(256))?;
+
+        let mut output = Output::default();
+        output.naming.set(
+            Algorithm::Gzip,
+            "{stem}.{enc}.{ext}".parse().expect("valid template"),
+        );
+        let options = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            output,
+            ..CompressOptions::default()
+        };
+        run(&root, options.clone())?;
+        let stats = run(&root, options)?;
+
+        assert_eq!(stats.num_source_files, 1);
+        assert!(root.join("app.gz.js").is_file());
+        assert!(!root.join("app.gz.gz.js").exists());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compressor_refuses_to_overwrite_a_source_with_a_variant() -> Result<()> {
+        let root = test_dir("variant-over-source");
+        fs::write(root.join("app.js"), "const x = 1;\n".repeat(256))?;
+        let minified = "const x=1;".repeat(256);
+        fs::write(root.join("app.min.js"), &minified)?;
+
+        // Validation rejects this template, as the gzip variant of `app.js`
+        // is named like the source `app.min.js`.
+        let mut output = Output::default();
+        output.naming.set(
+            Algorithm::Gzip,
+            "{stem}.min.{ext}".parse().expect("valid template"),
+        );
+        let options = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            output,
+            ..CompressOptions::default()
+        };
+        let stats = run(&root, options)?;
+
+        assert_eq!(stats.num_errors, 1);
+        assert_eq!(fs::read_to_string(root.join("app.min.js"))?, minified);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn check_reports_missing_stale_and_corrupt_variants() -> Result<()> {
+        let root = test_dir("check");
+        for name in ["ok.js", "stale.js", "corrupt.js", "missing.js"] {
+            fs::write(root.join(name), format!("// {name}\n").repeat(256))?;
+        }
+        let compress = CompressOptions {
+            min_size: 1,
+            algorithms: gzip_only(),
+            ..CompressOptions::default()
+        };
+        run(&root, compress.clone())?;
+        let listing = fs::read_dir(&root)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        fs::remove_file(root.join("missing.js.gz"))?;
+        fs::write(root.join("stale.js"), "// changed\n".repeat(256))?;
+        let corrupt = fs::read(root.join("corrupt.js.gz"))?;
+        fs::write(root.join("corrupt.js.gz"), &corrupt[0..corrupt.len() / 2])?;
+        let modified = fs::metadata(root.join("corrupt.js"))?.modified()?;
+        fs::File::options()
+            .write(true)
+            .open(root.join("corrupt.js.gz"))?
+            .set_modified(modified)?;
+
+        let stats = run(
+            &root,
+            CompressOptions {
+                mode: Mode::Check,
+                ..compress
+            },
+        )?;
+        assert_eq!(stats.num_source_files, 4);
+        assert_eq!(stats.num_missing, 1);
+        assert_eq!(stats.num_stale, 1);
+        assert_eq!(stats.num_corrupt, 1);
+        assert!(!root.join("missing.js.gz").exists());
+        assert_eq!(listing.len() - 1, fs::read_dir(&root)?.count());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn zopfli_output_is_smaller_and_decodes() -> Result<()> {
+        let root = test_dir("zopfli");
+        let src_path = root.join("asset.js");
+        let payload = (0..128)
+            .map(|i| format!("const value{i} = {};\n", i * 7 % 13))
+            .collect::<String>();
+        fs::write(&src_path, payload)?;
+        let options = CompressOptions {
+            min_size: 1,
+            algorithms: Algorithms {
+                brotli: false,
+                deflate: true,
+                gzip: true,
+                zstd: false,
+            },
+            verify: true,
+            ..CompressOptions::default()
+        };
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_errors, 0);
+        let regular = stats.gzip.total_bytes;
+
+        let iterations = NonZeroU64::new(5).expect("non-zero");
+        let mut quality = Quality::default();
+        assert!(quality.set_zopfli(Algorithm::Gzip, iterations));
+        assert!(quality.set_zopfli(Algorithm::Deflate, iterations));
+        assert!(!quality.set_zopfli(Algorithm::Brotli, iterations));
+        let stats = run(&root, CompressOptions { quality, ..options })?;
+        assert_eq!(stats.num_errors, 0);
+        assert!(stats.gzip.total_bytes < regular);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn dictionary_variants_use_the_previous_build() -> Result<()> {
+        let root = test_dir("dcz");
+        let previous = test_dir("dcz-previous");
+        let payload = (0..512)
+            .map(|i| format!("export const value{i} = {};\n", i * 7 % 13))
+            .collect::<String>();
+        fs::write(previous.join("app.js"), &payload)?;
+        fs::write(root.join("app.js"), payload.replace("value42", "renamed"))?;
+        fs::write(root.join("new.js"), &payload)?;
+        let options = CompressOptions {
+            algorithms: gzip_only(),
+            incremental: true,
+            verify: true,
+            dictionaries: Some(Dictionaries::PreviousTree(previous.clone())),
+            dictionary_formats: vec![DictionaryFormat::Dcz, DictionaryFormat::Dcb],
+            ..CompressOptions::default()
+        };
+
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_errors, 0);
+        assert!(root.join("app.js.dcz").exists());
+        assert!(root.join("app.js.dcb").exists());
+        assert!(!root.join("new.js.dcz").exists());
+        assert!(stats.dcz.total_bytes > 0);
+        assert!(stats.dcz.total_bytes < stats.dcz.baseline_bytes);
+        assert!(stats.dcb.total_bytes > 0);
+        assert!(stats.dcb.total_bytes < stats.dcb.baseline_bytes);
+
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_up_to_date, 2);
+
+        let check = CompressOptions {
+            mode: Mode::Check,
+            ..options.clone()
+        };
+        assert_eq!(run(&root, check.clone())?.num_missing, 0);
+        fs::remove_file(root.join("app.js.dcb"))?;
+        assert_eq!(run(&root, check.clone())?.num_missing, 1);
+        run(&root, options.clone())?;
+
+        // A new dictionary makes the existing variant stale.
+        fs::write(previous.join("app.js"), payload.replace("value1", "other"))?;
+        assert_eq!(run(&root, check)?.num_stale, 2);
+        let stats = run(&root, options)?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_up_to_date, 1);
+        assert!(stats.dcz.total_bytes > 0);
+
+        fs::remove_dir_all(root)?;
+        fs::remove_dir_all(previous)?;
+        Ok(())
+    }
+
+    #[test]
+    fn zstd_dictionary_shrinks_small_files() -> Result<()> {
+        let root = test_dir("zstd-dict");
+        let fragment = |i: usize| {
+            format!(
+                r#"{{"id":{i},"type":"product","name":"Item {i}","tags":["new","sale"],"price":{}}}"#,
+                i * 7
+            )
+        };
+        for i in 0..20 {
+            fs::write(root.join(format!("item-{i}.json")), fragment(i))?;
+        }
+        let samples = (100..300)
+            .map(|i| fragment(i).into_bytes())
+            .collect::<Vec<_>>();
+        let options = CompressOptions {
+            min_size: 0,
+            algorithms: Algorithms {
+                brotli: false,
+                deflate: false,
+                gzip: false,
+                zstd: true,
+            },
+            quality: Quality {
+                zstd: 3,
+                ..Quality::default()
+            },
+            zstd_dictionary: Some(Arc::new(Dictionary::new(train(&samples, 4096)?))),
+            incremental: true,
+            verify: true,
+            ..CompressOptions::default()
+        };
+
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_errors, 0);
+        assert_eq!(stats.num_source_files, 20);
+        assert!(stats.zstd.total_bytes < stats.zstd_baseline_bytes);
+
+        let stats = run(&root, options.clone())?;
+        assert_eq!(stats.num_up_to_date, 20);
+
+        // Variants compressed with a different dictionary are rewritten.
+        let options = CompressOptions {
+            zstd_dictionary: Some(Arc::new(Dictionary::new(train(&samples, 2048)?))),
+            ..options
+        };
+        let stats = run(&root, options)?;
+        assert_eq!(stats.num_errors, 0);
+        assert_eq!(stats.num_source_files, 20);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn verify_accepts_every_algorithm_and_rejects_mismatches() -> Result<()> {
+        let root = test_dir("verify");
+        let src_path = root.join("asset.js");
+        fs::write(&src_path, "const payload = 'hello world';\n".repeat(256))?;
+
+        let stats = run(
+            &root,
+            CompressOptions {
+                min_size: 1,
+                algorithms: Algorithms::all(),
+                verify: true,
+                ..CompressOptions::default()
+            },
+        )?;
+        assert_eq!(stats.num_source_files, 1);
+        assert_eq!(stats.num_errors, 0);
+
+        let mut ctx = Context::new(1 << 14, Quality::default());
+        let mut src = fs::File::open(&src_path)?;
+        let dst_path = root.join("asset.js.gz");
+        let err = write_atomic(&dst_path, |dst| {
+            dst.write_all(&fs::read(root.join("asset.js.zz"))?)?;
+            verify_variant(
+                &mut ctx,
+                Encoding::Algorithm(Algorithm::Gzip),
+                dst,
+                &mut src,
+            )
+        })
+        .expect_err("verification should fail");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!tmp_output_path(&dst_path).exists());
+
+        let truncated = fs::read(&dst_path)?;
+        let err = write_atomic(&dst_path, |dst| {
+            dst.write_all(&truncated[0..truncated.len() - 8])?;
+            verify_variant(
+                &mut ctx,
+                Encoding::Algorithm(Algorithm::Gzip),
+                dst,
+                &mut src,
+            )
+        })
+        .expect_err("verification should fail");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&dst_path)?, truncated);
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    fn run(root: &Path, options: CompressOptions) -> Result<Stats> {
+        let compressor = Compressor::new(1, options);
+        compressor.precompress(root, &WalkOptions::default())?;
+        Ok(compressor.finish())
+    }
+
+    fn gzip_only() -> Algorithms {
+        Algorithms {
+            brotli: false,
+            deflate: false,
+            gzip: true,
+            zstd: false,
+        }
+    }
+
     fn walk_paths(root: &Path, options: &WalkOptions) -> Result<Vec<String>> {
         let mut paths = build_walk(root, options)?
             .filter_map(|entry| entry.ok())
@@ -688,14 +3306,4 @@ mod tests {
         paths.sort();
         Ok(paths)
     }
-
-    fn test_dir(name: &str) -> PathBuf {
-        let unique = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("time went backwards")
-            .as_nanos();
-        let root = std::env::temp_dir().join(format!("precompress-{name}-{unique}"));
-        fs::create_dir_all(&root).expect("unable to create temp directory");
-        root
-    }
 }
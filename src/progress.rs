@@ -0,0 +1,267 @@
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::precompress::{Algorithm, Algorithms};
+
+/// The minimum time between two calls of the progress callback.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// A snapshot of how far a run has got. More counters may be added, so
+/// create one with [`Default`] rather than a struct expression.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Progress {
+    /// Files found by the walk so far, including those too small to compress.
+    pub files_discovered: u64,
+    /// The total size of the files discovered.
+    pub bytes_discovered: u64,
+    /// Files that every worker is done with.
+    pub files_done: u64,
+    /// The total size of the files done.
+    pub bytes_done: u64,
+    /// Total size of the variants written so far.
+    pub bytes_written: u64,
+    /// Whether the walk has finished, so that the totals are final.
+    pub walk_done: bool,
+    /// Time since the run started.
+    pub elapsed: Duration,
+    /// Bytes encoded by each enabled algorithm so far.
+    pub algorithms: Vec<AlgProgress>,
+}
+
+/// How many bytes one algorithm has encoded so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AlgProgress {
+    /// The algorithm these counts are for.
+    pub algorithm: Algorithm,
+    /// Source bytes encoded.
+    pub bytes_in: u64,
+    /// Encoded bytes written.
+    pub bytes_out: u64,
+}
+
+impl AlgProgress {
+    /// Returns the given counts for `algorithm`.
+    pub fn new(algorithm: Algorithm, bytes_in: u64, bytes_out: u64) -> Self {
+        AlgProgress {
+            algorithm,
+            bytes_in,
+            bytes_out,
+        }
+    }
+}
+
+impl Progress {
+    /// Estimates the time left from the rate at which bytes have been done,
+    /// once the walk has found every file.
+    pub fn eta(&self) -> Option<Duration> {
+        if !self.walk_done || self.bytes_done == 0 {
+            return None;
+        }
+        let left = self.bytes_discovered.saturating_sub(self.bytes_done);
+        Some(self.elapsed.mul_f64(left as f64 / self.bytes_done as f64))
+    }
+}
+
+/// Called with a snapshot as the run progresses.
+#[derive(Clone)]
+pub(crate) struct ProgressCallback(pub(crate) Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Progress counters shared between the walk and the workers.
+#[derive(Debug)]
+pub(crate) struct Counters {
+    start: Instant,
+    algorithms: Algorithms,
+    callback: Option<ProgressCallback>,
+    /// The minimum time between two calls of the callback, [`INTERVAL`]
+    /// outside of tests.
+    interval: Duration,
+    /// Milliseconds since `start` at which the callback was last called.
+    last_call: AtomicU64,
+    files_discovered: AtomicU64,
+    bytes_discovered: AtomicU64,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    walk_done: AtomicBool,
+    /// Bytes in and out for each algorithm, in declaration order.
+    encoded: [(AtomicU64, AtomicU64); 4],
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters::new(Algorithms::default(), None)
+    }
+}
+
+impl Counters {
+    pub(crate) fn new(algorithms: Algorithms, callback: Option<ProgressCallback>) -> Self {
+        Counters {
+            start: Instant::now(),
+            algorithms,
+            callback,
+            interval: INTERVAL,
+            last_call: AtomicU64::new(0),
+            files_discovered: AtomicU64::new(0),
+            bytes_discovered: AtomicU64::new(0),
+            files_done: AtomicU64::new(0),
+            bytes_done: AtomicU64::new(0),
+            walk_done: AtomicBool::new(false),
+            encoded: Default::default(),
+        }
+    }
+
+    pub(crate) fn discovered(&self, size: u64) {
+        self.files_discovered.fetch_add(1, Ordering::Relaxed);
+        self.bytes_discovered.fetch_add(size, Ordering::Relaxed);
+        self.notify(false);
+    }
+
+    pub(crate) fn walk_done(&self) {
+        self.walk_done.store(true, Ordering::Relaxed);
+        self.notify(false);
+    }
+
+    pub(crate) fn encoded(&self, alg: Algorithm, bytes_in: u64, bytes_out: u64) {
+        let (total_in, total_out) = &self.encoded[alg as usize];
+        total_in.fetch_add(bytes_in, Ordering::Relaxed);
+        total_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    pub(crate) fn file_done(&self, size: u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.bytes_done.fetch_add(size, Ordering::Relaxed);
+        self.notify(false);
+    }
+
+    pub(crate) fn snapshot(&self) -> Progress {
+        let algorithms = self
+            .algorithms
+            .iter()
+            .map(|algorithm| {
+                let (bytes_in, bytes_out) = &self.encoded[algorithm as usize];
+                AlgProgress {
+                    algorithm,
+                    bytes_in: bytes_in.load(Ordering::Relaxed),
+                    bytes_out: bytes_out.load(Ordering::Relaxed),
+                }
+            })
+            .collect::<Vec<_>>();
+        Progress {
+            files_discovered: self.files_discovered.load(Ordering::Relaxed),
+            bytes_discovered: self.bytes_discovered.load(Ordering::Relaxed),
+            files_done: self.files_done.load(Ordering::Relaxed),
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            bytes_written: algorithms.iter().map(|alg| alg.bytes_out).sum(),
+            walk_done: self.walk_done.load(Ordering::Relaxed),
+            elapsed: self.start.elapsed(),
+            algorithms,
+        }
+    }
+
+    /// Calls the callback with a new snapshot, unless it was called less
+    /// than `interval` ago and `force` is not set.
+    pub(crate) fn notify(&self, force: bool) {
+        let Some(callback) = &self.callback else {
+            return;
+        };
+        let now = self.start.elapsed().as_millis() as u64;
+        let last = self.last_call.load(Ordering::Relaxed);
+        if !force
+            && (now < last + self.interval.as_millis() as u64
+                || self
+                    .last_call
+                    .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_err())
+        {
+            return;
+        }
+        (callback.0)(&self.snapshot());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{Counters, Progress, ProgressCallback};
+    use crate::precompress::{Algorithm, Algorithms};
+
+    #[test]
+    fn counters_snapshot_and_throttle_the_callback() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let callback = {
+            let calls = Arc::clone(&calls);
+            ProgressCallback(Arc::new(move |progress: &Progress| {
+                calls.lock().expect("lock poisoned").push(progress.clone())
+            }))
+        };
+        let mut counters = Counters::new(Algorithms::default(), Some(callback));
+        // However slowly the test runs, only the forced call is due.
+        counters.interval = Duration::from_secs(3600);
+
+        counters.discovered(300);
+        counters.discovered(100);
+        counters.encoded(Algorithm::Gzip, 300, 40);
+        counters.file_done(300);
+        counters.walk_done();
+        assert!(calls.lock().expect("lock poisoned").is_empty());
+
+        counters.notify(true);
+        let progress = {
+            let calls = calls.lock().expect("lock poisoned");
+            assert_eq!(calls.len(), 1);
+            calls[0].clone()
+        };
+        assert_eq!(progress.files_discovered, 2);
+        assert_eq!(progress.bytes_discovered, 400);
+        assert_eq!(progress.files_done, 1);
+        assert_eq!(progress.bytes_written, 40);
+        assert!(progress.walk_done);
+        let algorithms = progress
+            .algorithms
+            .iter()
+            .map(|alg| (alg.algorithm, alg.bytes_in))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            algorithms,
+            vec![
+                (Algorithm::Brotli, 0),
+                (Algorithm::Gzip, 300),
+                (Algorithm::Zstd, 0)
+            ]
+        );
+
+        counters.interval = Duration::ZERO;
+        counters.file_done(100);
+        assert_eq!(calls.lock().expect("lock poisoned").len(), 2);
+    }
+
+    #[test]
+    fn eta_waits_for_the_walk() {
+        let mut progress = Progress {
+            bytes_discovered: 400,
+            bytes_done: 100,
+            elapsed: Duration::from_secs(2),
+            ..Progress::default()
+        };
+        assert_eq!(progress.eta(), None);
+        progress.walk_done = true;
+        assert_eq!(progress.eta(), Some(Duration::from_secs(6)));
+    }
+}
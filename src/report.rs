@@ -0,0 +1,295 @@
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::precompress::Stats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    // A single JSON document written when the run completes.
+    Json,
+    // One JSON record per line, written as each source file completes.
+    Jsonl,
+}
+
+/// The results for a single source file.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    /// The path of the source file.
+    pub path: String,
+    /// The size of the source file.
+    pub size: u64,
+    /// The result for each variant.
+    pub variants: Vec<VariantReport>,
+    /// Why the source could not be read, if it could not.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The result of producing one compressed variant of a source file.
+#[derive(Debug, Clone, Serialize)]
+pub struct VariantReport {
+    /// The algorithm or dictionary format of the variant.
+    pub algorithm: String,
+    /// The path of the variant file, once it is known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// What happened to the variant.
+    pub status: VariantOutcome,
+    /// The size of the variant, if it exists.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The time spent producing the variant.
+    #[serde(serialize_with = "serialize_secs")]
+    pub time: Duration,
+    /// The size of the source less `size`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved_bytes: Option<i64>,
+    /// `saved_bytes` as a percentage of the size of the source.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub savings: Option<u8>,
+    /// For dictionary-compressed variants, the size of the plain variant
+    /// they are compared against.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline_size: Option<u64>,
+    /// For dictionary-compressed variants, the SHA-256 hash of the
+    /// dictionary as sent by clients in `Available-Dictionary`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dictionary_hash: Option<String>,
+    /// Why the variant could not be written or checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl VariantReport {
+    /// Returns a report for a variant that has not been written yet.
+    pub(crate) fn failed(algorithm: String) -> Self {
+        VariantReport {
+            algorithm,
+            path: None,
+            status: VariantOutcome::Failed,
+            size: None,
+            time: Duration::ZERO,
+            saved_bytes: None,
+            savings: None,
+            baseline_size: None,
+            dictionary_hash: None,
+            error: None,
+        }
+    }
+}
+
+/// What a run did with a single variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VariantOutcome {
+    /// The variant was encoded and written.
+    Written,
+    /// The variant was copied from the cache.
+    Cached,
+    /// The existing variant was already up to date.
+    UpToDate,
+    /// The variant could not be written.
+    Failed,
+    /// The variant did not save enough over its source, so it was not
+    /// kept and any existing one was removed.
+    Discarded,
+    /// The variant was no smaller than the variant of the keep-best
+    /// baseline, so it was removed.
+    Pruned,
+    /// When checking, the variant does not exist.
+    Missing,
+    /// When checking, the variant does not match its source.
+    Stale,
+    /// When checking, the variant could not be decoded.
+    Corrupt,
+}
+
+/// Something that happened during a run, sent to callers that ask for
+/// events so that they can display progress.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The results for a source file, once every variant has been handled.
+    File(FileReport),
+    /// A problem that did not stop the run, such as an unreadable directory
+    /// entry.
+    Warning(String),
+}
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    #[serde(serialize_with = "serialize_secs")]
+    elapsed: Duration,
+    stats: &'a Stats,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    File(&'a FileReport),
+    Summary(Summary<'a>),
+}
+
+#[derive(Serialize)]
+struct Document<'a> {
+    files: &'a [FileReport],
+    #[serde(flatten)]
+    summary: Summary<'a>,
+}
+
+/// Writes per-file results and the final summary in a machine-readable
+/// format.
+pub struct Reporter<W: Write> {
+    format: ReportFormat,
+    out: W,
+    files: Vec<FileReport>,
+}
+
+impl<W: Write> Reporter<W> {
+    pub fn new(format: ReportFormat, out: W) -> Self {
+        Reporter {
+            format,
+            out,
+            files: Vec::new(),
+        }
+    }
+
+    /// Records the results for a source file, writing them immediately for
+    /// line-delimited output.
+    pub fn file(&mut self, report: FileReport) -> io::Result<()> {
+        match self.format {
+            ReportFormat::Json => {
+                self.files.push(report);
+                Ok(())
+            }
+            ReportFormat::Jsonl => self.write_line(&Record::File(&report)),
+        }
+    }
+
+    /// Writes the aggregate statistics, along with every recorded file for
+    /// single-document output.
+    pub fn finish(mut self, elapsed: Duration, stats: &Stats) -> io::Result<W> {
+        let summary = Summary { elapsed, stats };
+        match self.format {
+            ReportFormat::Json => {
+                self.files.sort_by(|a, b| a.path.cmp(&b.path));
+                let document = Document {
+                    files: &self.files,
+                    summary,
+                };
+                serde_json::to_writer_pretty(&mut self.out, &document)?;
+                self.out.write_all(b"\n")?;
+            }
+            ReportFormat::Jsonl => self.write_line(&Record::Summary(summary))?,
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_line(&mut self, record: &Record<'_>) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, record)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
+
+/// Returns the percentage of the original size saved, given the bytes saved
+/// and the compressed size.
+pub fn calc_savings(saved: i64, total: u64) -> u8 {
+    if saved == 0 && total == 0 {
+        return 0;
+    }
+    ((saved as f64 / (saved as f64 + total as f64)) * 100.0) as u8
+}
+
+/// Serializes a duration as fractional seconds.
+pub(crate) fn serialize_secs<S: Serializer>(dur: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(dur.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use super::{FileReport, ReportFormat, Reporter, VariantOutcome, VariantReport, calc_savings};
+    use crate::precompress::{AlgStat, Stats};
+
+    fn file_report(path: &str) -> FileReport {
+        FileReport {
+            path: String::from(path),
+            size: 4096,
+            variants: vec![VariantReport {
+                algorithm: String::from("gzip"),
+                path: Some(format!("{path}.gz")),
+                status: VariantOutcome::Written,
+                size: Some(1024),
+                time: Duration::from_millis(5),
+                saved_bytes: Some(3072),
+                savings: Some(75),
+                baseline_size: None,
+                dictionary_hash: None,
+                error: None,
+            }],
+            error: None,
+        }
+    }
+
+    #[test]
+    fn jsonl_reports_one_record_per_line() {
+        let mut reporter = Reporter::new(ReportFormat::Jsonl, Vec::new());
+        reporter.file(file_report("a.js")).expect("write to vec");
+        let stats = Stats {
+            num_source_files: 1,
+            gzip: AlgStat {
+                total_time: Duration::from_millis(1500),
+                ..AlgStat::default()
+            },
+            ..Stats::default()
+        };
+        let out = reporter
+            .finish(Duration::from_secs(2), &stats)
+            .expect("write to vec");
+
+        let lines = String::from_utf8(out).expect("valid utf-8");
+        let records = lines
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).expect("valid json"))
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["type"], "file");
+        assert_eq!(records[0]["variants"][0]["algorithm"], "gzip");
+        assert_eq!(records[0]["variants"][0]["status"], "written");
+        assert!(records[0]["variants"][0].get("error").is_none());
+        assert_eq!(records[1]["type"], "summary");
+        assert_eq!(records[1]["elapsed"], 2.0);
+        assert_eq!(records[1]["stats"]["num_source_files"], 1);
+        assert_eq!(records[1]["stats"]["gzip"]["total_time"], 1.5);
+    }
+
+    #[test]
+    fn json_reports_a_single_sorted_document() {
+        let mut reporter = Reporter::new(ReportFormat::Json, Vec::new());
+        reporter.file(file_report("b.js")).expect("write to vec");
+        reporter.file(file_report("a.js")).expect("write to vec");
+        let out = reporter
+            .finish(Duration::from_secs(1), &Stats::default())
+            .expect("write to vec");
+
+        let document: Value = serde_json::from_slice(&out).expect("valid json");
+        assert_eq!(document["files"][0]["path"], "a.js");
+        assert_eq!(document["files"][1]["path"], "b.js");
+        assert_eq!(document["stats"]["num_errors"], 0);
+    }
+
+    #[test]
+    fn calc_savings_handles_zero_positive_and_negative_values() {
+        assert_eq!(calc_savings(0, 0), 0);
+        assert_eq!(calc_savings(50, 50), 50);
+        assert_eq!(calc_savings(-50, 100), 0);
+    }
+}
@@ -0,0 +1,424 @@
+//! A local HTTP server that serves precompressed variants.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use anyhow::{Result, anyhow};
+use tiny_http::{Header, Method, Request, Response, ResponseBox};
+
+use crate::encode::{Context, Quality};
+use crate::negotiate::{AcceptEncoding, DEFAULT_PREFERENCE, Negotiated, Negotiator};
+use crate::output::Output;
+use crate::precompress::{Algorithm, Algorithms, is_compressible};
+
+/// How a [`Server`] answers requests.
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    /// The algorithms whose variants may be served.
+    pub algorithms: Algorithms,
+    /// The quality used to compress files on the fly.
+    pub quality: Quality,
+    /// The extensions of the files compressed on the fly, or the default
+    /// compressible extensions when unset.
+    pub extensions: Option<HashSet<String>>,
+    /// Where the variants were written, so that they can be found.
+    pub output: Output,
+    /// Whether compressible files without a suitable variant are compressed
+    /// on the fly rather than served as is.
+    pub compress_missing: bool,
+    /// Whether the zstd variants were compressed with a dictionary, in which
+    /// case zstd is never served.
+    pub zstd_dictionary: bool,
+}
+
+/// How a request was answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Served {
+    /// The request method.
+    pub method: String,
+    /// The request URL.
+    pub url: String,
+    /// The response status code.
+    pub status: u16,
+    /// The encoding of the response body, if any.
+    pub encoding: Option<Algorithm>,
+    /// Whether the body was compressed on the fly because the variant is
+    /// missing.
+    pub on_the_fly: bool,
+}
+
+/// A small HTTP server for checking that a precompressed tree is served
+/// correctly. Each request for a file is answered with its best existing
+/// variant for the request's `Accept-Encoding`.
+pub struct Server {
+    root: PathBuf,
+    options: ServeOptions,
+    negotiator: Negotiator,
+    http: tiny_http::Server,
+}
+
+/// The representation of a file chosen for a request.
+#[derive(Debug, PartialEq, Eq)]
+enum Selected {
+    Variant(Algorithm, PathBuf),
+    Compress(Algorithm),
+    Identity,
+    /// The client accepts no encoding that can be served.
+    NotAcceptable,
+}
+
+impl Server {
+    /// Listens on `addr`, such as "127.0.0.1:8000", to serve files under
+    /// `root`.
+    pub fn bind(root: &Path, addr: &str, options: ServeOptions) -> Result<Server> {
+        let http = tiny_http::Server::http(addr)
+            .map_err(|err| anyhow!("unable to listen on {addr}: {err}"))?;
+        let negotiator = Negotiator::new(root)
+            .output(options.output.clone())
+            .preference(
+                DEFAULT_PREFERENCE
+                    .into_iter()
+                    .filter(|alg| options.algorithms.is_enabled(*alg)),
+            )
+            .zstd_dictionary(options.zstd_dictionary);
+        Ok(Server {
+            root: root.to_path_buf(),
+            options,
+            negotiator,
+            http,
+        })
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /// Answers requests on `threads` threads, calling `log` after each one,
+    /// until receiving a request fails.
+    pub fn run(&self, threads: usize, log: impl Fn(&Served) + Sync) -> io::Result<()> {
+        thread::scope(|scope| {
+            let handles = (0..threads.max(1))
+                .map(|_| scope.spawn(|| self.serve_requests(&log)))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().expect("unable to join server thread"))
+        })
+    }
+
+    fn serve_requests(&self, log: &impl Fn(&Served)) -> io::Result<()> {
+        let mut ctx = Context::new(1 << 14, self.options.quality);
+        loop {
+            let request = self.http.recv()?;
+            log(&self.respond(&mut ctx, request));
+        }
+    }
+
+    fn respond(&self, ctx: &mut Context, request: Request) -> Served {
+        let method = request.method().clone();
+        let mut served = Served {
+            method: method.to_string(),
+            url: request.url().to_owned(),
+            status: 200,
+            encoding: None,
+            on_the_fly: false,
+        };
+        let response = if method == Method::Get || method == Method::Head {
+            let accept = request
+                .headers()
+                .iter()
+                .filter(|h| h.field.equiv("Accept-Encoding"))
+                .map(|h| h.value.as_str())
+                .collect::<Vec<_>>()
+                .join(",");
+            let accept = AcceptEncoding::parse(&accept);
+            match self.response(ctx, request.url(), &accept) {
+                Ok((response, selected)) => {
+                    match selected {
+                        Some(Selected::Variant(alg, _)) => served.encoding = Some(alg),
+                        Some(Selected::Compress(alg)) => {
+                            served.encoding = Some(alg);
+                            served.on_the_fly = true;
+                        }
+                        _ => {}
+                    }
+                    response
+                }
+                Err(_) => Response::empty(500).boxed(),
+            }
+        } else {
+            Response::empty(405)
+                .with_header(header("Allow", "GET, HEAD"))
+                .boxed()
+        };
+        served.status = response.status_code().0;
+        // The client may already have gone away.
+        _ = request.respond(response);
+        served
+    }
+
+    fn response(
+        &self,
+        ctx: &mut Context,
+        url: &str,
+        accept: &AcceptEncoding,
+    ) -> io::Result<(ResponseBox, Option<Selected>)> {
+        let Some(rel) = request_path(url) else {
+            return Ok((Response::empty(404).boxed(), None));
+        };
+        let mut path = self.root.join(rel);
+        if path.is_dir() {
+            path.push("index.html");
+        }
+        if !path.is_file() {
+            return Ok((Response::empty(404).boxed(), None));
+        }
+
+        let vary = header("Vary", "Accept-Encoding");
+        let selected = self.select(&path, accept);
+        let response = match &selected {
+            Selected::Variant(_, variant) => Response::from_file(File::open(variant)?).boxed(),
+            Selected::Compress(alg) => {
+                let mut data = Vec::new();
+                ctx.write(*alg, &mut File::open(&path)?, &mut data)?;
+                Response::from_data(data).boxed()
+            }
+            Selected::Identity => Response::from_file(File::open(&path)?).boxed(),
+            Selected::NotAcceptable => {
+                return Ok((Response::empty(406).with_header(vary).boxed(), None));
+            }
+        };
+        let mut response = response
+            .with_header(header("Content-Type", content_type(&path)))
+            .with_header(vary);
+        if let Selected::Variant(alg, _) | Selected::Compress(alg) = &selected {
+            response.add_header(header("Content-Encoding", alg.encoding()));
+        }
+        Ok((response, Some(selected)))
+    }
+
+    /// Chooses the representation of the file at `path` to serve, falling
+    /// back to compressing it on the fly when no acceptable variant exists.
+    fn select(&self, path: &Path, accept: &AcceptEncoding) -> Selected {
+        match self.negotiator.negotiate_parsed(path, accept) {
+            Negotiated::Variant {
+                path, algorithm, ..
+            } => Selected::Variant(algorithm, path),
+            negotiated => {
+                if self.options.compress_missing
+                    && is_compressible(path, self.options.extensions.as_ref())
+                    && let Some(alg) = self.negotiator.rank(accept).first()
+                {
+                    return Selected::Compress(*alg);
+                }
+                if negotiated == Negotiated::Identity {
+                    Selected::Identity
+                } else {
+                    Selected::NotAcceptable
+                }
+            }
+        }
+    }
+}
+
+/// Returns the path relative to the root that a request URL names, or
+/// `None` if it would escape the root.
+fn request_path(url: &str) -> Option<PathBuf> {
+    let path = url.split(['?', '#']).next()?;
+    let mut rel = PathBuf::new();
+    for part in percent_decode(path)?.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            part if part.contains('\\') => return None,
+            part => rel.push(part),
+        }
+    }
+    Some(rel)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            out.push(u8::from_str_radix(s.get(i + 1..i + 3)?, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("header is valid ASCII")
+}
+
+/// Returns the `Content-Type` of the file at `path`, from its extension.
+pub(crate) fn content_type(path: &Path) -> &'static str {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| CONTENT_TYPES.get(ext.to_ascii_lowercase().as_str()))
+        .copied()
+        .unwrap_or("application/octet-stream")
+}
+
+static CONTENT_TYPES: phf::Map<&'static str, &'static str> = phf::phf_map! {
+    "atom" => "application/atom+xml",
+    "avif" => "image/avif",
+    "css" => "text/css; charset=utf-8",
+    "csv" => "text/csv; charset=utf-8",
+    "eot" => "application/vnd.ms-fontobject",
+    "geojson" => "application/geo+json",
+    "gif" => "image/gif",
+    "graphql" => "application/graphql",
+    "htm" => "text/html; charset=utf-8",
+    "html" => "text/html; charset=utf-8",
+    "ico" => "image/x-icon",
+    "jpeg" => "image/jpeg",
+    "jpg" => "image/jpeg",
+    "js" => "text/javascript; charset=utf-8",
+    "json" => "application/json",
+    "jsx" => "text/plain; charset=utf-8",
+    "ldjson" => "application/ld+json",
+    "log" => "text/plain; charset=utf-8",
+    "manifest" => "text/cache-manifest; charset=utf-8",
+    "map" => "application/json",
+    "md" => "text/markdown; charset=utf-8",
+    "mjs" => "text/javascript; charset=utf-8",
+    "otf" => "font/otf",
+    "pdf" => "application/pdf",
+    "png" => "image/png",
+    "rss" => "application/rss+xml",
+    "rtf" => "application/rtf",
+    "sfnt" => "font/sfnt",
+    "svg" => "image/svg+xml",
+    "text" => "text/plain; charset=utf-8",
+    "ts" => "text/plain; charset=utf-8",
+    "tsv" => "text/tab-separated-values; charset=utf-8",
+    "tsx" => "text/plain; charset=utf-8",
+    "ttf" => "font/ttf",
+    "txt" => "text/plain; charset=utf-8",
+    "wasm" => "application/wasm",
+    "webmanifest" => "application/manifest+json",
+    "webp" => "image/webp",
+    "woff" => "font/woff",
+    "woff2" => "font/woff2",
+    "xhtml" => "application/xhtml+xml",
+    "xml" => "application/xml",
+    "yaml" => "application/yaml",
+    "yml" => "application/yaml",
+};
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::TcpStream,
+        path::PathBuf,
+        thread,
+    };
+
+    use anyhow::Result;
+
+    use super::{Selected, ServeOptions, Server, request_path};
+    use crate::negotiate::AcceptEncoding;
+    use crate::precompress::{Algorithm, Algorithms};
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn request_path_decodes_and_stays_under_the_root() {
+        assert_eq!(
+            request_path("/a%20b/./app.js?v=1"),
+            Some(PathBuf::from("a b/app.js"))
+        );
+        assert_eq!(request_path("/"), Some(PathBuf::new()));
+        assert_eq!(request_path("/../etc/passwd"), None);
+        assert_eq!(request_path("/%2e%2e/etc/passwd"), None);
+        assert_eq!(request_path("/%zz"), None);
+    }
+
+    #[test]
+    fn select_compresses_missing_variants_on_the_fly() -> Result<()> {
+        let root = test_dir("serve-select");
+        let path = root.join("app.js");
+        fs::write(&path, "const x = 1;\n".repeat(256))?;
+        fs::write(root.join("app.js.gz"), "gz")?;
+        let mut options = ServeOptions {
+            algorithms: Algorithms::all(),
+            compress_missing: true,
+            ..ServeOptions::default()
+        };
+        let server = Server::bind(&root, "127.0.0.1:0", options.clone())?;
+        let select = |accept: &str| server.select(&path, &AcceptEncoding::parse(accept));
+
+        // An existing variant wins over compressing on the fly.
+        assert_eq!(
+            select("zstd, gzip;q=0.5"),
+            Selected::Variant(Algorithm::Gzip, root.join("app.js.gz"))
+        );
+        assert_eq!(select("zstd"), Selected::Compress(Algorithm::Zstd));
+        assert_eq!(select("zstd;q=0.5, identity"), Selected::Identity);
+        assert_eq!(select("identity;q=0"), Selected::NotAcceptable);
+
+        options.zstd_dictionary = true;
+        let server = Server::bind(&root, "127.0.0.1:0", options.clone())?;
+        assert_eq!(
+            server.select(&path, &AcceptEncoding::parse("zstd, br;q=0.5")),
+            Selected::Compress(Algorithm::Brotli)
+        );
+
+        options.zstd_dictionary = false;
+        options.compress_missing = false;
+        let server = Server::bind(&root, "127.0.0.1:0", options)?;
+        assert_eq!(
+            server.select(&path, &AcceptEncoding::parse("zstd")),
+            Selected::Identity
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn server_sets_encoding_headers() -> Result<()> {
+        let root = test_dir("serve-http");
+        fs::write(root.join("index.html"), "<p>Hello</p>\n".repeat(100))?;
+        fs::write(root.join("index.html.gz"), "not really gzip")?;
+        let server = Server::bind(&root, "127.0.0.1:0", ServeOptions::default())?;
+        let addr = server.local_addr().expect("listening on an IP address");
+
+        let response = thread::scope(|scope| -> Result<String> {
+            let running = scope.spawn(|| server.run(1, |_| {}));
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(
+                b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+            )?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            server.http.unblock();
+            assert!(running.join().expect("server panicked").is_err());
+            Ok(response)
+        })?;
+        let response = response.to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 200"));
+        assert!(response.contains("content-encoding: gzip\r\n"));
+        assert!(response.contains("vary: accept-encoding\r\n"));
+        assert!(response.contains("content-type: text/html; charset=utf-8\r\n"));
+        assert!(response.ends_with("\r\n\r\nnot really gzip"));
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,533 @@
+//! Web server configuration for serving precompressed variants.
+//!
+//! [`server_config`] renders a snippet for a [`Target`] server from the same
+//! algorithms, extensions and output options as a run, so the server looks
+//! for exactly the files the run writes.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    path::Path,
+};
+
+use anyhow::{Result, bail};
+
+use crate::negotiate::DEFAULT_PREFERENCE;
+use crate::output::{Layout, Output};
+use crate::precompress::{Algorithm, Algorithms, compressible_extensions};
+use crate::serve::content_type;
+
+/// A web server to render configuration for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Target {
+    // `gzip_static`, plus `brotli_static` and `zstd_static` from their
+    // third-party modules.
+    Nginx,
+    // The `precompressed` option of `file_server`.
+    Caddy,
+    // mod_rewrite rules, with mod_headers setting the response headers.
+    Apache,
+    // A mod_magnet script.
+    Lighttpd,
+}
+
+impl Target {
+    fn name(self) -> &'static str {
+        match self {
+            Target::Nginx => "nginx",
+            Target::Caddy => "caddy",
+            Target::Apache => "apache",
+            Target::Lighttpd => "lighttpd",
+        }
+    }
+
+    /// Reports whether the server can find `alg` variants named with
+    /// `suffix`. nginx and Caddy only look for the conventional suffixes, and
+    /// neither serves deflate variants.
+    fn supports(self, alg: Algorithm, suffix: &str) -> bool {
+        match self {
+            Target::Nginx | Target::Caddy => alg != Algorithm::Deflate && suffix == alg.extension(),
+            Target::Apache | Target::Lighttpd => true,
+        }
+    }
+}
+
+/// The options of the run whose variants the server should serve.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfigOptions {
+    /// The algorithms variants were written with.
+    pub algorithms: Algorithms,
+    /// The extensions of the files that were compressed, or the default
+    /// compressible extensions when unset.
+    pub extensions: Option<HashSet<String>>,
+    /// Where the variants were written.
+    pub output: Output,
+    /// Whether the zstd variants were compressed with a dictionary, without
+    /// which clients cannot decode them. They are left out if so.
+    pub zstd_dictionary: bool,
+}
+
+/// Renders a configuration snippet that serves the variants written by a run
+/// with `options`, preferring encodings in [`DEFAULT_PREFERENCE`] order.
+///
+/// Fails when the server cannot find the variants, such as with the
+/// per-encoding layout, or with a name template that does not append a fixed
+/// suffix to the source name.
+pub fn server_config(target: Target, options: &ServerConfigOptions) -> Result<String> {
+    if options.output.layout == Layout::PerEncoding {
+        bail!(
+            "the {} configuration only supports the sibling layout",
+            target.name()
+        );
+    }
+
+    let mut algorithms = options.algorithms;
+    let skip_zstd = options.zstd_dictionary && algorithms.is_enabled(Algorithm::Zstd);
+    if skip_zstd {
+        algorithms.disable(Algorithm::Zstd);
+    }
+    let mut variants = Vec::new();
+    let mut unsupported = Vec::new();
+    for alg in DEFAULT_PREFERENCE {
+        if !algorithms.is_enabled(alg) {
+            continue;
+        }
+        let template = options.output.naming.get(alg);
+        let Some(suffix) = template.suffix(alg) else {
+            bail!(
+                "{} cannot find {alg} variants named {template}; use a template that appends \
+                 a suffix to {{name}}",
+                target.name()
+            );
+        };
+        if target.supports(alg, &suffix) {
+            variants.push(Variant { alg, suffix });
+        } else if alg == Algorithm::Deflate || *template == Default::default() {
+            unsupported.push(alg);
+        } else {
+            bail!(
+                "{} only finds {alg} variants named {{name}}{}",
+                target.name(),
+                alg.extension()
+            );
+        }
+    }
+    if variants.is_empty() {
+        bail!(
+            "{} cannot serve any of the enabled algorithms",
+            target.name()
+        );
+    }
+
+    let extensions = compressible_extensions(options.extensions.as_ref());
+    let mut out = String::new();
+    let names = variants
+        .iter()
+        .map(|v| v.alg.to_string())
+        .collect::<Vec<_>>();
+    let comment = if target == Target::Lighttpd {
+        "--"
+    } else {
+        "#"
+    };
+    writeln!(
+        out,
+        "{comment} Serves the {} variants written by precompress.",
+        names.join(", ")
+    )?;
+    for alg in unsupported {
+        writeln!(
+            out,
+            "{comment} {} cannot serve the {alg} variants.",
+            target.name()
+        )?;
+    }
+    if skip_zstd {
+        writeln!(
+            out,
+            "{comment} The zstd variants are not served, as clients cannot decode them without \
+             the dictionary they were compressed with."
+        )?;
+    }
+    if let Some(out_dir) = &options.output.out_dir {
+        writeln!(
+            out,
+            "{comment} Serve {} with the originals placed in it.",
+            out_dir.display()
+        )?;
+    }
+
+    let config = Config {
+        variants,
+        extensions,
+    };
+    match target {
+        Target::Nginx => config.nginx(&mut out)?,
+        Target::Caddy => config.caddy(&mut out)?,
+        Target::Apache => config.apache(&mut out)?,
+        Target::Lighttpd => config.lighttpd(&mut out)?,
+    }
+    Ok(out)
+}
+
+struct Variant {
+    alg: Algorithm,
+    suffix: String,
+}
+
+struct Config<'a> {
+    variants: Vec<Variant>,
+    extensions: Vec<&'a str>,
+}
+
+impl Config<'_> {
+    fn nginx(&self, out: &mut String) -> std::fmt::Result {
+        for variant in &self.variants {
+            match variant.alg {
+                Algorithm::Brotli => writeln!(
+                    out,
+                    "# brotli_static needs https://github.com/google/ngx_brotli."
+                )?,
+                Algorithm::Zstd => writeln!(
+                    out,
+                    "# zstd_static needs https://github.com/tokers/zstd-nginx-module."
+                )?,
+                Algorithm::Deflate | Algorithm::Gzip => {}
+            }
+        }
+        writeln!(out, "location ~* {} {{", self.extension_regex())?;
+        for variant in &self.variants {
+            let directive = match variant.alg {
+                Algorithm::Brotli => "brotli_static",
+                Algorithm::Gzip => "gzip_static",
+                Algorithm::Zstd => "zstd_static",
+                Algorithm::Deflate => unreachable!("nginx cannot serve deflate variants"),
+            };
+            writeln!(out, "    {directive} on;")?;
+        }
+        writeln!(out, "    gzip_vary on;")?;
+        writeln!(out, "}}")
+    }
+
+    fn caddy(&self, out: &mut String) -> std::fmt::Result {
+        let paths = self
+            .extensions
+            .iter()
+            .map(|ext| format!("*.{ext}"))
+            .collect::<Vec<_>>();
+        let encodings = self
+            .variants
+            .iter()
+            .map(|v| v.alg.encoding())
+            .collect::<Vec<_>>();
+        writeln!(out, "@precompressed path {}", paths.join(" "))?;
+        writeln!(out, "handle @precompressed {{")?;
+        writeln!(out, "\tfile_server {{")?;
+        writeln!(out, "\t\tprecompressed {}", encodings.join(" "))?;
+        writeln!(out, "\t}}")?;
+        writeln!(out, "}}")?;
+        writeln!(out, "handle {{")?;
+        writeln!(out, "\tfile_server")?;
+        writeln!(out, "}}")
+    }
+
+    fn apache(&self, out: &mut String) -> std::fmt::Result {
+        let extensions = self.extension_alternation();
+        let suffixes = self
+            .variants
+            .iter()
+            .map(|v| regex_escape(&v.suffix))
+            .collect::<Vec<_>>()
+            .join("|");
+        writeln!(out, "# Needs mod_rewrite and mod_headers.")?;
+        writeln!(out, "RewriteEngine On")?;
+        for variant in &self.variants {
+            writeln!(out)?;
+            // The encoding must be listed without `;q=0`, which refuses it.
+            writeln!(
+                out,
+                "RewriteCond \"%{{HTTP:Accept-Encoding}}\" \"\\b{}\\b(?!\\s*;\\s*[qQ]=0(?:\\.0*)?(?![.\\d]))\"",
+                variant.alg.encoding()
+            )?;
+            writeln!(
+                out,
+                "RewriteCond \"%{{REQUEST_FILENAME}}{}\" -s",
+                variant.suffix
+            )?;
+            writeln!(
+                out,
+                "RewriteRule \"^(.+\\.(?:{extensions}))$\" \"$1{}\" [QSA]",
+                variant.suffix
+            )?;
+        }
+
+        // Variants keep the type of their source, and must not be compressed
+        // again by mod_deflate.
+        writeln!(out)?;
+        for (content_type, extensions) in self.content_types() {
+            writeln!(
+                out,
+                "RewriteRule \"\\.(?:{})(?:{suffixes})$\" \"-\" \"[T={},E=no-gzip:1,E=no-brotli:1]\"",
+                extensions.join("|"),
+                content_type.replace("; ", ";")
+            )?;
+        }
+        for variant in &self.variants {
+            writeln!(out)?;
+            writeln!(
+                out,
+                "<FilesMatch \"\\.(?:{extensions}){}$\">",
+                regex_escape(&variant.suffix)
+            )?;
+            writeln!(
+                out,
+                "    Header set Content-Encoding {}",
+                variant.alg.encoding()
+            )?;
+            writeln!(out, "</FilesMatch>")?;
+        }
+        writeln!(out)?;
+        writeln!(out, "<FilesMatch \"\\.(?:{extensions})(?:{suffixes})?$\">")?;
+        writeln!(out, "    Header append Vary Accept-Encoding")?;
+        writeln!(out, "</FilesMatch>")
+    }
+
+    fn lighttpd(&self, out: &mut String) -> std::fmt::Result {
+        writeln!(
+            out,
+            "-- Needs lighttpd 1.4.60 or later. Save this script as precompress.lua and add:"
+        )?;
+        writeln!(out, "--")?;
+        writeln!(out, "--   server.modules += (\"mod_magnet\")")?;
+        writeln!(
+            out,
+            "--   $HTTP[\"url\"] =~ \"{}\" {{",
+            self.extension_regex()
+        )?;
+        writeln!(
+            out,
+            "--       magnet.attract-physical-path-to = (\"/etc/lighttpd/precompress.lua\")"
+        )?;
+        writeln!(out, "--   }}")?;
+        writeln!(out)?;
+        writeln!(out, "local types = {{")?;
+        for ext in &self.extensions {
+            writeln!(
+                out,
+                "  [\"{}\"] = \"{}\",",
+                lua_escape(ext),
+                content_type(Path::new(&format!("_.{ext}")))
+            )?;
+        }
+        writeln!(out, "}}")?;
+        writeln!(out, "local variants = {{")?;
+        for variant in &self.variants {
+            writeln!(
+                out,
+                "  {{ \"{}\", \"{}\" }},",
+                variant.alg.encoding(),
+                lua_escape(&variant.suffix)
+            )?;
+        }
+        writeln!(out, "}}")?;
+        writeln!(out)?;
+        writeln!(out, "local r = lighty.r")?;
+        writeln!(out, "local path = r.req_attr[\"physical.path\"]")?;
+        writeln!(out, "local ext = path:match(\"%.([^./]+)$\")")?;
+        writeln!(
+            out,
+            "local accept = r.req_header[\"Accept-Encoding\"] or \"\""
+        )?;
+        writeln!(out, "r.resp_header[\"Vary\"] = \"Accept-Encoding\"")?;
+        // An encoding listed with `;q=0` is refused.
+        writeln!(out, "local function accepts(encoding)")?;
+        writeln!(
+            out,
+            "  local _, last = accept:find(\"%f[%w]\" .. encoding .. \"%f[%W]\")"
+        )?;
+        writeln!(out, "  if not last then return false end")?;
+        writeln!(
+            out,
+            "  local q = accept:match(\"^%s*;%s*[qQ]=([%d.]+)\", last + 1)"
+        )?;
+        writeln!(out, "  return not q or tonumber(q) ~= 0")?;
+        writeln!(out, "end")?;
+        writeln!(out, "for _, variant in ipairs(variants) do")?;
+        writeln!(
+            out,
+            "  if accepts(variant[1]) and lighty.c.stat(path .. variant[2]) then"
+        )?;
+        writeln!(
+            out,
+            "    r.req_attr[\"physical.path\"] = path .. variant[2]"
+        )?;
+        writeln!(out, "    r.resp_header[\"Content-Encoding\"] = variant[1]")?;
+        writeln!(
+            out,
+            "    r.resp_header[\"Content-Type\"] = types[ext] or \"application/octet-stream\""
+        )?;
+        writeln!(out, "    break")?;
+        writeln!(out, "  end")?;
+        writeln!(out, "end")?;
+        writeln!(out, "return 0")
+    }
+
+    /// A regex matching paths with one of the extensions, e.g.
+    /// `\.(?:css|js)$`.
+    fn extension_regex(&self) -> String {
+        format!("\\.(?:{})$", self.extension_alternation())
+    }
+
+    fn extension_alternation(&self) -> String {
+        self.extensions
+            .iter()
+            .map(|ext| regex_escape(ext))
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    /// Groups the extensions by the content type of their files.
+    fn content_types(&self) -> BTreeMap<&'static str, Vec<String>> {
+        let mut types: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for ext in &self.extensions {
+            types
+                .entry(content_type(Path::new(&format!("_.{ext}"))))
+                .or_default()
+                .push(regex_escape(ext));
+        }
+        types
+    }
+}
+
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn lua_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use anyhow::Result;
+
+    use super::{ServerConfigOptions, Target, server_config};
+    use crate::naming::Naming;
+    use crate::output::{Layout, Output};
+    use crate::precompress::{Algorithm, Algorithms};
+
+    fn options(algorithms: &[Algorithm]) -> ServerConfigOptions {
+        let mut algs = Algorithms::empty();
+        for alg in algorithms {
+            algs.enable(*alg);
+        }
+        ServerConfigOptions {
+            algorithms: algs,
+            extensions: Some(HashSet::from([String::from("js"), String::from("css")])),
+            output: Output::default(),
+            zstd_dictionary: false,
+        }
+    }
+
+    #[test]
+    fn nginx_and_caddy_use_their_static_modules() -> Result<()> {
+        let options = options(&[Algorithm::Brotli, Algorithm::Deflate, Algorithm::Gzip]);
+        let nginx = server_config(Target::Nginx, &options)?;
+        assert!(nginx.contains("location ~* \\.(?:css|js)$ {"));
+        assert!(nginx.contains("    brotli_static on;\n    gzip_static on;\n"));
+        assert!(nginx.contains("gzip_vary on;"));
+        assert!(nginx.contains("nginx cannot serve the deflate variants."));
+        assert!(!nginx.contains("zstd_static"));
+
+        let caddy = server_config(Target::Caddy, &options)?;
+        assert!(caddy.contains("@precompressed path *.css *.js\n"));
+        assert!(caddy.contains("precompressed br gzip\n"));
+
+        let deflate = self::options(&[Algorithm::Deflate]);
+        assert!(server_config(Target::Caddy, &deflate).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn zstd_variants_with_a_dictionary_are_left_out() -> Result<()> {
+        let mut options = options(&[Algorithm::Brotli, Algorithm::Zstd]);
+        options.zstd_dictionary = true;
+        let caddy = server_config(Target::Caddy, &options)?;
+        assert!(caddy.contains("precompressed br\n"));
+        assert!(caddy.contains("# The zstd variants are not served"));
+
+        options.algorithms = Algorithms::empty();
+        options.algorithms.enable(Algorithm::Zstd);
+        assert!(server_config(Target::Nginx, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn apache_and_lighttpd_skip_encodings_refused_with_q_0() -> Result<()> {
+        let options = options(&[Algorithm::Gzip]);
+        let apache = server_config(Target::Apache, &options)?;
+        assert!(apache.contains(
+            "RewriteCond \"%{HTTP:Accept-Encoding}\" \
+             \"\\bgzip\\b(?!\\s*;\\s*[qQ]=0(?:\\.0*)?(?![.\\d]))\"\n"
+        ));
+
+        let lighttpd = server_config(Target::Lighttpd, &options)?;
+        assert!(lighttpd.contains(
+            "  local q = accept:match(\"^%s*;%s*[qQ]=([%d.]+)\", last + 1)\n  \
+             return not q or tonumber(q) ~= 0\n"
+        ));
+        assert!(lighttpd.contains("  if accepts(variant[1]) and lighty.c.stat("));
+        Ok(())
+    }
+
+    #[test]
+    fn apache_and_lighttpd_follow_name_templates() -> Result<()> {
+        let mut options = options(&[Algorithm::Brotli, Algorithm::Zstd]);
+        options.output.naming.set(
+            Algorithm::Zstd,
+            "{name}.zstd".parse().expect("valid template"),
+        );
+        let apache = server_config(Target::Apache, &options)?;
+        assert!(apache.contains("RewriteCond \"%{REQUEST_FILENAME}.zstd\" -s\n"));
+        assert!(apache.contains("RewriteRule \"^(.+\\.(?:css|js))$\" \"$1.zstd\" [QSA]\n"));
+        assert!(apache.contains(
+            "RewriteRule \"\\.(?:js)(?:\\.br|\\.zstd)$\" \"-\" \
+             \"[T=text/javascript;charset=utf-8,E=no-gzip:1,E=no-brotli:1]\"\n"
+        ));
+        assert!(apache.contains(
+            "<FilesMatch \"\\.(?:css|js)\\.zstd$\">\n    Header set Content-Encoding zstd\n"
+        ));
+        assert!(apache.contains("Header append Vary Accept-Encoding"));
+
+        let lighttpd = server_config(Target::Lighttpd, &options)?;
+        assert!(lighttpd.contains("  { \"br\", \".br\" },\n  { \"zstd\", \".zstd\" },\n"));
+        assert!(lighttpd.contains("  [\"css\"] = \"text/css; charset=utf-8\",\n"));
+
+        // nginx only looks for the conventional suffix.
+        assert!(server_config(Target::Nginx, &options).is_err());
+
+        options.output.naming = Naming::default();
+        options.output.naming.set(
+            Algorithm::Brotli,
+            "{stem}.{enc}.{ext}".parse().expect("valid template"),
+        );
+        assert!(server_config(Target::Apache, &options).is_err());
+
+        options.output = Output {
+            out_dir: Some("out".into()),
+            layout: Layout::PerEncoding,
+            ..Output::default()
+        };
+        assert!(server_config(Target::Lighttpd, &options).is_err());
+        Ok(())
+    }
+}
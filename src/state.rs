@@ -0,0 +1,163 @@
+//! What incremental runs remember about each source file between runs.
+//!
+//! Variants are stamped with the modification time of their source, but a
+//! matching mtime alone is weak evidence: restoring a tree with `tar` or
+//! `rsync -t` can replace a source with different contents and the same
+//! mtime. The state file records the size and mtime each source had when its
+//! variants were last brought up to date, and a variant is only trusted
+//! without decoding it when both still match.
+//!
+//! Variants that were discarded or pruned leave nothing on disk to compare
+//! with, so the state file also records how they were dropped, along with a
+//! digest of the settings that decided it. While neither the source nor the
+//! settings change, they are not encoded again.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::cache::to_hex;
+use crate::encode::Quality;
+use crate::precompress::{KeepBest, MinSavings, write_atomic};
+use crate::report::{VariantOutcome, VariantReport};
+
+/// The name of the state file, kept at the root of the output.
+pub(crate) const STATE_FILE_NAME: &str = ".precompress-state.json";
+
+/// The source file a set of variants was brought up to date with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Record {
+    pub(crate) size: u64,
+    /// The modification time, in nanoseconds since the Unix epoch.
+    pub(crate) modified: u64,
+    /// The [`settings_digest`] the variants were brought up to date with.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) settings: String,
+    /// How each variant that was not kept was dropped, by [`drop_key`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) dropped: BTreeMap<String, VariantOutcome>,
+}
+
+impl Record {
+    pub(crate) fn new(size: u64, modified: SystemTime) -> Self {
+        Record {
+            size,
+            modified: nanos_since_epoch(modified),
+            settings: String::new(),
+            dropped: BTreeMap::new(),
+        }
+    }
+
+    /// Reports whether a source of `size` bytes modified at `modified` is
+    /// the one recorded.
+    pub(crate) fn matches(&self, size: u64, modified: SystemTime) -> bool {
+        self.size == size && self.modified == nanos_since_epoch(modified)
+    }
+
+    /// Records how the discarded and pruned variants in `variants` were
+    /// dropped with the settings of `settings`.
+    pub(crate) fn with_dropped(mut self, settings: String, variants: &[VariantReport]) -> Self {
+        self.settings = settings;
+        self.dropped = variants
+            .iter()
+            .filter(|variant| {
+                matches!(
+                    variant.status,
+                    VariantOutcome::Discarded | VariantOutcome::Pruned
+                )
+            })
+            .map(|variant| (drop_key(variant), variant.status))
+            .collect();
+        self
+    }
+
+    /// Returns how `variant` was dropped, if it was.
+    pub(crate) fn dropped(&self, variant: &VariantReport) -> Option<VariantOutcome> {
+        self.dropped.get(&drop_key(variant)).copied()
+    }
+}
+
+/// Identifies a variant by its algorithm or dictionary format, and by the
+/// dictionary it was encoded with, if any.
+fn drop_key(variant: &VariantReport) -> String {
+    match &variant.dictionary_hash {
+        Some(hash) => format!("{}{hash}", variant.algorithm),
+        None => variant.algorithm.clone(),
+    }
+}
+
+/// Returns a digest of the settings that decide whether a variant is kept.
+pub(crate) fn settings_digest(
+    quality: Quality,
+    min_savings: &MinSavings,
+    keep_best: Option<KeepBest>,
+) -> String {
+    let settings = format!("{quality:?} {min_savings:?} {keep_best:?}");
+    to_hex(&Sha256::digest(settings))[..16].to_owned()
+}
+
+/// The records of the previous run, along with those of the current one.
+/// Only records set during the current run are saved, so deleted sources do
+/// not linger.
+#[derive(Debug)]
+pub(crate) struct State {
+    path: PathBuf,
+    previous: BTreeMap<String, Record>,
+    current: Mutex<BTreeMap<String, Record>>,
+}
+
+impl State {
+    /// Loads the state file in `dir`. A missing or unreadable file is
+    /// treated as empty, which only makes the run decode more variants.
+    pub(crate) fn load(dir: &Path) -> Self {
+        let path = dir.join(STATE_FILE_NAME);
+        let previous = fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        State {
+            path,
+            previous,
+            current: Mutex::default(),
+        }
+    }
+
+    /// Returns the previous run's record for the source at `rel`.
+    pub(crate) fn get(&self, rel: &Path) -> Option<&Record> {
+        self.previous.get(&key(rel))
+    }
+
+    pub(crate) fn set(&self, rel: &Path, record: Record) {
+        self.current
+            .lock()
+            .expect("state lock poisoned")
+            .insert(key(rel), record);
+    }
+
+    /// Replaces the state file with the records set during this run.
+    pub(crate) fn save(&self) -> io::Result<()> {
+        let current = self.current.lock().expect("state lock poisoned");
+        let data = serde_json::to_vec(&*current)?;
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&self.path, |file| file.write_all(&data)).map(drop)
+    }
+}
+
+fn key(rel: &Path) -> String {
+    rel.to_string_lossy().replace('\\', "/")
+}
+
+fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
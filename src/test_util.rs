@@ -0,0 +1,19 @@
+//! Helpers shared by the unit tests.
+
+use std::{
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Creates an empty, uniquely named directory under the system temp
+/// directory.
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let unique = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_nanos();
+    let root = std::env::temp_dir().join(format!("precompress-{name}-{unique}"));
+    fs::create_dir_all(&root).expect("unable to create temp directory");
+    root
+}
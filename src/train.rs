@@ -0,0 +1,217 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::config::{Config, FileSettings};
+use crate::encode::Quality;
+use crate::output::Output;
+use crate::precompress::{Algorithm, Algorithms, WalkOptions, build_walk, is_compressible};
+
+/// Number of leading bytes of each file used as a sample; zstd does not use
+/// more than this from any one sample.
+const MAX_SAMPLE_SIZE: u64 = 128 * 1024;
+
+/// The default size of a trained dictionary, matching the zstd CLI.
+pub const DEFAULT_DICTIONARY_SIZE: u64 = 112_640;
+
+/// The default maximum number of files sampled for training.
+pub const DEFAULT_MAX_SAMPLES: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct TrainOptions {
+    pub min_size: u64,
+    pub algorithms: Algorithms,
+    pub extensions: Option<HashSet<String>>,
+    pub output: Output,
+    /// Files beyond this many are sampled evenly across the sorted paths.
+    pub max_samples: usize,
+    /// Per-file rules; files they exclude, or compress without zstd, are not
+    /// sampled.
+    pub config: Config,
+}
+
+/// Reads a sample from every file under `root` that a run with the same
+/// options would compress, up to `max_samples` files.
+pub fn collect_samples(
+    root: &Path,
+    options: &TrainOptions,
+    walk_options: &WalkOptions,
+) -> Result<Vec<Vec<u8>>> {
+    let nested_out_dir = options.output.nested_out_dir(root);
+    let base = FileSettings {
+        algorithms: options.algorithms,
+        quality: Quality::default(),
+        min_size: options.min_size,
+        include: None,
+    };
+    let variant_algorithms = options.config.all_algorithms(options.algorithms);
+    let mut paths = Vec::new();
+    for entry in build_walk(root, walk_options)? {
+        let Ok(entry) = entry else { continue };
+        let path = entry.path();
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        let settings = options.config.resolve(rel, base);
+        let selected = match settings.include {
+            Some(include) => include,
+            None => is_compressible(path, options.extensions.as_ref()),
+        };
+        if !selected
+            || !settings.algorithms.is_enabled(Algorithm::Zstd)
+            || nested_out_dir
+                .as_ref()
+                .is_some_and(|out_dir| path.starts_with(out_dir))
+            || options.output.is_sibling_variant(path, variant_algorithms)
+            || path.is_symlink()
+            || !path.is_file()
+        {
+            continue;
+        }
+        if entry
+            .metadata()
+            .is_ok_and(|metadata| metadata.len() >= settings.min_size)
+        {
+            paths.push(path.to_path_buf());
+        }
+    }
+    paths.sort();
+
+    let mut samples = Vec::new();
+    for path in spread(&paths, options.max_samples) {
+        let mut sample = Vec::new();
+        File::open(path)?
+            .take(MAX_SAMPLE_SIZE)
+            .read_to_end(&mut sample)?;
+        if !sample.is_empty() {
+            samples.push(sample);
+        }
+    }
+    Ok(samples)
+}
+
+/// Trains a zstd dictionary of at most `size` bytes from `samples`.
+pub fn train(samples: &[Vec<u8>], size: usize) -> Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, size).map_err(|err| {
+        anyhow!(
+            "unable to train a dictionary from {} samples: {}",
+            samples.len(),
+            err
+        )
+    })
+}
+
+/// Returns at most `max` of `paths`, evenly spaced.
+fn spread(paths: &[PathBuf], max: usize) -> impl Iterator<Item = &PathBuf> {
+    let count = paths.len().min(max);
+    (0..count).map(move |i| &paths[i * paths.len() / count])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use anyhow::Result;
+
+    use super::{TrainOptions, collect_samples, spread, train};
+    use crate::config::Config;
+    use crate::output::Output;
+    use crate::precompress::{Algorithms, WalkOptions};
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn trains_a_dictionary_from_selected_files() -> Result<()> {
+        let root = test_dir("train");
+        fs::write(root.join(".ignore"), "ignored/\n")?;
+        fs::create_dir(root.join("ignored"))?;
+        fs::write(root.join("ignored/skip.json"), "{}".repeat(64))?;
+        for i in 0..200 {
+            fs::write(
+                root.join(format!("item-{i}.json")),
+                format!(
+                    r#"{{"id":{i},"type":"product","name":"Item {i}","tags":["new","sale"],"price":{}}}"#,
+                    i * 7
+                ),
+            )?;
+        }
+        fs::write(root.join("item-0.json.gz"), [0; 64])?;
+        fs::write(root.join("logo.png"), [0; 64])?;
+
+        let options = TrainOptions {
+            min_size: 0,
+            algorithms: Algorithms::all(),
+            extensions: None,
+            output: Output::default(),
+            max_samples: 150,
+            config: Config::default(),
+        };
+        let samples = collect_samples(&root, &options, &WalkOptions::default())?;
+        assert_eq!(samples.len(), 150);
+        assert!(samples.iter().all(|sample| sample.starts_with(b"{\"id\"")));
+
+        let dictionary = train(&samples, 4096)?;
+        assert!(!dictionary.is_empty() && dictionary.len() <= 4096);
+        assert!(train(&samples[..1], 4096).is_err());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn samples_only_files_that_rules_compress_with_zstd() -> Result<()> {
+        let root = test_dir("train-config");
+        fs::create_dir_all(root.join("gzip"))?;
+        fs::create_dir_all(root.join("private"))?;
+        for name in ["app.json", "data.bin", "gzip/app.json", "private/app.json"] {
+            fs::write(root.join(name), name.repeat(16))?;
+        }
+        let config = Config::parse(
+            r#"
+            [[rule]]
+            glob = "*.bin"
+            include = true
+
+            [[rule]]
+            glob = "gzip/**"
+            algorithms = ["gzip"]
+
+            [[rule]]
+            glob = "private/**"
+            include = false
+            "#,
+        )?;
+        let options = TrainOptions {
+            min_size: 0,
+            algorithms: Algorithms::all(),
+            extensions: None,
+            output: Output::default(),
+            max_samples: 10,
+            config,
+        };
+        let mut samples = collect_samples(&root, &options, &WalkOptions::default())?;
+        samples.sort();
+        assert_eq!(
+            samples,
+            ["app.json".repeat(16), "data.bin".repeat(16)].map(String::into_bytes)
+        );
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn spread_samples_evenly() {
+        let paths = (0..10)
+            .map(|i| PathBuf::from(i.to_string()))
+            .collect::<Vec<_>>();
+        let picked = spread(&paths, 4)
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec!["0", "2", "5", "7"]);
+        assert_eq!(spread(&paths, 20).count(), 10);
+        assert_eq!(spread(&[], 4).count(), 0);
+    }
+}